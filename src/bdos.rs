@@ -8,6 +8,10 @@ use crate::bdos_file;
 use crate::console_emulator::ConsoleEmulator;
 use crate::cpm_machine::CpmMachine;
 use crate::constants::*;
+use crate::host_commands;
+use crate::memory_map::MemoryMap;
+use crate::rsx;
+use crate::symbols::SymbolTable;
 
 const BDOS_COMMAND_NAMES: [&str; 106] = [
     // 0
@@ -27,7 +31,7 @@ const BDOS_COMMAND_NAMES: [&str; 106] = [
     "F_ERRMODE", "", "", "", "",
 
     "", "", "", "", "", "", "", "", "", "", // 50-59
-    "", "", "", "", "", "", "", "", "", "", // 60-69
+    "P_RSX", "", "", "", "", "", "", "", "", "", // 60-69
     "", "", "", "", "", "", "", "", "", "", // 70-79
     "", "", "", "", "", "", "", "", "", "", // 80-89
     "", "", "", "", "", "", "", "", "", "", // 00-09
@@ -37,26 +41,51 @@ const BDOS_COMMAND_NAMES: [&str; 106] = [
     "T_GET"
     ];
 
+pub fn bdos_command_name(command: u8) -> &'static str {
+    if command < BDOS_COMMAND_NAMES.len() as u8 {
+        BDOS_COMMAND_NAMES[command as usize]
+    } else if command == 224 {
+        "H_MOUNT"
+    } else if command == 225 {
+        "H_COMMAND"
+    } else {
+        "unknown"
+    }
+}
+
 pub struct Bdos {
     state: BdosState,
+    call_trace: bool,
+    call_trace_skip_console: bool,
 }
 
 impl Bdos {
 
     pub fn new() -> Bdos {
         Bdos {
-            state: BdosState::new()
+            state: BdosState::new(),
+            call_trace: false,
+            call_trace_skip_console: false,
         }
     }
 
-    pub fn warm_reset(&mut self, machine: &mut CpmMachine) {
-        // Setup/Restore BOOT entrypoint
-        machine.poke(  BDOS_ENTRY_ADDRESS,   0xc3 /* jp BDOS_BASE_ADDRESS */);
-        machine.poke16(BDOS_ENTRY_ADDRESS+1, BDOS_BASE_ADDRESS);
+    pub fn set_call_trace(&mut self, call_trace: bool, skip_console: bool) {
+        self.call_trace = call_trace;
+        self.call_trace_skip_console = skip_console;
+    }
+
+    /// Returns the names of the RSXs that stay resident
+    pub fn warm_reset(&mut self, machine: &mut CpmMachine) -> Vec<String> {
+        // Setup/Restore BOOT entrypoint, to the BDOS or to the RSXs that
+        // stay resident
+        machine.poke(BDOS_ENTRY_ADDRESS, 0xc3 /* jp nnnn */);
+        rsx::remove_rsxs(machine)
     }
 
     pub fn reset(&mut self, machine: &mut CpmMachine) {
         self.state.reset();
+        let bdos_base = machine.memory_map().bdos;
+        machine.poke16(BDOS_ENTRY_ADDRESS+1, bdos_base); // No RSXs
         self.warm_reset(machine);
 
         // Reset IOBYTE
@@ -66,57 +95,74 @@ impl Bdos {
         // HL to A and B. It is done as code to make sure that the flags are
         // set correctly.
         // The actual CP/M 2.2 code executes these three instructions to return.
-        machine.poke(BDOS_BASE_ADDRESS, 0x7d /*ld a,l*/);
-        machine.poke(BDOS_BASE_ADDRESS+1, 0x44 /*ld b,h*/);
-        machine.poke(BDOS_BASE_ADDRESS+2, 0xc9 /*ret*/);
+        let map = *machine.memory_map();
+        machine.poke(map.bdos, 0x7d /*ld a,l*/);
+        machine.poke(map.bdos+1, 0x44 /*ld b,h*/);
+        machine.poke(map.bdos+2, 0xc9 /*ret*/);
 
         // Note: if the first 6 bytes of BDOS change, the serial number in the
         // CCP source code needs to be updated.
 
         // Disk parameter block 0
         // See "Programmer CP/M Handbook" by Andy Johnson-Laird, page 33
-        machine.poke16(BDOS_DPB0_ADDRESS     ,  26);        // 128 bytes sectors per track
-        machine.poke  (BDOS_DPB0_ADDRESS +  2,   3);        // Block shift for 1024 bytes block
-        machine.poke  (BDOS_DPB0_ADDRESS +  3,   7);        // Block mask for 1024 bytes block
-        machine.poke  (BDOS_DPB0_ADDRESS +  4,   3);        // Extent mask for 1024 bytes block
-        machine.poke16(BDOS_DPB0_ADDRESS +  5, 242);        // Max allocation block number
-        machine.poke16(BDOS_DPB0_ADDRESS +  7,  63);        // Number of directory entries - 1
-        machine.poke  (BDOS_DPB0_ADDRESS +  9, 0b11000000); // Bitmap for allocation blocks
-        machine.poke  (BDOS_DPB0_ADDRESS + 10, 0b00000000); // Bitmap for allocation blocks
-        machine.poke16(BDOS_DPB0_ADDRESS + 11,  16);        // Max allocation block number
-        machine.poke16(BDOS_DPB0_ADDRESS + 13,   2);        // Number of tracks before directory
+        machine.poke16(map.dpb0     ,  26);        // 128 bytes sectors per track
+        machine.poke  (map.dpb0 +  2,   3);        // Block shift for 1024 bytes block
+        machine.poke  (map.dpb0 +  3,   7);        // Block mask for 1024 bytes block
+        machine.poke  (map.dpb0 +  4,   3);        // Extent mask for 1024 bytes block
+        machine.poke16(map.dpb0 +  5, 242);        // Max allocation block number
+        machine.poke16(map.dpb0 +  7,  63);        // Number of directory entries - 1
+        machine.poke  (map.dpb0 +  9, 0b11000000); // Bitmap for allocation blocks
+        machine.poke  (map.dpb0 + 10, 0b00000000); // Bitmap for allocation blocks
+        machine.poke16(map.dpb0 + 11,  16);        // Max allocation block number
+        machine.poke16(map.dpb0 + 13,   2);        // Number of tracks before directory
 
         // Allocation vector 0, we need 30 bytes for 242 blocks
         for i in 0..30 {
-            machine.poke(BDOS_ALVEC0_ADDRESS + i, 0);
+            machine.poke(map.alvec0 + i, 0);
         }
     }
 
+    pub fn add_symbols(&self, map: &MemoryMap, symbols: &mut SymbolTable) {
+        symbols.add(map.bdos, "BDOS");
+    }
+
+    pub fn current_drive(&self) -> u8 {
+        self.state.drive
+    }
+
+    pub fn dma(&self) -> u16 {
+        self.state.dma
+    }
+
+    pub fn set_host_commands(&mut self, enabled: bool) {
+        self.state.host_commands = enabled;
+    }
+
     pub fn assign_drive(&mut self, drive: u8, path: String) {
         self.state.directories[(drive & 0x0f) as usize] = Some(path);
     }
 }
 
 pub fn execute_bdos(bdos: &mut Bdos, bios: &mut Bios, console: &mut dyn ConsoleEmulator,
-        machine: &mut CpmMachine, reg: &mut Registers,
-        call_trace: bool, call_trace_skip_console: bool) -> ExecutionResult {
+        machine: &mut CpmMachine, reg: &mut Registers, symbols: &SymbolTable) -> ExecutionResult {
 
     // We do the BIOS actions outside the emulation.
     let pc = reg.pc();
-    if pc == BDOS_BASE_ADDRESS {
+    if pc == machine.memory_map().bdos {
+        // The return address of the CALL 5 is on the top of the stack
+        let caller = machine.peek16(reg.get16(Reg16::SP)).wrapping_sub(3);
+        let call_trace = bdos.call_trace;
         let env = &mut BdosEnvironment::new(&mut bdos.state, bios, console, machine, call_trace);
         let arg8 = reg.get8(Reg8::E);
         let arg16 = reg.get16(Reg16::DE);
         let command = reg.get8(Reg8::C);
 
-        let bdos_trace = call_trace && !(call_trace_skip_console && command <= 12);
+        let bdos_trace = call_trace && !(bdos.call_trace_skip_console && command <= 12);
         if bdos_trace {
-            let name = if command < BDOS_COMMAND_NAMES.len() as u8 {
-                BDOS_COMMAND_NAMES[command as usize]
-            } else {
-                "unknown"
-            };
-            print!("[[BDOS command {}: {}({:04x})]]", command, name, arg16);
+            match symbols.lookup(caller) {
+                Some(symbol) => env.trace(&format!("[[BDOS command {}: {}({:04x}) from {}]]", command, bdos_command_name(command), arg16, symbol)),
+                None => env.trace(&format!("[[BDOS command {}: {}({:04x})]]", command, bdos_command_name(command), arg16)),
+            }
         }
 
         let mut res8: Option<u8> = None;
@@ -259,13 +305,36 @@ pub fn execute_bdos(bdos: &mut Bdos, bios: &mut Bios, console: &mut dyn ConsoleE
             },
 
 
+            60 => { // Call RSX (CP/M 3)
+                // The call wasn't handled by any RSX
+                res16 = Some(0x00ff);
+            },
+
             105 => { // T_GET - Get date and time
                 // Not implemented
                 // Ignored silently to run https://github.com/sblendorio/gorilla-cpm
             },
 
+            224 => { // H_MOUNT - Mount host directories (iz-cpm)
+                if env.state.host_commands {
+                    res8 = Some(bdos_drive::mount(env, arg16));
+                } else {
+                    res8 = Some(0xff);
+                }
+            },
+            225 => { // H_COMMAND - Host command (iz-cpm)
+                if env.state.host_commands {
+                    let result = host_commands::execute(env, arg8);
+                    if result != ExecutionResult::Continue {
+                        return result;
+                    }
+                } else {
+                    res8 = Some(0xff);
+                }
+            },
+
             _ => {
-                eprintln!("BDOS command {} not implemented.\n", command);
+                env.console.message(&format!("BDOS command {} not implemented.\n", command));
                 return ExecutionResult::Stop;
             }
         }
@@ -276,12 +345,12 @@ pub fn execute_bdos(bdos: &mut Bdos, bios: &mut Bios, console: &mut dyn ConsoleE
         // will copy it to A and B.
         let res = if let Some(a) = res8 {
             if bdos_trace {
-                println!("[[=>{:02x}]]", a);
+                console.trace(&format!("[[=>{:02x}]]\n", a));
             }
             a as u16
         } else if let Some(hl) = res16 {
             if bdos_trace {
-                println!("[[=>{:04x}]]", hl);
+                console.trace(&format!("[[=>{:04x}]]\n", hl));
             }
             hl
         } else {
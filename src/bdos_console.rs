@@ -25,8 +25,7 @@ pub fn write(env: &mut BdosEnvironment, ch: u8) {
 pub fn read_reader(env: &mut BdosEnvironment) -> u8 {
     // The Reader Input function reads the next character from the logical reader
     // into register A. Control does not return until the character has been read.
-    let ch = env.bios.read(env.console, );
-    ch
+    env.bios.read(env.console)
 }
 
 pub fn write_string(env: &mut BdosEnvironment, address: u16) {
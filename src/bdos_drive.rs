@@ -4,6 +4,7 @@ use std::io;
 use crate::bdos_environment::*;
 use crate::constants::*;
 use crate::fcb::name_to_8_3;
+use crate::host_commands::find_host_path;
 use iz80::Machine;
 
 pub fn all_reset(env: &mut BdosEnvironment) -> u8{
@@ -25,7 +26,7 @@ pub fn all_reset(env: &mut BdosEnvironment) -> u8{
 
 fn has_dollar_file(env: &mut BdosEnvironment) -> io::Result<bool> {
     let path = env.get_directory(0, false)
-        .ok_or(io::Error::new(io::ErrorKind::Other, "No directory assigned to drive"))?;
+        .ok_or(io::Error::other("No directory assigned to drive"))?;
     let dir = fs::read_dir(path)?;
 
     for entry in dir {
@@ -100,7 +101,7 @@ pub fn get_read_only_vector(env: &BdosEnvironment) -> u16 {
     env.state.read_only_bitmap
 }
 
-pub fn get_disk_allocation_vector(_env: &BdosEnvironment) -> u16 {
+pub fn get_disk_allocation_vector(env: &BdosEnvironment) -> u16 {
     // An allocation vector is maintained in main memory for each on-line disk
     // drive. Various system programs use the information provided by the
     // allocation vector to determine the amount of remaining storage (see the
@@ -110,10 +111,10 @@ pub fn get_disk_allocation_vector(_env: &BdosEnvironment) -> u16 {
     // Read-Only. Although this function is not normally used by application
     // programs, additional details of the allocation vector are found in
     // Section 6.
-    BDOS_ALVEC0_ADDRESS
+    env.machine.memory_map().alvec0
 }
 
-pub fn get_disk_parameter_block(_env: &BdosEnvironment) -> u16 {
+pub fn get_disk_parameter_block(env: &BdosEnvironment) -> u16 {
     // The address of the BIOS resident disk parameter block is returned in HL
     // as a result of this function call. This address can be used for either of
     // two purposes. First, the disk parameter values can be extracted for
@@ -121,7 +122,7 @@ pub fn get_disk_parameter_block(_env: &BdosEnvironment) -> u16 {
     // dynamically change the values of current disk parameters when the disk
     // environment changes, if required. Normally, application programs will not
     // require this facility.
-    BDOS_DPB0_ADDRESS
+    env.machine.memory_map().dpb0
 }
 
 pub fn reset_drives(env: &mut BdosEnvironment, drives: u16) -> u8 {
@@ -137,3 +138,124 @@ pub fn reset_drives(env: &mut BdosEnvironment, drives: u16) -> u8 {
 
     0
 }
+
+/*
+Mounting of host directories on the drives while the emulator runs, with the
+private BDOS function 224 (E0h). DE points to a command line like the
+command tail at 0080h, a length byte followed by the text:
+
+    d:=path     Mounts the host directory on the drive, replacing the
+                previous one.
+    d:=         Unmounts the drive. Drive A: and the current drive can't be
+                unmounted.
+    d:          Remounts the drive, to log it in again after changes on the
+                host directory.
+    (empty)     Lists the drives mounted.
+
+The drive affected is logged out and set back to read/write. Returns A=0, or
+FFh if there is an error, with the error written to the console. The CCP
+converts the command line to upper case, the path is matched ignoring the
+case if it isn't found as it is. Relative paths are from the host current
+directory of HOSTCD, see host_commands.rs.
+*/
+
+#[derive(Debug, PartialEq)]
+pub enum MountCommand {
+    List,
+    Mount(u8, String),
+    Unmount(u8),
+    Remount(u8),
+}
+
+pub fn parse_mount(text: &str) -> Result<MountCommand, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(MountCommand::List);
+    }
+    let bytes = text.as_bytes();
+    let drive = bytes[0].to_ascii_uppercase();
+    if !(b'A'..=b'P').contains(&drive) || bytes.get(1) != Some(&b':') {
+        return Err(format!("Invalid drive in \"{}\"", text));
+    }
+    let drive = drive - b'A';
+    let rest = text[2..].trim();
+    if rest.is_empty() {
+        Ok(MountCommand::Remount(drive))
+    } else if let Some(path) = rest.strip_prefix('=') {
+        let path = path.trim();
+        if path.is_empty() {
+            Ok(MountCommand::Unmount(drive))
+        } else {
+            Ok(MountCommand::Mount(drive, path.to_string()))
+        }
+    } else {
+        Err(format!("Use d:=path to mount, d:= to unmount or d: to remount, not \"{}\"", text))
+    }
+}
+
+fn execute_mount(env: &mut BdosEnvironment, command: MountCommand) -> Result<(), String> {
+    match command {
+        MountCommand::List => {
+            let mut text = String::new();
+            for (drive, directory) in env.state.directories.iter().enumerate() {
+                if let Some(directory) = directory {
+                    let read_only = if env.state.read_only_bitmap & 1 << drive != 0 { " (R/O)" } else { "" };
+                    text += &format!("{}: = {}{}\r\n", (b'A' + drive as u8) as char, directory, read_only);
+                }
+            }
+            env.bios.write_string(env.console, &text);
+            return Ok(());
+        },
+        MountCommand::Mount(drive, path) => {
+            let directory = find_host_path(&env.state.host_directory, &path)
+                .filter(|directory| directory.is_dir())
+                .ok_or_else(|| format!("Directory \"{}\" not found", path))?;
+            env.state.directories[drive as usize] = Some(directory.to_string_lossy().to_string());
+            reset_drives(env, 1 << drive);
+        },
+        MountCommand::Unmount(drive) => {
+            if drive == 0 || drive == env.state.drive {
+                return Err("Drive A: and the current drive can't be unmounted".to_string());
+            }
+            env.state.directories[drive as usize] = None;
+            reset_drives(env, 1 << drive);
+        },
+        MountCommand::Remount(drive) => {
+            if env.state.directories[drive as usize].is_none() {
+                return Err(format!("Drive {}: is not mounted", (b'A' + drive) as char));
+            }
+            reset_drives(env, 1 << drive);
+        },
+    }
+    Ok(())
+}
+
+pub fn mount(env: &mut BdosEnvironment, address: u16) -> u8 {
+    let length = env.machine.peek(address) as u16;
+    let text: String = (0..length).map(|i| env.machine.peek(address + 1 + i) as char).collect();
+    match parse_mount(&text).and_then(|command| execute_mount(env, command)) {
+        Ok(()) => 0,
+        Err(err) => {
+            env.bios.write_string(env.console, &format!("Mount error: {}\r\n", err));
+            0xff
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mount() {
+        assert_eq!(parse_mount(" "), Ok(MountCommand::List));
+        assert_eq!(parse_mount(" C:=SOFTWARE/ZORK"), Ok(MountCommand::Mount(2, "SOFTWARE/ZORK".to_string())));
+        assert_eq!(parse_mount("p: = /tmp"), Ok(MountCommand::Mount(15, "/tmp".to_string())));
+        assert_eq!(parse_mount(" B:="), Ok(MountCommand::Unmount(1)));
+        assert_eq!(parse_mount(" B:"), Ok(MountCommand::Remount(1)));
+        assert!(parse_mount(" Q:=/tmp").is_err());
+        assert!(parse_mount(" B /tmp").is_err());
+        assert!(parse_mount(" B:/tmp").is_err());
+    }
+
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use iz80::Machine;
 
 use crate::bios::Bios;
@@ -19,6 +21,9 @@ pub struct BdosState {
     pub selected_bitmap: u16,
     pub read_only_bitmap: u16,
     pub directories: [Option<String>; 16],
+    // Host
+    pub host_directory: PathBuf,
+    pub host_commands: bool,
     // File
     pub dma: u16,
     // DIR state
@@ -37,6 +42,8 @@ impl BdosState {
             read_only_bitmap: 0,
             directories: [None, None, None, None, None, None, None, None,
                 None, None, None, None, None, None, None, None],
+            host_directory: PathBuf::new(),
+            host_commands: true,
             dma: DEFAULT_DMA,
             dir_drive: 0,
             dir_pattern: "????????.???".to_string(),
@@ -73,6 +80,10 @@ impl <'a> BdosEnvironment<'_> {
         BdosEnvironment {state, bios, console, machine, call_trace}
     }
 
+    pub fn trace(&mut self, text: &str) {
+        self.console.trace(text);
+    }
+
     pub fn iobyte(&self) -> u8 {
         self.machine.peek(IOBYTE_ADDRESS) & 0x0f
     }
@@ -81,15 +92,15 @@ impl <'a> BdosEnvironment<'_> {
     }
 
     pub fn store_buffer_to_dma(&mut self, buffer: &Buffer) {
-        for i in 0..RECORD_SIZE {
-            self.machine.poke(self.state.dma + i as u16, buffer[i]);
+        for (i, value) in buffer.iter().enumerate() {
+            self.machine.poke(self.state.dma + i as u16, *value);
         }
     }
 
     pub fn load_buffer_from_dma(&mut self) -> Buffer {
         let mut buffer = [0; RECORD_SIZE];
-        for i in 0..RECORD_SIZE {
-            buffer[i] = self.machine.peek(self.state.dma + i as u16);
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = self.machine.peek(self.state.dma + i as u16);
         }
         buffer
     }
@@ -10,6 +10,7 @@ use std::path::PathBuf;
 use crate::bdos_environment::*;
 use crate::constants::*;
 use crate::fcb::*;
+use crate::host_commands;
 use iz80::Machine;
 
 // Many file processing functions return a value in register A that is either
@@ -63,10 +64,17 @@ pub fn open(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     // from the first record.
     let mut fcb = Fcb::new(fcb_address);
     if env.call_trace {
-        print!("[[Open file {}]]", fcb.get_name_for_log(env));
+        let message = format!("[[Open file {}]]", fcb.get_name_for_log(env));
+        env.trace(&message);
     }
     match find_host_files(env, &fcb, false, false) {
-        Err(_) => FILE_NOT_FOUND, // Error or file not found
+        Err(_) => match host_command_stub(env, &fcb) {
+            Some(stub) => {
+                fcb.init(env, stub.len().div_ceil(RECORD_SIZE) as u32);
+                DIRECTORY_CODE
+            },
+            None => FILE_NOT_FOUND, // Error or file not found
+        },
         Ok(paths) => match fs::File::open(&paths[0]) {
             Err(_) => FILE_NOT_FOUND,
             Ok(os_file) => match os_file_size_records(os_file) {
@@ -94,7 +102,8 @@ pub fn make(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     // and thus a subsequent open is not necessary.
     let mut fcb = Fcb::new(fcb_address);
     if env.call_trace {
-        print!("[[Create file {}]]", fcb.get_name_for_log(env));
+        let message = format!("[[Create file {}]]", fcb.get_name_for_log(env));
+        env.trace(&message);
     }
     match create_file(env, &fcb) {
         Err(_) => FILE_NOT_FOUND, // Error or file not found
@@ -118,6 +127,7 @@ pub fn close(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     // necessary to record the new directory information permanently.
     let fcb = Fcb::new(fcb_address);
     match find_host_files(env, &fcb, false, false){
+        Err(_) if host_command_stub(env, &fcb).is_some() => DIRECTORY_CODE,
         Err(_) => FILE_NOT_FOUND, // Error or file not found
         Ok(paths) => match truncate_if_needed(env, &fcb, &paths[0]) {
             Err(_) => FILE_NOT_FOUND,
@@ -138,12 +148,13 @@ fn truncate_if_needed(env: &mut BdosEnvironment, fcb: &Fcb, os_file_name: &OsStr
     }
 
     if env.call_trace {
-        println!("Truncating file from {} to {}", record_count, fcb_record_count);
+        let message = format!("Truncating file from {} to {}\n", record_count, fcb_record_count);
+        env.trace(&message);
     }
 
     let file = fs::OpenOptions::new().write(true).open(os_file_name)?;
     file.set_len(fcb_record_count as u64 * RECORD_SIZE as u64)?;
-    return Ok(());
+    Ok(())
 }
 
 pub fn delete(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
@@ -155,7 +166,8 @@ pub fn delete(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     // be found; otherwise, a value in the range 0 to 3 returned.
     let fcb = Fcb::new(fcb_address);
     if env.call_trace {
-        print!("[[Delete file {}]]", fcb.get_name_for_log(env));
+        let message = format!("[[Delete file {}]]", fcb.get_name_for_log(env));
+        env.trace(&message);
     }
 
     match find_host_files(env, &fcb, true, true) {
@@ -184,7 +196,8 @@ pub fn set_attributes(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     // system expansion.
     let fcb = Fcb::new(fcb_address);
     if env.call_trace {
-        print!("[[Set attribuyes {}]]", fcb.get_name_for_log(env));
+        let message = format!("[[Set attribuyes {}]]", fcb.get_name_for_log(env));
+        env.trace(&message);
     }
 
     match find_host_files(env, &fcb, false, true) {
@@ -203,7 +216,8 @@ pub fn rename(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     // the first filename could not be found in the directory scan.
     let fcb = Fcb::new(fcb_address);
     if env.call_trace {
-        print!("[[Rename file {} to {}]]", fcb.get_name_for_log(env), fcb.get_name_secondary(env));
+        let message = format!("[[Rename file {} to {}]]", fcb.get_name_for_log(env), fcb.get_name_secondary(env));
+        env.trace(&message);
     }
     match find_host_files(env, &fcb, false, true) {
         Err(_) => FILE_NOT_FOUND, // Error or file not found
@@ -237,28 +251,26 @@ pub fn read(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     let mut fcb = Fcb::new(fcb_address);
     let record = fcb.get_sequential_record_number(env);
     if env.call_trace {
-        print!("[Read record {:x} into {:04x}]", record, env.state.dma);
+        let message = format!("[Read record {:x} into {:04x}]", record, env.state.dma);
+        env.trace(&message);
     }
 
     let extent_changed = fcb.inc_current_record(env);
 
     let mut buffer: Buffer = [0; RECORD_SIZE]; 
-    let res = read_record_in_buffer(env, &fcb, record as u16, &mut buffer).unwrap_or(NO_DATA);
+    let res = read_record_in_buffer(env, &fcb, record, &mut buffer).unwrap_or(NO_DATA);
     if res == DIRECTORY_CODE {
         env.store_buffer_to_dma(&buffer);
     }
 
-    if extent_changed {
-        match update_record_count(env, &mut fcb) {
-            Err(_) => return NO_DATA,
-            Ok(_) => {}
-        }
+    if extent_changed && update_record_count(env, &mut fcb).is_err() {
+        return NO_DATA;
     }
     res
 }
 
 fn update_record_count(env: &mut BdosEnvironment, fcb: &mut Fcb) -> io::Result<()> {
-    let record_count = compute_file_size_internal(env, &fcb)?;
+    let record_count = compute_file_size_internal(env, fcb)?;
     fcb.update_record_count(env, record_count);
     Ok(())
 }
@@ -279,16 +291,16 @@ pub fn write(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     let mut fcb = Fcb::new(fcb_address);
     let record = fcb.get_sequential_record_number(env);
     if env.call_trace {
-        print!("[Write record {:x} from {:04x}]", record, env.state.dma);
+        let message = format!("[Write record {:x} from {:04x}]", record, env.state.dma);
+        env.trace(&message);
     }
 
     let buffer = env.load_buffer_from_dma();
-    let result = write_record_from_buffer(env, &fcb, record as u16, &buffer).unwrap_or(NO_DATA);
+    let result = write_record_from_buffer(env, &fcb, record, &buffer).unwrap_or(NO_DATA);
 
     fcb.inc_current_record(env);
-    match update_record_count(env, &mut fcb) {
-        Err(_) => return NO_DATA,
-        Ok(_) => {}
+    if update_record_count(env, &mut fcb).is_err() {
+        return NO_DATA;
     }
 
     result
@@ -347,7 +359,8 @@ pub fn read_rand(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     let fcb = Fcb::new(fcb_address);
     let record = fcb.get_random_record_number(env);
     if env.call_trace {
-        print!("[Read random record {:x} into {:04x}]", record, env.state.dma);
+        let message = format!("[Read random record {:x} into {:04x}]", record, env.state.dma);
+        env.trace(&message);
     }
     if record > 65535 {
         return 6; //06	seek Past Physical end of disk
@@ -356,6 +369,11 @@ pub fn read_rand(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     let res = read_record_in_buffer(env, &fcb, record as u16, &mut buffer).unwrap_or(NO_DATA);
     if res == DIRECTORY_CODE {
         env.store_buffer_to_dma(&buffer);
+
+        // The extent and current record are set to the record read
+        let mut fcb = fcb;
+        fcb.set_sequential_record_number(env, record as u16);
+        let _ = update_record_count(env, &mut fcb);
     }
     res
 }
@@ -382,14 +400,25 @@ pub fn write_rand(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     let fcb = Fcb::new(fcb_address);
     let record = fcb.get_random_record_number(env);
     if env.call_trace {
-        print!("[Write random record {:x} into {:04x}]", record, env.state.dma);
+        let message = format!("[Write random record {:x} into {:04x}]", record, env.state.dma);
+        env.trace(&message);
     }
     if record > 65535 {
         return 6; //06	seek Past Physical end of disk
     }
 
     let buffer = env.load_buffer_from_dma();
-    write_record_from_buffer(env, &fcb, record as u16, &buffer).unwrap_or(NO_DATA)
+    let res = write_record_from_buffer(env, &fcb, record as u16, &buffer).unwrap_or(NO_DATA);
+    if res == DIRECTORY_CODE {
+        // The extent and current record are set to the record written, the
+        // record count has to be updated for close not to truncate the file.
+        let mut fcb = fcb;
+        fcb.set_sequential_record_number(env, record as u16);
+        if update_record_count(env, &mut fcb).is_err() {
+            return NO_DATA;
+        }
+    }
+    res
 }
 
 pub fn write_rand_zero_fill(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
@@ -466,7 +495,8 @@ pub fn set_random_record(env: &mut BdosEnvironment, fcb_address: u16) {
     // from the selected point in the file.
     let mut fcb = Fcb::new(fcb_address);
     if env.call_trace {
-        print!("[[Set pos of {}]]", fcb.get_name_for_log(env));
+        let message = format!("[[Set pos of {}]]", fcb.get_name_for_log(env));
+        env.trace(&message);
     }
     let record = fcb.get_sequential_record_number(env);
     fcb.set_random_record_number(env, record as u32);
@@ -493,7 +523,8 @@ pub fn search_first(env: &mut BdosEnvironment, fcb_address: u16) -> u8 {
     // field is not a question mark, the s2 byte is automatically zeroed.
     let fcb = Fcb::new(fcb_address);
     if env.call_trace {
-        print!("[[DIR start {}]]", fcb.get_name_for_log(env));
+        let message = format!("[[DIR start {}]]", fcb.get_name_for_log(env));
+        env.trace(&message);
     }
     env.state.dir_drive = fcb.get_drive(env);
     env.state.dir_pattern = fcb.get_name(env);
@@ -532,7 +563,8 @@ pub fn compute_file_size(env: &mut BdosEnvironment, fcb_address: u16) {
     // actually allocated.
     let mut fcb = Fcb::new(fcb_address);
     if env.call_trace {
-        print!("[[Size of {}]]", fcb.get_name_for_log(env));
+        let message = format!("[[Size of {}]]", fcb.get_name_for_log(env));
+        env.trace(&message);
     }
     let size = compute_file_size_internal(env, &fcb);
     match size {
@@ -544,7 +576,13 @@ pub fn compute_file_size(env: &mut BdosEnvironment, fcb_address: u16) {
 }
 
 fn compute_file_size_internal(env: &mut BdosEnvironment, fcb: &Fcb) -> io::Result<u32> {
-    let paths = find_host_files(env, fcb, false, false)?;
+    let paths = match find_host_files(env, fcb, false, false) {
+        Err(err) => return match host_command_stub(env, fcb) {
+            Some(stub) => Ok(stub.len().div_ceil(RECORD_SIZE) as u32),
+            None => Err(err),
+        },
+        Ok(paths) => paths,
+    };
     let os_file = fs::File::open(&paths[0])?;
     os_file_size_records(os_file)
 }
@@ -566,7 +604,7 @@ fn os_file_size_records(os_file: fs::File) -> io::Result<u32> {
 fn find_host_files(env: &mut BdosEnvironment, fcb: &Fcb, wildcard: bool, to_write: bool) -> io::Result<Vec<OsString>> {
     let fcb_drive = fcb.get_drive(env);
     let path = env.get_directory(fcb_drive, to_write)
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No directory assigned to drive"))?;
+        .ok_or_else(|| io::Error::other("No directory assigned to drive"))?;
     let dir = fs::read_dir(path)?;
     let mut files = Vec::new();
     for entry in dir {
@@ -589,17 +627,38 @@ fn find_host_files(env: &mut BdosEnvironment, fcb: &Fcb, wildcard: bool, to_writ
     }
 }
 
+// The COM file of a host command, when there is no file with that name
+fn host_command_stub(env: &mut BdosEnvironment, fcb: &Fcb) -> Option<Vec<u8>> {
+    if env.state.host_commands {
+        host_commands::stub(&fcb.get_name(env))
+    } else {
+        None
+    }
+}
+
 fn create_file(env: &mut BdosEnvironment, fcb: &Fcb) -> io::Result<()> {
     let fcb_drive = fcb.get_drive(env);
     let path = env.get_directory(fcb_drive, true)
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No directory assigned to drive"))?;
+        .ok_or_else(|| io::Error::other("No directory assigned to drive"))?;
     let file = Path::new(&path).join(fcb.get_name_host(env));
     fs::File::create(&file)?;
     Ok(())
 }
 
 fn read_record_in_buffer(env: &mut BdosEnvironment, fcb: &Fcb, record: u16, buffer: &mut Buffer) -> io::Result<u8> {
-    let paths = find_host_files(env, fcb, false, false)?;
+    let paths = match find_host_files(env, fcb, false, false) {
+        Err(err) => return match host_command_stub(env, fcb) {
+            Some(stub) if record == 0 => {
+                // The stubs fit in one record
+                buffer.fill(26); // (CTRL-Z)
+                buffer[..stub.len()].copy_from_slice(&stub);
+                Ok(0)
+            },
+            Some(_) => Ok(1), // End of file
+            None => Err(err),
+        },
+        Ok(paths) => paths,
+    };
     let mut os_file = fs::File::open(&paths[0])?;
 
     let file_offset = record as u64 * RECORD_SIZE as u64;
@@ -611,8 +670,8 @@ fn read_record_in_buffer(env: &mut BdosEnvironment, fcb: &Fcb, record: u16, buff
     let size = os_file.read(buffer)?;
 
     // Fill with ctrl-Z
-    for i in size..RECORD_SIZE {
-        buffer[i] = 26; // (CTRL-Z)
+    for value in buffer.iter_mut().skip(size) {
+        *value = 26; // (CTRL-Z)
     }
     Ok(0)
 }
@@ -643,7 +702,7 @@ fn search_nth(env: &mut BdosEnvironment) -> io::Result<u8> {
     // position. I don't know if BDOS was storing the state on the FCB or
     // globally. [Later] Yes, it does.
     let path = env.get_directory(env.state.dir_drive, false)
-        .ok_or(io::Error::new(io::ErrorKind::Other, "No directory assigned to drive"))?;
+        .ok_or(io::Error::other("No directory assigned to drive"))?;
     let dir = fs::read_dir(path)?;
 
     let mut i = 0;
@@ -679,10 +738,10 @@ fn build_directory_entry(cpm_name: String) -> Buffer {
     // Store name in the first entry
     let bytes = cpm_name.as_bytes();
     for i in 0..8 {
-        buffer[1+i] = 0x7F & bytes[i as usize];
+        buffer[1+i] = 0x7F & bytes[i];
     }
     for i in 0..3 {
-        buffer[9+i] = 0x7F & bytes[9 + i as usize];
+        buffer[9+i] = 0x7F & bytes[9 + i];
     }
 
     // This user-number byte serves a second purpose. If this byte is set to a
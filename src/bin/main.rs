@@ -1,6 +1,36 @@
-use izcpm::{run, Console};
+use std::env;
+use std::process;
+
+use izcpm::{run, run_script_file, serve, parse_command_line, Console, Mode};
 
 fn main() {
-    let mut console = Console::new();
-    run(None, &mut console);
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mode = match parse_command_line(args) {
+        Ok(mode) => mode,
+        Err(exit_code) => process::exit(exit_code),
+    };
+
+    match mode {
+        Mode::Script(script, args) => {
+            // Test script mode, the script replaces the console
+            let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+            if let Err(failure) = run_script_file(&script, args) {
+                eprintln!("{}", failure);
+                process::exit(1);
+            }
+        },
+        Mode::Listen { address, session_dir, max_sessions, args } => {
+            // Server mode, a telnet session per connection
+            if let Err(err) = serve(&address, args, session_dir, max_sessions) {
+                eprintln!("Error listening on \"{}\": {}", address, err);
+                process::exit(1);
+            }
+        },
+        Mode::Console(args) => {
+            let mut console = Console::new();
+            let exit_code = run(Some(args.iter().map(|arg| arg.as_str()).collect()), &mut console);
+            drop(console); // Restores the terminal, process::exit doesn't run the destructors
+            process::exit(exit_code);
+        },
+    }
+}
@@ -1,52 +1,108 @@
+use std::thread;
+use std::time::Duration;
+
 use iz80::*;
 
 use crate::cpm_machine::*;
 use crate::constants::*;
 use crate::console_emulator::ConsoleEmulator;
+use crate::keyboard::KeyTranslator;
+use crate::memory_map::MemoryMap;
+use crate::symbols::SymbolTable;
 use crate::terminal::TerminalEmulator;
 
 
 pub struct Bios {
     terminal: Box<dyn TerminalEmulator>,
-    ctrl_c_count: u8
+    keyboard: KeyTranslator,
+    ctrl_c_count: u8,
+    xmove_banks: Option<(u8, u8)>,
 }
 
-const BIOS_COMMAND_NAMES: [&str; 17] = [
+const BIOS_COMMAND_NAMES: [&str; 30] = [
     "BOOT", "WBOOT", "CONST", "CONIN", "CONOUT",
     "LIST", "PUNCH", "READER", "HOME", "SELDSK",
     "SETTRK", "SETSEC", "SETDMA", "READ", "WRITE",
-    "LISTST", "SECTRAN"];
+    "LISTST", "SECTRAN",
+    // CP/M 3
+    "CONOST", "AUXIST", "AUXOST", "DEVTBL", "DEVINI",
+    "DRVTBL", "MULTIO", "FLUSH", "MOVE", "TIME",
+    "SELMEM", "SETBNK", "XMOVE"];
+
+pub const BIOS_ENTRY_POINT_COUNT: usize = 30;
+const BIOS_RET_TRAP_OFFSET: u16 = 0x80;
+
+// Returns the BIOS command trapped at the given address, if any
+pub fn bios_command(map: &MemoryMap, pc: u16) -> Option<u16> {
+    let offset = pc.wrapping_sub(map.bios);
+    if (BIOS_RET_TRAP_OFFSET..0x100).contains(&offset) {
+        Some(offset - BIOS_RET_TRAP_OFFSET)
+    } else {
+        None
+    }
+}
 
-const BIOS_ENTRY_POINT_COUNT: usize = 30;
-const BIOS_RET_TRAP_START: u16 = BIOS_BASE_ADDRESS + 0x80;
+pub fn bios_command_name(command: u16) -> &'static str {
+    if command < BIOS_COMMAND_NAMES.len() as u16 {
+        BIOS_COMMAND_NAMES[command as usize]
+    } else {
+        "unknown"
+    }
+}
 
 impl Bios {
     pub fn new(terminal: Box<dyn TerminalEmulator>) -> Bios {
         Bios {
             terminal,
-            ctrl_c_count: 0
+            keyboard: KeyTranslator::default(),
+            ctrl_c_count: 0,
+            xmove_banks: None,
         }
     }
 
+    pub fn set_keyboard(&mut self, keyboard: KeyTranslator) {
+        self.keyboard = keyboard;
+    }
+
     pub fn setup(&self, machine: &mut CpmMachine) {
+        let bios_base = machine.memory_map().bios;
         // Setup warm start at 0x000
         machine.poke(0, 0xc3 /* jp nnnn */);
-        machine.poke16(1, BIOS_BASE_ADDRESS + 3); // Warm start is the second entrypoint in BIOS
+        machine.poke16(1, bios_base + 3); // Warm start is the second entrypoint in BIOS
 
-        // At BIOS_BASE_ADDRESS we need a "JMP address" for each entry point. At
+        // At the BIOS base we need a "JMP address" for each entry point. At
         // the destination we will put a RET and trap that on the emulator.
         // Programs like MBASIC expect this and copy the address.
         for i in 0..BIOS_ENTRY_POINT_COUNT {
-            let entry_point = BIOS_BASE_ADDRESS + (i * 3) as u16;
-            let ret_trap = BIOS_RET_TRAP_START + i as u16;
+            let entry_point = bios_base + (i * 3) as u16;
+            let ret_trap = bios_base + BIOS_RET_TRAP_OFFSET + i as u16;
             machine.poke(entry_point, 0xc3 /* jp nnnn */);
             machine.poke16(entry_point+1, ret_trap);
             machine.poke(ret_trap, 0xc9 /*ret*/);
         }
     }
 
+    pub fn add_symbols(&self, map: &MemoryMap, symbols: &mut SymbolTable) {
+        for i in 0..BIOS_ENTRY_POINT_COUNT {
+            let name = match bios_command_name(i as u16) {
+                "unknown" => format!("BIOS_{}", i),
+                name => format!("BIOS_{}", name),
+            };
+            symbols.add(map.bios + (i * 3) as u16, &name);
+            symbols.add(map.bios + BIOS_RET_TRAP_OFFSET + i as u16, &format!("{}_TRAP", name));
+        }
+    }
+
+    fn receive_keys(&mut self, console: &mut dyn ConsoleEmulator) {
+        while !self.keyboard.is_ready() && console.status() {
+            self.keyboard.input(console.read());
+        }
+        self.keyboard.check_timeout();
+    }
+
     pub fn status(&mut self, console: &mut dyn ConsoleEmulator) -> u8 {
-        if console.status() {
+        self.receive_keys(console);
+        if self.keyboard.is_ready() {
             0xff
         } else {
             0
@@ -54,8 +110,20 @@ impl Bios {
     }
 
     pub fn read(&mut self, console: &mut dyn ConsoleEmulator) -> u8 {
-        let ch = console.read();
-        if ch == 3 { // Control-C
+        let (ch, typed) = loop {
+            if let Some(key) = self.keyboard.pop() {
+                break key;
+            }
+            if self.keyboard.is_waiting() {
+                // Wait for the rest of the escape sequence
+                thread::sleep(Duration::from_millis(1));
+                self.receive_keys(console);
+            } else {
+                // Blocks waiting for a key
+                self.keyboard.input(console.read());
+            }
+        };
+        if ch == 3 && typed { // Control-C, not from a key like ^C for PgDn
             self.ctrl_c_count += 1;
         } else {
             self.ctrl_c_count = 0;
@@ -75,11 +143,26 @@ impl Bios {
     }
 
 
+    /// Called while the CPU is halted waiting for an interrupt, there may be
+    /// no program reading the keyboard. Takes the control-c typed, two of
+    /// them stop as usual.
+    pub fn poll_halted(&mut self, console: &mut dyn ConsoleEmulator) -> ExecutionResult {
+        self.receive_keys(console);
+        if self.keyboard.peek() == Some((3, true)) {
+            self.read(console);
+        }
+        if self.stop() {
+            self.ctrl_c_count = 0;
+            return ExecutionResult::StopConfirm;
+        }
+        ExecutionResult::Continue
+    }
+
     pub fn stop(&self) -> bool {
         self.ctrl_c_count > 1
     }
 
-    pub fn execute(&mut self, console: &mut dyn ConsoleEmulator, reg: &mut Registers, call_trace: bool) -> ExecutionResult {
+    pub fn execute(&mut self, console: &mut dyn ConsoleEmulator, machine: &mut CpmMachine, reg: &mut Registers, call_trace: bool) -> ExecutionResult {
         if self.stop() {
             // Stop with two control-c
             self.ctrl_c_count = 0;
@@ -87,15 +170,9 @@ impl Bios {
         }
 
         let pc = reg.pc();
-        if pc >= BIOS_RET_TRAP_START {
-            let command = pc - BIOS_RET_TRAP_START;
+        if let Some(command) = bios_command(machine.memory_map(), pc) {
             if call_trace {
-                let name = if command < BIOS_COMMAND_NAMES.len() as u16 {
-                    BIOS_COMMAND_NAMES[command as usize]
-                } else {
-                    "unknown"
-                };
-                println!("[[BIOS command {}: {}]]", command, name);
+                console.trace(&format!("[[BIOS command {}: {}]]\n", command, bios_command_name(command)));
             }
             /*
             See: http://www.gaby.de/cpm/manuals/archive/cpm22htm/ch6.htm#Table_6-5
@@ -120,11 +197,12 @@ impl Bios {
             */
             match command {
                 0 => { // BOOT: Cold Start Routine
-                    println!("Terminated. cold restart");
+                    console.message("Terminated. cold restart\n");
                     return ExecutionResult::ColdBoot;
                 }
                 1 => { // WBOOT: Warm boot.
                     // Reload command processor. We will go back to the host.
+                    machine.select_bank(0);
                     return ExecutionResult::WarmBoot;
                 }
                 2 => { // CONST: Check for console ready
@@ -155,8 +233,39 @@ impl Bios {
                     // Siegler terminal to clear the screen, for example). 
                     self.write(console, reg.get8(Reg8::C));
                 }
+                25 => { // MOVE: Memory to memory move (CP/M 3)
+                    // Moves BC bytes from DE to HL, returns HL and DE
+                    // pointing to the next bytes. Between banks if XMOVE
+                    // was called before.
+                    let mut source = reg.get16(Reg16::DE);
+                    let mut destination = reg.get16(Reg16::HL);
+                    let count = reg.get16(Reg16::BC);
+                    let bank = machine.bank();
+                    let (destination_bank, source_bank) = self.xmove_banks.take().unwrap_or((bank, bank));
+                    for _ in 0..count {
+                        let value = machine.peek_bank(source_bank, source);
+                        machine.poke_bank(destination_bank, destination, value);
+                        source = source.wrapping_add(1);
+                        destination = destination.wrapping_add(1);
+                    }
+                    reg.set16(Reg16::DE, source);
+                    reg.set16(Reg16::HL, destination);
+                }
+                27 => { // SELMEM: Select memory bank (CP/M 3)
+                    // The bank in A is selected, the caller must be on the
+                    // common area.
+                    machine.select_bank(reg.a());
+                }
+                28 => { // SETBNK: Set bank for DMA (CP/M 3)
+                    // Ignored, the disk access is done by the BDOS on the
+                    // bank selected.
+                }
+                29 => { // XMOVE: Set banks for the next MOVE (CP/M 3)
+                    // B is the destination bank and C the source bank.
+                    self.xmove_banks = Some((reg.get8(Reg8::B), reg.get8(Reg8::C)));
+                }
                 _ => {
-                    eprintln!("BIOS command {} not implemented.\n", command);
+                    console.message(&format!("BIOS command {} not implemented.\n", command));
                     return ExecutionResult::Stop;
                 }    
             }
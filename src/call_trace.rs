@@ -0,0 +1,368 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+
+use iz80::*;
+
+use crate::bdos::{Bdos, bdos_command_name};
+use crate::bios::{BIOS_ENTRY_POINT_COUNT, bios_command, bios_command_name};
+use crate::cpm_machine::CpmMachine;
+use crate::fcb::{name_from_8_3, name_to_8_3};
+use crate::json::JsonObject;
+use crate::symbols::SymbolTable;
+
+/*
+Structured trace of the BDOS and BIOS calls. Unlike the -t and -T traces,
+that are mixed with the console output, the events are sent to a file, to
+stderr or to a callback provided by the library user. On files and stderr
+each event is written as a JSON object on its own line:
+
+{"time_us":1520,"type":"bdos","function":15,"name":"F_OPEN","de":92,
+"caller":368,"caller_symbol":"OPEN+0x3","drive":"A","file":"DATA.TXT",
+"result":0}
+
+The arguments are decoded before the call is executed, the result is taken
+from HL (BDOS) or A (BIOS) after it.
+*/
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CallKind {
+    Bdos,
+    Bios,
+}
+
+impl CallKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CallKind::Bdos => "bdos",
+            CallKind::Bios => "bios",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CallTraceEvent {
+    pub time_us: u64,
+    pub kind: CallKind,
+    pub function: u8,
+    pub name: &'static str,
+    pub de: u16,
+    pub caller: u16,
+    pub caller_symbol: Option<String>,
+    pub drive: Option<u8>, // 0 for A:
+    pub file: Option<String>, // As in the FCB, 8.3 padded with spaces
+    pub new_file: Option<String>, // Second name of F_RENAME
+    pub dma: Option<u16>,
+    pub record: Option<u32>,
+    pub result: Option<u16>,
+}
+
+impl CallTraceEvent {
+    pub fn to_json(&self) -> String {
+        let mut json = JsonObject::new()
+            .num("time_us", self.time_us)
+            .str("type", self.kind.name())
+            .num("function", self.function)
+            .str("name", self.name);
+        if self.kind == CallKind::Bdos {
+            json = json.num("de", self.de);
+        } else {
+            json = json.num("c", self.de & 0xff);
+        }
+        json = json.num("caller", self.caller);
+        if let Some(symbol) = &self.caller_symbol {
+            json = json.str("caller_symbol", symbol);
+        }
+        if let Some(drive) = self.drive {
+            json = json.str("drive", &((b'A' + drive) as char).to_string());
+        }
+        if let Some(file) = &self.file {
+            json = json.str("file", &name_from_8_3(file));
+        }
+        if let Some(file) = &self.new_file {
+            json = json.str("new_file", &name_from_8_3(file));
+        }
+        if let Some(dma) = self.dma {
+            json = json.num("dma", dma);
+        }
+        if let Some(record) = self.record {
+            json = json.num("record", record);
+        }
+        if let Some(result) = self.result {
+            json = json.num("result", result);
+        }
+        json.build()
+    }
+}
+
+pub type CallTraceCallback = Box<dyn FnMut(&CallTraceEvent)>;
+
+pub enum CallTraceSink {
+    Stderr,
+    File(BufWriter<File>),
+    Callback(CallTraceCallback),
+}
+
+impl CallTraceSink {
+    /// Opens a sink by name, "-" or "stderr" are the standard error.
+    pub fn open(name: &str) -> io::Result<CallTraceSink> {
+        if name == "-" || name == "stderr" {
+            Ok(CallTraceSink::Stderr)
+        } else {
+            Ok(CallTraceSink::File(BufWriter::new(File::create(name)?)))
+        }
+    }
+
+    fn send(&mut self, event: &CallTraceEvent) {
+        // Errors writing the trace are ignored, we don't want to stop the
+        // emulation for them.
+        match self {
+            CallTraceSink::Stderr => eprintln!("{}", event.to_json()),
+            CallTraceSink::File(file) => {
+                let _ = writeln!(file, "{}", event.to_json());
+            },
+            CallTraceSink::Callback(callback) => callback(event),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let CallTraceSink::File(file) = self {
+            let _ = file.flush();
+        }
+    }
+}
+
+/*
+Filters. Each kind of filter is optional, an event has to pass all the
+filters given. When filtering by drive or by file, the calls without a drive
+or file are discarded.
+*/
+pub struct CallTraceFilter {
+    functions: Vec<(CallKind, u8)>,
+    drives: Vec<u8>,
+    file_pattern: Option<String>,
+}
+
+impl CallTraceFilter {
+    pub fn new() -> CallTraceFilter {
+        CallTraceFilter {
+            functions: Vec::new(),
+            drives: Vec::new(),
+            file_pattern: None,
+        }
+    }
+
+    /// Adds a function by BDOS number or by BDOS or BIOS name.
+    pub fn add_function(&mut self, spec: &str) -> bool {
+        if let Ok(number) = spec.parse::<u8>() {
+            self.functions.push((CallKind::Bdos, number));
+            return true;
+        }
+        let name = spec.to_ascii_uppercase();
+        if let Some(number) = (0..=255).find(|&n| bdos_command_name(n) == name) {
+            self.functions.push((CallKind::Bdos, number));
+            return true;
+        }
+        if let Some(number) = (0..BIOS_ENTRY_POINT_COUNT as u16).find(|&n| bios_command_name(n) == name) {
+            self.functions.push((CallKind::Bios, number as u8));
+            return true;
+        }
+        false
+    }
+
+    /// Adds a drive as a letter, with or without the colon.
+    pub fn add_drive(&mut self, spec: &str) -> bool {
+        let spec = spec.trim_end_matches(':').to_ascii_uppercase();
+        match spec.as_bytes() {
+            [letter @ b'A'..=b'P'] => {
+                self.drives.push(letter - b'A');
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Sets an ambiguous file name, like *.COM or DATA?.TXT
+    pub fn set_file_pattern(&mut self, pattern: &str) -> bool {
+        self.file_pattern = expand_wildcards(pattern);
+        self.file_pattern.is_some()
+    }
+
+    pub fn matches(&self, event: &CallTraceEvent) -> bool {
+        if !self.functions.is_empty()
+                && !self.functions.contains(&(event.kind, event.function)) {
+            return false;
+        }
+        if !self.drives.is_empty() {
+            match event.drive {
+                Some(drive) if self.drives.contains(&drive) => (),
+                _ => return false,
+            }
+        }
+        if let Some(pattern) = &self.file_pattern {
+            let matched = [&event.file, &event.new_file].iter().any(|file| match file {
+                Some(name) => name_match_ignore_case(name, pattern),
+                None => false,
+            });
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub struct CallTracer {
+    sinks: Vec<CallTraceSink>,
+    filter: CallTraceFilter,
+    bios: bool,
+    start: Instant,
+    pending: Option<CallTraceEvent>,
+}
+
+impl CallTracer {
+    pub fn new(filter: CallTraceFilter, bios: bool) -> CallTracer {
+        CallTracer {
+            sinks: Vec::new(),
+            filter,
+            bios,
+            start: Instant::now(),
+            pending: None,
+        }
+    }
+
+    pub fn add_sink(&mut self, sink: CallTraceSink) {
+        self.sinks.push(sink);
+    }
+
+    /// Call before the BIOS and BDOS traps are processed. Decodes the call
+    /// if the PC is on one of the traps.
+    pub fn before(&mut self, machine: &CpmMachine, reg: &Registers, bdos: &Bdos,
+            symbols: &SymbolTable) {
+        let pc = reg.pc();
+        let (kind, function, name) = if pc == machine.memory_map().bdos {
+            let function = reg.get8(Reg8::C);
+            (CallKind::Bdos, function, bdos_command_name(function))
+        } else if let Some(command) = bios_command(machine.memory_map(), pc) {
+            if !self.bios {
+                return;
+            }
+            (CallKind::Bios, command as u8, bios_command_name(command))
+        } else {
+            return;
+        };
+
+        // The return address of the CALL is on the top of the stack
+        let caller = machine.peek16(reg.get16(Reg16::SP)).wrapping_sub(3);
+        let de = reg.get16(Reg16::DE);
+        let mut event = CallTraceEvent {
+            time_us: self.start.elapsed().as_micros() as u64,
+            kind,
+            function,
+            name,
+            de,
+            caller,
+            caller_symbol: symbols.lookup(caller),
+            drive: None,
+            file: None,
+            new_file: None,
+            dma: None,
+            record: None,
+            result: None,
+        };
+        if kind == CallKind::Bdos {
+            decode_bdos_args(&mut event, machine, bdos);
+        }
+        if self.filter.matches(&event) {
+            self.pending = Some(event);
+        }
+    }
+
+    /// Call after the BIOS and BDOS traps are processed to send the event
+    /// with the result.
+    pub fn after(&mut self, reg: &Registers) {
+        if let Some(mut event) = self.pending.take() {
+            event.result = Some(match event.kind {
+                CallKind::Bdos => reg.get16(Reg16::HL),
+                CallKind::Bios => reg.a() as u16,
+            });
+            for sink in self.sinks.iter_mut() {
+                sink.send(&event);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for sink in self.sinks.iter_mut() {
+            sink.flush();
+        }
+    }
+}
+
+fn decode_bdos_args(event: &mut CallTraceEvent, machine: &CpmMachine, bdos: &Bdos) {
+    let fcb = event.de;
+    let uses_fcb = matches!(event.function,
+        15 | 16 | 17 | 19 | 20 | 21 | 22 | 23 | 30 | 33 | 34 | 35 | 36 | 40);
+    if uses_fcb {
+        let drive = machine.peek(fcb);
+        event.drive = Some(if drive == 0 || drive > 16 {
+            bdos.current_drive()
+        } else {
+            drive - 1
+        });
+        event.file = Some(fcb_name(machine, fcb));
+    }
+    match event.function {
+        14 => event.drive = Some((event.de & 0x0f) as u8), // DRV_SET
+        23 => event.new_file = Some(fcb_name(machine, fcb.wrapping_add(16))), // F_RENAME
+        26 => event.dma = Some(event.de), // F_DMAOFF
+        _ => ()
+    }
+    match event.function {
+        17 | 18 | 20 | 21 | 33 | 34 | 40 => event.dma = Some(bdos.dma()),
+        _ => ()
+    }
+    match event.function {
+        20 | 21 => { // Sequential: extent and current record
+            event.record = Some(128 * machine.peek(fcb.wrapping_add(12)) as u32
+                + machine.peek(fcb.wrapping_add(32)) as u32);
+        },
+        33 | 34 | 40 => { // Random: r0, r1, r2
+            event.record = Some(machine.peek(fcb.wrapping_add(33)) as u32
+                + ((machine.peek(fcb.wrapping_add(34)) as u32) << 8)
+                + ((machine.peek(fcb.wrapping_add(35)) as u32) << 16));
+        },
+        _ => ()
+    }
+}
+
+fn fcb_name(machine: &CpmMachine, address: u16) -> String {
+    let mut name = String::new();
+    for i in 0..11 {
+        if i == 8 {
+            name.push('.');
+        }
+        let ch = machine.peek(address.wrapping_add(1 + i)) & 0x7f;
+        name.push(ch as char);
+    }
+    name
+}
+
+// Converts "*.COM" to "????????.COM", as the CCP does.
+fn expand_wildcards(pattern: &str) -> Option<String> {
+    let (name, extension) = match pattern.split_once('.') {
+        Some((name, extension)) => (name, extension),
+        None => (pattern, ""),
+    };
+    let expand = |part: &str, size: usize| match part.find('*') {
+        Some(index) => format!("{:?<size$}", &part[..index], size = size),
+        None => part.to_string(),
+    };
+    name_to_8_3(&format!("{}.{}", expand(name, 8), expand(extension, 3)))
+}
+
+fn name_match_ignore_case(name: &str, pattern: &str) -> bool {
+    name.bytes().zip(pattern.bytes()).all(|(n, p)|
+        p == b'?' || n.eq_ignore_ascii_case(&p))
+}
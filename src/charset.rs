@@ -0,0 +1,192 @@
+use crate::terminal::TerminalEmulator;
+
+/*
+Graphics character sets of the machines, mapped to Unicode for an UTF-8 host
+terminal. The charset layer goes in front of the terminal emulator: the
+graphics chars are converted here, everything else, and all the bytes of the
+escape sequences, go to the terminal emulator.
+
+See:
+    http://skookumpete.com/KayproGraphics.htm
+    http://bitsavers.org/pdf/osborne/Osborne_1_Technical_Manual.pdf
+
+Kaypro '84
+    ESC,B,5 enters the video mode and ESC,C,5 exits it. On video mode the chars
+    0x80 to 0xFF are pixel blocks of 2x4 pixels, with the bits 0 to 6 from the
+    top left to the bottom, two pixels per row. The last row has a single bit
+    for both pixels. We show them with the 2x2 quadrant blocks, a quadrant is
+    set if any of its pixels is set. Out of the video mode, the high bit is
+    ignored as before.
+
+Osborne 1
+    ESC,g enters the graphics mode and ESC,G exits it. On graphics mode the
+    chars 0x00 to 0x1F, but ESC, are the block and line drawing graphics of
+    the character ROM instead of control chars.
+*/
+
+const ESC: u8 = 27;
+
+// Indexed by a bit mask: 1 upper left, 2 upper right, 4 lower left, 8 lower right
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛',
+    '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+
+const OSBORNE_LINES: [char; 16] = [
+    '─', '│', '┌', '┐', '└', '┘', '├', '┤',
+    '┬', '┴', '┼', '↑', '↓', '←', '→', '▒',
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Charset {
+    Kaypro84,
+    Osborne,
+}
+
+impl Charset {
+    pub fn from_name(name: &str) -> Option<Charset> {
+        match name {
+            "kaypro" | "kaypro84" => Some(Charset::Kaypro84),
+            "osborne" => Some(Charset::Osborne),
+            _ => None,
+        }
+    }
+}
+
+pub struct CharsetLayer {
+    terminal: Box<dyn TerminalEmulator>,
+    charset: Charset,
+    sequence: Vec<u8>, // Bytes sent to the terminal emulator on an unfinished sequence
+    escape_held: bool,
+    graphics: bool,
+}
+
+impl CharsetLayer {
+    pub fn new(charset: Charset, terminal: Box<dyn TerminalEmulator>) -> CharsetLayer {
+        CharsetLayer {
+            terminal,
+            charset,
+            sequence: Vec::new(),
+            escape_held: false,
+            graphics: false,
+        }
+    }
+
+    fn graphics_char(&self, ch: u8) -> Option<char> {
+        if !self.graphics || ch == ESC {
+            return None;
+        }
+        match self.charset {
+            Charset::Kaypro84 if ch >= 0x80 => Some(kaypro_pixels(ch)),
+            Charset::Osborne if ch < 0x10 => Some(QUADRANTS[ch as usize]),
+            Charset::Osborne if ch < 0x20 => Some(OSBORNE_LINES[(ch - 0x10) as usize]),
+            _ => None,
+        }
+    }
+
+    fn send(&mut self, ch: u8) -> Option<String> {
+        self.sequence.push(ch);
+        let conversion = self.terminal.translate(ch);
+        if conversion.is_some() {
+            if self.charset == Charset::Kaypro84 {
+                match self.sequence.as_slice() {
+                    [ESC, b'B', b'5'] => self.graphics = true,
+                    [ESC, b'C', b'5'] => self.graphics = false,
+                    _ => (),
+                }
+            }
+            self.sequence.clear();
+        }
+        conversion
+    }
+}
+
+fn kaypro_pixels(ch: u8) -> char {
+    let bits = ch & 0x7f;
+    let mut mask = 0;
+    if bits & 0b0000101 != 0 { mask |= 1; } // Rows 0 and 1 left
+    if bits & 0b0001010 != 0 { mask |= 2; } // Rows 0 and 1 right
+    if bits & 0b1010000 != 0 { mask |= 4; } // Rows 2 and 3 left
+    if bits & 0b1100000 != 0 { mask |= 8; } // Rows 2 and 3 right
+    QUADRANTS[mask]
+}
+
+impl TerminalEmulator for CharsetLayer {
+    fn translate(&mut self, ch: u8) -> Option<String> {
+        if self.escape_held {
+            // Osborne graphics mode sequences are handled here, other
+            // sequences go to the terminal emulator.
+            self.escape_held = false;
+            match ch {
+                b'g' => {
+                    self.graphics = true;
+                    return Some("".to_string());
+                },
+                b'G' => {
+                    self.graphics = false;
+                    return Some("".to_string());
+                },
+                _ => {
+                    if let Some(conversion) = self.send(ESC) {
+                        // The terminal emulator didn't need more bytes
+                        return match self.translate(ch) {
+                            Some(next) => Some(conversion + &next),
+                            None => Some(conversion),
+                        };
+                    }
+                    return self.send(ch);
+                }
+            }
+        }
+
+        if self.sequence.is_empty() {
+            if let Some(graphics) = self.graphics_char(ch) {
+                return Some(graphics.to_string());
+            }
+            if ch == ESC && self.charset == Charset::Osborne {
+                self.escape_held = true;
+                return None;
+            }
+        }
+        self.send(ch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal_adm3a::Adm3aToAnsi;
+
+    fn translate_all(layer: &mut CharsetLayer, input: &[u8]) -> String {
+        input.iter().filter_map(|&ch| layer.translate(ch)).collect()
+    }
+
+    #[test]
+    fn test_kaypro_video_mode() {
+        let mut layer = CharsetLayer::new(Charset::Kaypro84, Box::new(Adm3aToAnsi::new()));
+        // Out of video mode the high bit is ignored
+        assert_eq!(translate_all(&mut layer, &[0xc1]), "A");
+        assert_eq!(translate_all(&mut layer, &[ESC, b'B', b'5', 0x80, 0xff, 0x83, 0xf0]), " █▀▄");
+        assert_eq!(translate_all(&mut layer, &[ESC, b'C', b'5', 0xc1]), "A");
+    }
+
+    #[test]
+    fn test_kaypro_sequences_go_to_the_terminal() {
+        let mut layer = CharsetLayer::new(Charset::Kaypro84, Box::new(Adm3aToAnsi::new()));
+        assert_eq!(translate_all(&mut layer, &[ESC, b'B', b'5', ESC, b'=', 0x80 + 33, 0x80 + 34]), "\x1b[2;3H");
+    }
+
+    #[test]
+    fn test_osborne_graphics_mode() {
+        let mut layer = CharsetLayer::new(Charset::Osborne, Box::new(Adm3aToAnsi::new()));
+        assert_eq!(translate_all(&mut layer, &[0x0b, b'a']), "\x1b[Aa");
+        assert_eq!(translate_all(&mut layer, &[ESC, b'g', 0x12, 0x10, 0x13, 0x0f, ESC, b'G', b'a']), "┌─┐█a");
+    }
+
+    #[test]
+    fn test_osborne_other_escapes() {
+        let mut layer = CharsetLayer::new(Charset::Osborne, Box::new(Adm3aToAnsi::new()));
+        assert_eq!(translate_all(&mut layer, &[ESC, b'=', 32 + 1, 32 + 2, b'x']), "\x1b[2;3Hx");
+        assert_eq!(translate_all(&mut layer, &[ESC, b'R']), "\x1b[L");
+    }
+}
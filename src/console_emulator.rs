@@ -1,6 +1,28 @@
+use std::io::{stdout, Write};
+
+// Not every instruction, to keep the cost low
+pub const TICK_INSTRUCTIONS: u32 = 128;
+
 pub trait ConsoleEmulator {
     fn status(&mut self) -> bool;
     fn read(&mut self) -> u8;
     fn put(&mut self, sequence: Option<String>);
     fn terminated(&self) -> bool;
-}
\ No newline at end of file
+
+    // Called every TICK_INSTRUCTIONS instructions executed, with the count
+    fn tick(&mut self, _instructions: u32) {}
+
+    // Text from the emulator, not from the CP/M program, like the welcome
+    // message. Consoles not on the process stdout, like the telnet sessions,
+    // send it to their own output.
+    fn message(&mut self, text: &str) {
+        print!("{}", text);
+        let _ = stdout().flush();
+    }
+
+    // The -t and -T traces, on stderr not to mix them with the output of
+    // the program
+    fn trace(&mut self, text: &str) {
+        eprint!("{}", text);
+    }
+}
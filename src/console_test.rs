@@ -1,104 +1,407 @@
 use core::panic;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
 
 use crate::console_emulator::ConsoleEmulator;
+use crate::screen::Screen;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Timeout {
+    StatusCalls(u32),
+    Instructions(u64),
+    WallTime(Duration),
+}
 
 pub enum Step <'a> {
     Input(&'a str),
+    Key(&'a str),
     Expect(&'a str),
     ExpectTimeout(&'a str, u32),
+    ExpectRegex(&'a str),
+    ExpectNot(&'a str),
+    ExpectRow(usize, &'a str),
+    ExpectCursor(usize, usize),
+    ExpectScreen(&'a str),
+    SetTimeout(Timeout),
 }
 
 /*
 When Input is found, it is added to the input queue. We wait for this to be consumed ignoring
-the output. When completed, we move to the next step.
+the output. When completed, we move to the next step. The ${name} in the input are replaced
+with the captures of the regular expressions, ${1} for the first group. Key sends a key by
+name, like "ctrl-c", "^C", "enter" or "esc".
 
 When Expect is found, we collect the output until the expected output is found as a substring.
 We wait at most the nuber give of calls to status(). When found, we move to the next step.
+ExpectRegex is the same with a regular expression, the named and numbered groups are captured.
+ExpectNot waits for the whole timeout, or until the program waits for a key, and fails if
+the text is written.
+
+The output is also sent to a virtual screen of 80x24 chars. ExpectRow waits until the row
+contains the text, ExpectCursor until the cursor is on the row and column and ExpectScreen
+until the screen snapshot, without the trailing spaces and empty rows, is the text. Rows and
+columns are 1-based.
 
-When all the steps are completed, the test is passed.
+The expectations wait 100 calls to status() by default. SetTimeout changes it for the next
+steps to a number of calls to status(), of instructions executed, counted on each tick(), or
+to a wall time.
+
+When all the steps are completed, the test is passed and the run is terminated. With
+set_stop_when_done(false) the program goes on until it ends, or until it reads a key. On
+failure, it panics with the step, the steps around it, the output collected and the screen.
+With set_panic_on_failure(false) the test is terminated instead, and the message is available
+with failure().
 */
 
+const DEFAULT_TIMEOUT: Timeout = Timeout::StatusCalls(100);
+
+enum Expectation <'a> {
+    Nothing,
+    Text(&'a str),
+    Regex(Regex),
+    Not(&'a str),
+    Row(usize, &'a str),
+    Cursor(usize, usize),
+    Snapshot(&'a str),
+}
 
 pub struct ConsoleTest <'a> {
     input: VecDeque<u8>,
-    expected_output: Option<&'a str>,
+    expectation: Expectation<'a>,
     current_output: String,
-    current_count_left: u32,
+    current_count_left: Option<u32>,
+    instructions_left: Option<u64>,
+    deadline: Option<Instant>,
+    timeout: Timeout,
+    captures: HashMap<String, String>,
+    screen: Screen,
 
     script: Vec<Step<'a>>,
     step: usize,
     terminate: bool,
+    done: bool,
+    stop_when_done: bool,
+    panic_on_failure: bool,
+    failure: Option<String>,
 }
 
 impl <'a> ConsoleTest <'a> {
-    pub fn new(mut script: Vec<Step>) -> ConsoleTest {
-        script.reverse(); // Reverse to pop from the end
-
+    pub fn new(script: Vec<Step>) -> ConsoleTest {
         let mut c = ConsoleTest {
             input : VecDeque::new(),
-            expected_output: None,
+            expectation: Expectation::Nothing,
             current_output: String::new(),
-            current_count_left: 0,
+            current_count_left: None,
+            instructions_left: None,
+            deadline: None,
+            timeout: DEFAULT_TIMEOUT,
+            captures: HashMap::new(),
+            screen: Screen::new(),
 
             script,
             step: 0,
             terminate: false,
+            done: false,
+            stop_when_done: true,
+            panic_on_failure: true,
+            failure: None,
         };
 
-        c.next_step();
+        c.start_step();
         c
     }
 
-    fn next_step(&mut self) {
-        self.input.clear();
-        self.expected_output = None;
-        self.current_output.clear();
-        self.current_count_left = 0;
+    pub fn set_panic_on_failure(&mut self, panic_on_failure: bool) {
+        self.panic_on_failure = panic_on_failure;
+    }
+
+    /// With false, the run goes on after the last step until the program
+    /// ends or reads a key
+    pub fn set_stop_when_done(&mut self, stop_when_done: bool) {
+        self.stop_when_done = stop_when_done;
+    }
 
-        let step = self.script.pop();
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    /// Value captured by the regular expressions, by group name or number
+    pub fn capture(&self, name: &str) -> Option<&str> {
+        self.captures.get(name).map(|value| value.as_str())
+    }
+
+    fn next_step(&mut self) {
         self.step += 1;
-        match step {
-            Some(Step::Input(input)) => {
-                self.input = input.chars().map(|c| c as u8).collect();            }
-            Some(Step::Expect(output)) => {
-                self.expected_output = Some(output);
-                self.current_count_left = 100; // 100 calls to status() by default
+        self.start_step();
+    }
+
+    fn start_step(&mut self) {
+        loop {
+            self.input.clear();
+            self.expectation = Expectation::Nothing;
+            self.current_output.clear();
+
+            let mut timeout = self.timeout;
+            match self.script.get(self.step) {
+                Some(Step::Input(input)) => {
+                    let input = self.substitute(input);
+                    self.input = input.chars().map(|c| c as u8).collect();
+                }
+                Some(Step::Key(name)) => {
+                    match key_by_name(name) {
+                        Some(ch) => self.input.push_back(ch),
+                        None => {
+                            self.fail(&format!("unknown key \"{}\"", name));
+                            return;
+                        }
+                    }
+                }
+                Some(Step::Expect(output)) => {
+                    self.expectation = Expectation::Text(output);
+                }
+                Some(Step::ExpectTimeout(output, wait)) => {
+                    self.expectation = Expectation::Text(output);
+                    timeout = Timeout::StatusCalls(*wait);
+                }
+                Some(Step::ExpectRegex(pattern)) => {
+                    match Regex::new(pattern) {
+                        Ok(regex) => self.expectation = Expectation::Regex(regex),
+                        Err(err) => {
+                            self.fail(&format!("invalid regular expression: {}", err));
+                            return;
+                        }
+                    }
+                }
+                Some(Step::ExpectNot(output)) => {
+                    self.expectation = Expectation::Not(output);
+                }
+                Some(Step::ExpectRow(row, text)) => {
+                    self.expectation = Expectation::Row(*row, text);
+                }
+                Some(Step::ExpectCursor(row, column)) => {
+                    self.expectation = Expectation::Cursor(*row, *column);
+                }
+                Some(Step::ExpectScreen(snapshot)) => {
+                    self.expectation = Expectation::Snapshot(snapshot);
+                }
+                Some(Step::SetTimeout(timeout)) => {
+                    self.timeout = *timeout;
+                    self.step += 1;
+                    continue;
+                }
+                None => {
+                    self.done = true;
+                }
             }
-            Some(Step::ExpectTimeout(output, wait)) => {
-                self.expected_output = Some(output);
-                self.current_count_left = wait;
+
+            self.current_count_left = None;
+            self.instructions_left = None;
+            self.deadline = None;
+            if !self.is_waiting() {
+                return;
             }
-            None => {
-                self.terminate = true;
+            match timeout {
+                Timeout::StatusCalls(count) => self.current_count_left = Some(count),
+                Timeout::Instructions(count) => self.instructions_left = Some(count),
+                Timeout::WallTime(duration) => self.deadline = Some(Instant::now() + duration),
             }
+            return;
         }
     }
+
+    fn substitute(&self, input: &str) -> String {
+        let mut result = String::new();
+        let mut rest = input;
+        while let Some(start) = rest.find("${") {
+            result.push_str(&rest[..start]);
+            match rest[start..].find('}') {
+                Some(end) => {
+                    let name = &rest[start + 2..start + end];
+                    result.push_str(self.capture(name).unwrap_or(""));
+                    rest = &rest[start + end + 1..];
+                },
+                None => {
+                    result.push_str(&rest[start..]);
+                    rest = "";
+                }
+            }
+        }
+        result.push_str(rest);
+        result
+    }
+
+    fn is_waiting(&self) -> bool {
+        !matches!(self.expectation, Expectation::Nothing) && !self.terminate
+    }
+
+    // Checks the expectation, moving to the next step when fulfilled
+    fn check(&mut self) {
+        let fulfilled = match &self.expectation {
+            Expectation::Nothing => false,
+            Expectation::Text(text) => self.current_output.contains(*text),
+            Expectation::Regex(regex) => {
+                match regex.captures(&self.current_output) {
+                    Some(captures) => {
+                        for (i, name) in regex.capture_names().enumerate() {
+                            if let Some(value) = captures.get(i) {
+                                self.captures.insert(i.to_string(), value.as_str().to_string());
+                                if let Some(name) = name {
+                                    self.captures.insert(name.to_string(), value.as_str().to_string());
+                                }
+                            }
+                        }
+                        true
+                    },
+                    None => false,
+                }
+            },
+            Expectation::Not(text) => {
+                if self.current_output.contains(*text) {
+                    self.fail("text found");
+                }
+                false
+            },
+            Expectation::Row(row, text) => self.screen.row_text(*row).contains(text),
+            Expectation::Cursor(row, column) => self.screen.cursor() == (*row, *column),
+            Expectation::Snapshot(snapshot) => self.screen.snapshot() == snapshot.trim_end(),
+        };
+        if fulfilled {
+            self.next_step();
+        }
+    }
+
+    fn timed_out(&mut self) {
+        if let Expectation::Not(_) = self.expectation {
+            // Not found during the timeout, passed
+            self.next_step();
+        } else {
+            self.fail("timeout");
+        }
+    }
+
+    fn check_timeout(&mut self) {
+        if self.deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+            self.timed_out();
+        }
+    }
+
+    fn fail(&mut self, reason: &str) {
+        let message = self.failure_message(reason);
+        if self.panic_on_failure {
+            panic!("{}", message);
+        }
+        if self.failure.is_none() {
+            self.failure = Some(message);
+        }
+        self.input.clear();
+        self.expectation = Expectation::Nothing;
+        self.terminate = true;
+    }
+
+    fn failure_message(&self, reason: &str) -> String {
+        let mut message = format!("Test failed in step {}: {}\n", self.step + 1, reason);
+        let first = self.step.saturating_sub(2);
+        let last = (self.step + 2).min(self.script.len());
+        for i in first..last {
+            let marker = if i == self.step {">"} else {" "};
+            message += &format!("{} {:3}: {}\n", marker, i + 1, describe(&self.script[i]));
+        }
+        message += &format!("Output collected: \"{}\"\n", self.current_output.escape_debug());
+        message += &self.screen.dump();
+        message
+    }
+}
+
+fn describe(step: &Step) -> String {
+    match step {
+        Step::Input(input) => format!("input \"{}\"", input.escape_debug()),
+        Step::Key(name) => format!("key {}", name),
+        Step::Expect(text) => format!("expect \"{}\"", text.escape_debug()),
+        Step::ExpectTimeout(text, wait) => format!("expect \"{}\" in {} status calls", text.escape_debug(), wait),
+        Step::ExpectRegex(pattern) => format!("expect regex \"{}\"", pattern.escape_debug()),
+        Step::ExpectNot(text) => format!("expect not \"{}\"", text.escape_debug()),
+        Step::ExpectRow(row, text) => format!("expect row {} containing \"{}\"", row, text.escape_debug()),
+        Step::ExpectCursor(row, column) => format!("expect cursor at row {} column {}", row, column),
+        Step::ExpectScreen(_) => "expect screen".to_string(),
+        Step::SetTimeout(Timeout::StatusCalls(count)) => format!("timeout {} polls", count),
+        Step::SetTimeout(Timeout::Instructions(count)) => format!("timeout {} instructions", count),
+        Step::SetTimeout(Timeout::WallTime(duration)) => format!("timeout {}ms", duration.as_millis()),
+    }
+}
+
+/// Key code by name: "enter", "esc", "ctrl-c", "^C"...
+pub fn key_by_name(name: &str) -> Option<u8> {
+    let name = name.to_ascii_lowercase();
+    let code = match name.as_str() {
+        "enter" | "return" | "cr" => 13,
+        "lf" => 10,
+        "tab" => 9,
+        "esc" | "escape" => 27,
+        "bs" | "backspace" => 8,
+        "del" | "delete" | "rubout" => 127,
+        "space" => 32,
+        _ => {
+            let ch = name.strip_prefix("ctrl-").or_else(|| name.strip_prefix('^'))?;
+            match ch.as_bytes() {
+                [ch] if (b'@'..=b'_').contains(&ch.to_ascii_uppercase()) => ch.to_ascii_uppercase() & 0x1f,
+                _ => return None,
+            }
+        }
+    };
+    Some(code)
 }
 
 impl<'a> ConsoleEmulator for ConsoleTest <'a> {
     fn status(&mut self) -> bool {
-        if self.input.len() > 0 {
-            true
-        } else {
-            if self.current_count_left == 0 {
-                panic!("Test failed in step {}: expected text not found", self.step);
+        if !self.input.is_empty() {
+            return true;
+        }
+        self.check();
+        if !self.input.is_empty() {
+            return true;
+        }
+        if self.is_waiting() {
+            self.check_timeout();
+            if let Some(count) = self.current_count_left {
+                if count == 0 {
+                    self.timed_out();
+                    return !self.input.is_empty();
+                }
+                self.current_count_left = Some(count - 1);
             }
-            self.current_count_left -= 1;
-            false
         }
+        false
     }
 
     fn read(&mut self) -> u8 {
+        if self.input.is_empty() && self.is_waiting() {
+            // The program waits for a key, the output won't change
+            self.check();
+            if let Expectation::Not(_) = self.expectation {
+                self.next_step();
+            }
+        }
         match self.input.pop_front() {
             Some(ch) => {
-                if self.input.len() == 0 {
+                if self.input.is_empty() {
                     self.next_step();
                 }
                 ch
             }
             None => {
-                panic!("Test failed in step {}: input not available waiting for expected text", self.step);
+                if self.done {
+                    // No more keys for the program
+                    self.terminate = true;
+                } else if !self.terminate {
+                    self.fail("input not available waiting for expected output");
+                }
+                0
             }
         }
     }
@@ -106,18 +409,116 @@ impl<'a> ConsoleEmulator for ConsoleTest <'a> {
     fn put(&mut self, sequence: Option<String>) {
         if let Some(sequence) = sequence {
             print!("{}", sequence);
+            self.screen.feed(&sequence);
 
-            if let Some(expected) = &self.expected_output {
+            if self.is_waiting() {
                 self.current_output.push_str(&sequence);
-                if self.current_output.contains(*expected) {
-                    self.next_step();
-                }
+                self.check();
             }
         }
     }
 
     fn terminated(&self) -> bool {
-        self.terminate
+        self.terminate || (self.done && self.stop_when_done)
+    }
+
+    fn tick(&mut self, instructions: u32) {
+        if let Some(count) = self.instructions_left {
+            if count < instructions as u64 {
+                self.timed_out();
+            } else {
+                self.instructions_left = Some(count - instructions as u64);
+            }
+        } else if self.deadline.is_some() {
+            self.check_timeout();
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_by_name() {
+        assert_eq!(key_by_name("ctrl-c"), Some(3));
+        assert_eq!(key_by_name("^Z"), Some(26));
+        assert_eq!(key_by_name("Enter"), Some(13));
+        assert_eq!(key_by_name("ctrl-["), Some(27));
+        assert_eq!(key_by_name("ctrl-1"), None);
+        assert_eq!(key_by_name("nokey"), None);
+    }
+
+    #[test]
+    fn test_regex_captures_in_input() {
+        let mut console = ConsoleTest::new(vec!(
+            Step::ExpectRegex(r"Serial: (?P<serial>\d+)"),
+            Step::Input("${serial}-${1}\r"),
+        ));
+        console.put(Some("Serial: 1234\r\n".to_string()));
+        assert_eq!(console.capture("serial"), Some("1234"));
+        let input: Vec<u8> = (0..10).map(|_| console.read()).collect();
+        assert_eq!(input, b"1234-1234\r");
+        assert!(console.terminated());
+    }
+
+    #[test]
+    fn test_expect_not() {
+        let mut console = ConsoleTest::new(vec!(
+            Step::SetTimeout(Timeout::StatusCalls(2)),
+            Step::ExpectNot("Error"),
+            Step::Expect("Done"),
+        ));
+        console.put(Some("Working".to_string()));
+        for _ in 0..3 {
+            console.status();
+        }
+        console.put(Some("Done".to_string()));
+        assert!(console.terminated());
+    }
+
+    #[test]
+    fn test_run_after_the_steps() {
+        let mut console = ConsoleTest::new(vec!(
+            Step::Expect("A>"),
+        ));
+        console.set_stop_when_done(false);
+        console.put(Some("A>".to_string()));
+        assert!(!console.terminated());
+        console.read();
+        assert!(console.terminated());
+        assert!(console.failure().is_none());
+    }
+
+    #[test]
+    fn test_failure_without_panic() {
+        let mut console = ConsoleTest::new(vec!(
+            Step::Expect("A>"),
+            Step::ExpectNot("Error"),
+            Step::Expect("never"),
+        ));
+        console.set_panic_on_failure(false);
+        console.put(Some("A>".to_string()));
+        console.put(Some("Error".to_string()));
+        assert!(console.terminated());
+        let failure = console.failure().unwrap();
+        assert!(failure.starts_with("Test failed in step 2: text found"));
+        assert!(failure.contains("    1: expect \"A>\"\n>   2: expect not \"Error\"\n    3: expect \"never\""));
+        assert!(failure.contains("Output collected: \"Error\""));
+    }
+
+    #[test]
+    fn test_instructions_timeout() {
+        let mut console = ConsoleTest::new(vec!(
+            Step::SetTimeout(Timeout::Instructions(10)),
+            Step::Expect("never"),
+        ));
+        console.set_panic_on_failure(false);
+        for _ in 0..10 {
+            console.tick(1);
+        }
+        assert!(!console.terminated());
+        console.tick(1);
+        assert!(console.failure().unwrap().contains("timeout"));
+    }
+}
@@ -38,6 +38,12 @@ impl Console {
     }
 }
 
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ConsoleEmulator for Console {
     fn status(&mut self) -> bool {
         match self.next_char {
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::io::{Write, stdout};
 use std::time::Duration;
 
@@ -9,7 +10,7 @@ use crossterm::style;
 use crate::console_emulator::ConsoleEmulator;
 
 pub struct Console {
-    next_char: Option<u8>,
+    next_chars: VecDeque<u8>,
 }
 
 impl Console {
@@ -17,49 +18,46 @@ impl Console {
         terminal::enable_raw_mode().unwrap();
 
         Console {
-            next_char: None,
+            next_chars: VecDeque::new(),
         }
     }
 }
 
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ConsoleEmulator for Console {
     fn status(&mut self) -> bool {
-        match self.next_char {
-            Some(_) => true,
-            None => {
-                loop {
-                    if event::poll(Duration::from_nanos(100)).unwrap() {
-                        let event = event::read().unwrap();
-                        let some_ch = event_to_char(event);
-                        if let Some(ch) = some_ch {
-                            self.next_char = Some(ch);
-                            break true
-                        }
-                        // The event is not a valid char, ignore and retry
-                    } else {
-                        break false
-                    }
+        if !self.next_chars.is_empty() {
+            return true;
+        }
+        loop {
+            if event::poll(Duration::from_nanos(100)).unwrap() {
+                let event = event::read().unwrap();
+                if let Some(sequence) = event_to_sequence(event) {
+                    self.next_chars.extend(sequence);
+                    break true
                 }
+                // The event is not a valid char, ignore and retry
+            } else {
+                break false
             }
         }
     }
 
     fn read(&mut self) -> u8 {
-        match self.next_char {
-            Some(ch) => {
-                self.next_char = None;
-                ch
-            },
-            None => {
-                loop {
-                    let event = event::read().unwrap();
-                    let some_ch = event_to_char(event);
-                    if let Some(ch) = some_ch {
-                        break ch;
-                    }
-                    // The event is not a valid char, ignore and retry
-                }
+        loop {
+            if let Some(ch) = self.next_chars.pop_front() {
+                break ch;
             }
+            let event = event::read().unwrap();
+            if let Some(sequence) = event_to_sequence(event) {
+                self.next_chars.extend(sequence);
+            }
+            // The event is not a valid char, ignore and retry
         }
     }
 
@@ -81,42 +79,63 @@ impl Drop for Console {
     }
 }
 
-fn event_to_char(event: event::Event) -> Option<u8> {
-    let a = match event {
-        event::Event::Key(k) => match k.code {
-            event::KeyCode::Char(c) => {
-                if k.modifiers == event::KeyModifiers::NONE ||
-                        k.modifiers == event::KeyModifiers::SHIFT {
-                    if ' ' <= c && c <= '~' {
-                        // Valid ASCII, not control, char
-                        Some(c as u8)
-                    } else {
-                        None
-                    }
-                } else if k.modifiers == event::KeyModifiers::CONTROL {
-                    if '`' <= c && c <= '~' {
-                        // Valid control range
-                        Some(c as u8 - '`' as u8)
-                    } else {
-                        None
-                    }
+/*
+The cursor, editing and function keys are sent as the ANSI sequences of
+the Unix terminals, they are translated to CP/M keys by the BIOS.
+*/
+fn event_to_sequence(event: event::Event) -> Option<Vec<u8>> {
+    let k = match event {
+        event::Event::Key(k) => k,
+        _ => return None, // Not a keyboard event, ignore.
+    };
+    let ctrl = k.modifiers.contains(event::KeyModifiers::CONTROL);
+    let ansi = |last: char| if ctrl {
+        format!("\x1b[1;5{}", last).into_bytes()
+    } else {
+        format!("\x1b[{}", last).into_bytes()
+    };
+    let tilde = |number: u8| if ctrl {
+        format!("\x1b[{};5~", number).into_bytes()
+    } else {
+        format!("\x1b[{}~", number).into_bytes()
+    };
+    match k.code {
+        event::KeyCode::Char(c) => {
+            if k.modifiers == event::KeyModifiers::NONE ||
+                    k.modifiers == event::KeyModifiers::SHIFT {
+                if (' '..='~').contains(&c) {
+                    // Valid ASCII, not control, char
+                    Some(vec!(c as u8))
                 } else {
                     None
                 }
-            },
-            event::KeyCode::Backspace => Some(127),
-            event::KeyCode::Enter => Some(13),
-            event::KeyCode::Left =>  Some(8),
-            event::KeyCode::Right => Some(12),
-            event::KeyCode::Up => Some(11),
-            event::KeyCode::Down => Some(10),
-            event::KeyCode::Home => Some(30),
-            event::KeyCode::Tab => Some(9),
-            event::KeyCode::Esc => Some(27),
-            _ => None, // We ignore: End, PageUp, PageDown, BackTab, Delete, Insert, F(n)
+            } else if k.modifiers == event::KeyModifiers::CONTROL {
+                if ('`'..='~').contains(&c) {
+                    // Valid control range
+                    Some(vec!(c as u8 - b'`'))
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
         },
-        _ => None, // Not a keyboard event, ignore.
-    };
-
-    a
-}
\ No newline at end of file
+        event::KeyCode::Backspace => Some(vec!(127)),
+        event::KeyCode::Enter => Some(vec!(13)),
+        event::KeyCode::Tab => Some(vec!(9)),
+        event::KeyCode::Esc => Some(vec!(27)),
+        event::KeyCode::Up => Some(ansi('A')),
+        event::KeyCode::Down => Some(ansi('B')),
+        event::KeyCode::Right => Some(ansi('C')),
+        event::KeyCode::Left => Some(ansi('D')),
+        event::KeyCode::Home => Some(ansi('H')),
+        event::KeyCode::End => Some(ansi('F')),
+        event::KeyCode::Insert => Some(tilde(2)),
+        event::KeyCode::Delete => Some(tilde(3)),
+        event::KeyCode::PageUp => Some(tilde(5)),
+        event::KeyCode::PageDown => Some(tilde(6)),
+        event::KeyCode::F(n @ 1..=4) => Some(vec!(27, b'O', b'P' + n - 1)),
+        event::KeyCode::F(n @ 5..=12) => Some(tilde([15, 17, 18, 19, 20, 21, 23, 24][(n - 5) as usize])),
+        _ => None, // We ignore: BackTab, F(n) over 12
+    }
+}
@@ -6,14 +6,11 @@ pub const FCB1_ADDRESS:           u16 = 0x005c;
 pub const FCB2_ADDRESS:           u16 = 0x006c;
 pub const SYSTEM_PARAMS_ADDRESS:  u16 = 0x0080; // Also default DMA buffer
 
-// Memory map
+// Memory map, for 64K. With --tpa-top the system is moved, see memory_map.rs
 pub const TPA_BASE_ADDRESS:       u16 = 0x0100;
-pub const CCP_BASE_ADDRESS:       u16 = 0xf000; // The CCP binary has to be rebuilt if this changes (third-party/build_zcpr.sh)
-pub const TPA_STACK_ADDRESS:      u16 = 0xf080; // 16 bytes for an 8 level stack
+pub const CCP_BASE_ADDRESS:       u16 = 0xf000; // The built in CCP binary is linked here (third-party/build_zcpr.sh)
 pub const BDOS_BASE_ADDRESS:      u16 = 0xf800;
-pub const BDOS_DPB0_ADDRESS:      u16 = 0xf900;
-pub const BDOS_ALVEC0_ADDRESS:    u16 = 0xf910;
-pub const BIOS_BASE_ADDRESS:      u16 = 0xff00;
+pub const DEFAULT_COMMON_BASE:    u16 = 0xc000; // Banked memory
 
 // Exit conditions
 #[derive(PartialEq)]
@@ -23,4 +20,5 @@ pub enum ExecutionResult {
     ColdBoot,
     Stop,
     StopConfirm,
+    Exit(u8),
 }
\ No newline at end of file
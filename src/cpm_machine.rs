@@ -1,45 +1,158 @@
 use iz80::Machine;
 
+use crate::device::DeviceRegistry;
+use crate::memory_map::MemoryMap;
+
+/*
+Memory, flat by default or banked as on CP/M 3 systems: a number of banks of
+64K with a common area from common_base to the top that is the same memory
+on all the banks. The common area is physically on bank 0. Bank 0 is
+selected on start, the CCP, BDOS and BIOS are on the common area.
+
+The bank is selected with the BIOS SELMEM or with OUT to the bank port. IN
+from the bank port returns the bank selected.
+
+The machine also has the memory map, where the system is placed.
+*/
+
+const BANK_SIZE: usize = 65536;
+
 pub struct CpmMachine {
-    mem: [u8; 65536],
-    in_values: [u8; 256],
-    in_port: Option<u8>,
-    out_port: Option<u8>,
-    out_value: u8
+    mem: Vec<u8>,
+    banks: usize,
+    common_base: u16,
+    bank: u8,
+    bank_offset: usize,
+    bank_port: Option<u8>,
+    devices: DeviceRegistry,
+    memory_map: MemoryMap,
 }
 
 impl CpmMachine {
     pub fn new() -> CpmMachine {
         CpmMachine {
-            mem: [0; 65536],
-            in_values: [0; 256],
-            in_port: None,
-            out_port: None,
-            out_value: 0,
+            mem: vec![0; BANK_SIZE],
+            banks: 1,
+            common_base: 0,
+            bank: 0,
+            bank_offset: 0,
+            bank_port: None,
+            devices: DeviceRegistry::new(),
+            memory_map: MemoryMap::new(),
+        }
+    }
+
+    pub fn devices(&mut self) -> &mut DeviceRegistry {
+        &mut self.devices
+    }
+
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    pub fn set_memory_map(&mut self, memory_map: MemoryMap) {
+        self.memory_map = memory_map;
+    }
+
+    pub fn set_banks(&mut self, banks: usize, common_base: u16) {
+        self.mem = vec![0; BANK_SIZE * banks];
+        self.banks = banks;
+        self.common_base = common_base;
+        self.select_bank(0);
+    }
+
+    pub fn set_bank_port(&mut self, port: u8) {
+        self.bank_port = Some(port);
+    }
+
+    pub fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    /// Selects the bank for the addresses below the common area. Banks
+    /// that don't exist are ignored.
+    pub fn select_bank(&mut self, bank: u8) {
+        if (bank as usize) < self.banks {
+            self.bank = bank;
+            self.bank_offset = bank as usize * BANK_SIZE;
         }
     }
+
+    fn physical(&self, bank: u8, address: u16) -> usize {
+        if address >= self.common_base || bank as usize >= self.banks {
+            address as usize
+        } else {
+            bank as usize * BANK_SIZE + address as usize
+        }
+    }
+
+    pub fn peek_bank(&self, bank: u8, address: u16) -> u8 {
+        self.mem[self.physical(bank, address)]
+    }
+
+    pub fn poke_bank(&mut self, bank: u8, address: u16, value: u8) {
+        let physical = self.physical(bank, address);
+        self.mem[physical] = value;
+    }
 }
 
 impl Machine for CpmMachine {
     fn peek(&self, address: u16) -> u8 {
         //println!("$$$ {:04x}", address);
-        self.mem[address as usize]
+        if address >= self.common_base {
+            self.mem[address as usize]
+        } else {
+            self.mem[self.bank_offset + address as usize]
+        }
     }
 
     fn poke(&mut self, address: u16, value: u8) {
         //println!("$$$ {:04x} W", address);
-        self.mem[address as usize] = value;
+        if address >= self.common_base {
+            self.mem[address as usize] = value;
+        } else {
+            self.mem[self.bank_offset + address as usize] = value;
+        }
     }
 
     fn port_in(&mut self, address: u16) -> u8 {
-        let value = self.in_values[address as u8 as usize];
-        self.in_port = Some(address as u8);
-        value
+        if self.bank_port == Some(address as u8) {
+            return self.bank;
+        }
+        self.devices.read(address as u8)
     }
 
     fn port_out(&mut self, address: u16, value: u8) {
-        self.out_port = Some(address as u8);
-        self.out_value = value;
+        if self.bank_port == Some(address as u8) {
+            self.select_bank(value);
+            return;
+        }
+        self.devices.write(address as u8, value);
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banks_and_common_area() {
+        let mut machine = CpmMachine::new();
+        machine.set_banks(3, 0xc000);
+        machine.set_bank_port(0x40);
+        machine.poke(0x8000, 0x11);
+        machine.poke(0xc000, 0x33);
+        machine.port_out(0x40, 2);
+        assert_eq!(machine.port_in(0x40), 2);
+        assert_eq!(machine.peek(0x8000), 0x00);
+        assert_eq!(machine.peek(0xc000), 0x33);
+        machine.poke(0x8000, 0x22);
+        machine.port_out(0x40, 5); // Ignored
+        assert_eq!(machine.bank(), 2);
+        machine.select_bank(0);
+        assert_eq!(machine.peek(0x8000), 0x11);
+        assert_eq!(machine.peek_bank(2, 0x8000), 0x22);
+        machine.poke_bank(1, 0xc000, 0x44);
+        assert_eq!(machine.peek(0xc000), 0x44);
+    }
+}
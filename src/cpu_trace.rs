@@ -0,0 +1,58 @@
+use iz80::*;
+
+use crate::disassembler::*;
+use crate::symbols::SymbolTable;
+
+/*
+Trace of the instructions executed. Same format as the iz80 trace, but
+showing the labels and the symbols of the CALL and JP targets when there
+is a symbol table.
+*/
+
+pub struct CpuTracer {
+    disassembler: Disassembler,
+}
+
+impl CpuTracer {
+    pub fn new(is_8080: bool) -> CpuTracer {
+        CpuTracer {
+            disassembler: Disassembler::new(is_8080),
+        }
+    }
+
+    /// Traces an instruction. Call after executing it with the PC and the
+    /// instruction bytes sampled before the execution.
+    pub fn trace(&mut self, pc: u16, bytes: &[u8], cpu: &Cpu, symbols: &SymbolTable) -> String {
+        let mut text = String::new();
+        if let Some(label) = symbols.exact(pc) {
+            text += &format!("{}:\n", label);
+        }
+
+        let (disasm, size) = self.disassembler.disasm(pc, bytes);
+        let reg = cpu.immutable_registers();
+        text += &format!("==> {:04x}: {:20} PC:{:04x} AF:{:04x} BC:{:04x} DE:{:04x} HL:{:04x} SP:{:04x} IX:{:04x} IY:{:04x} Flags:{:08b} Cycle:{:04}",
+            pc, disasm,
+            reg.pc(),
+            reg.get16(Reg16::AF),
+            reg.get16(Reg16::BC),
+            reg.get16(Reg16::DE),
+            reg.get16(Reg16::HL),
+            reg.get16(Reg16::SP),
+            reg.get16(Reg16::IX),
+            reg.get16(Reg16::IY),
+            reg.get8(Reg8::F),
+            cpu.cycle_count()
+        );
+
+        let shown = (size as usize).clamp(1, MAX_INSTRUCTION_SIZE);
+        let hex: Vec<String> = bytes[..shown].iter().map(|b| format!("{:02x}", b)).collect();
+        text += &format!(" [{}]", hex.join(" "));
+
+        if let Some(target) = branch_target(bytes) {
+            if let Some(symbol) = symbols.lookup(target) {
+                text += &format!(" ; {}", symbol);
+            }
+        }
+        text
+    }
+}
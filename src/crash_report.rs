@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use iz80::*;
+
+use crate::bdos::bdos_command_name;
+use crate::constants::*;
+use crate::cpm_machine::CpmMachine;
+use crate::disassembler::*;
+use crate::symbols::SymbolTable;
+
+/*
+Crash report. We keep the last instructions executed and the last BDOS
+calls on ring buffers. When the emulation ends abnormally (HALT, a BDOS or
+BIOS function not implemented or the user aborting) we write a report with
+the history and the state of the machine.
+
+The report is optional, with --crash-report or a --history size. The
+history costs some speed.
+*/
+
+pub const DEFAULT_HISTORY_SIZE: usize = 32;
+const STACK_WORDS: u16 = 8;
+
+#[derive(Clone, Copy)]
+struct RegisterSnapshot {
+    pc: u16,
+    af: u16,
+    bc: u16,
+    de: u16,
+    hl: u16,
+    sp: u16,
+    ix: u16,
+    iy: u16,
+}
+
+impl RegisterSnapshot {
+    fn new(reg: &Registers) -> RegisterSnapshot {
+        RegisterSnapshot {
+            pc: reg.pc(),
+            af: reg.get16(Reg16::AF),
+            bc: reg.get16(Reg16::BC),
+            de: reg.get16(Reg16::DE),
+            hl: reg.get16(Reg16::HL),
+            sp: reg.get16(Reg16::SP),
+            ix: reg.get16(Reg16::IX),
+            iy: reg.get16(Reg16::IY),
+        }
+    }
+
+    fn to_text(self) -> String {
+        format!("PC:{:04x} AF:{:04x} BC:{:04x} DE:{:04x} HL:{:04x} SP:{:04x} IX:{:04x} IY:{:04x}",
+            self.pc, self.af, self.bc, self.de, self.hl, self.sp, self.ix, self.iy)
+    }
+}
+
+struct InstructionRecord {
+    pc: u16,
+    bytes: [u8; MAX_INSTRUCTION_SIZE],
+    registers: RegisterSnapshot, // After the execution
+}
+
+struct BdosCallRecord {
+    function: u8,
+    de: u16,
+    caller: u16,
+    result: Option<u16>,
+}
+
+pub struct History {
+    size: usize,
+    is_8080: bool,
+    instructions: VecDeque<InstructionRecord>,
+    bdos_calls: VecDeque<BdosCallRecord>,
+}
+
+impl History {
+    pub fn new(size: usize, is_8080: bool) -> History {
+        History {
+            size,
+            is_8080,
+            instructions: VecDeque::with_capacity(size),
+            bdos_calls: VecDeque::with_capacity(size),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    /// Call after executing an instruction with the PC and the instruction
+    /// bytes sampled before the execution.
+    pub fn instruction(&mut self, pc: u16, bytes: &[u8; MAX_INSTRUCTION_SIZE], reg: &Registers) {
+        if self.size == 0 {
+            return;
+        }
+        if self.instructions.len() == self.size {
+            self.instructions.pop_front();
+        }
+        self.instructions.push_back(InstructionRecord {
+            pc,
+            bytes: *bytes,
+            registers: RegisterSnapshot::new(reg),
+        });
+    }
+
+    /// Call before the BDOS is executed, with the PC on the BDOS entry.
+    pub fn bdos_call(&mut self, reg: &Registers, machine: &CpmMachine) {
+        if self.size == 0 {
+            return;
+        }
+        if self.bdos_calls.len() == self.size {
+            self.bdos_calls.pop_front();
+        }
+        self.bdos_calls.push_back(BdosCallRecord {
+            function: reg.get8(Reg8::C),
+            de: reg.get16(Reg16::DE),
+            caller: machine.peek16(reg.get16(Reg16::SP)).wrapping_sub(3),
+            result: None,
+        });
+    }
+
+    /// Call after the BDOS is executed.
+    pub fn bdos_result(&mut self, reg: &Registers) {
+        if let Some(call) = self.bdos_calls.back_mut() {
+            if call.result.is_none() {
+                call.result = Some(reg.get16(Reg16::HL));
+            }
+        }
+    }
+
+    pub fn report(&self, reason: &str, cpu: &Cpu, machine: &CpmMachine,
+            symbols: &SymbolTable) -> String {
+        let mut r = String::new();
+        r += "==== iz-cpm crash report ====\n";
+        r += &format!("Reason: {}\n", reason);
+
+        let reg = cpu.immutable_registers();
+        let registers = RegisterSnapshot::new(reg);
+        r += "\nRegisters:\n";
+        r += &format!("  {} Flags:{}\n", registers.to_text(), flags_text(reg.get8(Reg8::F)));
+        r += &format!("  PC at {}\n", symbols.describe(registers.pc));
+
+        r += "\nStack:\n";
+        for i in 0..STACK_WORDS {
+            let address = registers.sp.wrapping_add(2 * i);
+            let value = machine.peek16(address);
+            match symbols.lookup(value) {
+                Some(symbol) => r += &format!("  {:04x}: {:04x} ({})\n", address, value, symbol),
+                None => r += &format!("  {:04x}: {:04x}\n", address, value),
+            }
+        }
+
+        r += &format!("\nLast {} instructions:\n", self.instructions.len());
+        let mut disassembler = Disassembler::new(self.is_8080);
+        for record in self.instructions.iter() {
+            if let Some(label) = symbols.exact(record.pc) {
+                r += &format!("{}:\n", label);
+            }
+            let (text, size) = disassembler.disasm(record.pc, &record.bytes);
+            let size = (size as usize).clamp(1, MAX_INSTRUCTION_SIZE);
+            let hex: Vec<String> = record.bytes[..size].iter().map(|b| format!("{:02x}", b)).collect();
+            r += &format!("  {:04x}: {:11} {:20} => {}\n",
+                record.pc, hex.join(" "), text, record.registers.to_text());
+        }
+
+        r += &format!("\nLast {} BDOS calls:\n", self.bdos_calls.len());
+        for call in self.bdos_calls.iter() {
+            r += &format!("  {:3} {:12} DE:{:04x} from {}",
+                call.function, bdos_command_name(call.function), call.de, symbols.describe(call.caller));
+            if let Some(result) = call.result {
+                r += &format!(" => {:04x}", result);
+            }
+            r += "\n";
+        }
+
+        r += "\nZero page:\n";
+        r += &hex_dump(machine, 0x0000, 0x100);
+
+        r += &format!("\nFCB1 at {:04x}: {}\n", FCB1_ADDRESS, fcb_text(machine, FCB1_ADDRESS));
+        r += &format!("FCB2 at {:04x}: {}\n", FCB2_ADDRESS, fcb_text(machine, FCB2_ADDRESS));
+        r
+    }
+}
+
+/// Writes the report to the file given or to stderr.
+pub fn write_report(report: &str, filename: Option<&str>) -> io::Result<()> {
+    match filename {
+        None => {
+            // The console may be in raw mode, we need the carriage returns
+            eprint!("{}", report.replace('\n', "\r\n"));
+            Ok(())
+        },
+        Some(name) => {
+            let mut file = File::create(name)?;
+            file.write_all(report.as_bytes())
+        }
+    }
+}
+
+fn flags_text(flags: u8) -> String {
+    let names = ['S', 'Z', '5', 'H', '3', 'P', 'N', 'C'];
+    names.iter().enumerate().map(|(i, name)| {
+        if flags & (0x80 >> i) != 0 { *name } else { '-' }
+    }).collect()
+}
+
+fn hex_dump(machine: &CpmMachine, start: u16, size: u16) -> String {
+    let mut r = String::new();
+    for line in (0..size).step_by(16) {
+        let address = start.wrapping_add(line);
+        let bytes: Vec<u8> = (0..16).map(|i| machine.peek(address.wrapping_add(i))).collect();
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = bytes.iter().map(|&b| {
+            let ch = b & 0x7f;
+            if (0x20..0x7f).contains(&ch) { ch as char } else { '.' }
+        }).collect();
+        r += &format!("  {:04x}: {}  {}\n", address, hex.join(" "), ascii);
+    }
+    r
+}
+
+fn fcb_text(machine: &CpmMachine, address: u16) -> String {
+    let byte = |offset: u16| machine.peek(address.wrapping_add(offset));
+    let mut name = String::new();
+    for i in 1..12 {
+        if i == 9 {
+            name.push('.');
+        }
+        let ch = byte(i) & 0x7f;
+        name.push(if (0x20..0x7f).contains(&ch) { ch as char } else { '.' });
+    }
+    format!("dr:{:02x} name:\"{}\" ex:{:02x} s1:{:02x} s2:{:02x} rc:{:02x} cr:{:02x} r:{:02x}{:02x}{:02x}",
+        byte(0), name, byte(12), byte(13), byte(14), byte(15), byte(32), byte(35), byte(34), byte(33))
+}
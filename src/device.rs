@@ -0,0 +1,334 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::serial::parse_serial;
+use crate::symbols::parse_hex;
+
+/*
+I/O port devices. The host side devices implement the Device trait and are
+mapped to a range of ports. The IN and OUT instructions on the range are
+sent to the device, the rest of the ports read 0 and ignore the writes.
+With the call trace, the accesses to unmapped ports are reported.
+
+The ports are the low byte of the address, as on most CP/M systems. The
+devices can do work periodically with tick() and request interrupts.
+
+They are configured with --device kind:port[:argument], the port in hex,
+or with RunOptions::add_device() in the library. The built in devices are:
+
+    value:ports:NN      Reads NN on all the ports, ignores the writes. The
+                        ports can be a range like 10-13.
+    printer:port:file   Writes to the port go to the file, reading returns
+                        the ready status 0xff.
+    clock:port          Real time clock on 7 ports, UTC in BCD: seconds,
+                        minutes, hours, day, month, year and weekday (0 is
+                        sunday). The date is latched when reading seconds.
+    sio:port[:link]     Serial ports, see serial.rs
+    8251:port[:link]
+*/
+
+pub trait Device {
+    /// Name for the traces
+    fn name(&self) -> String;
+
+    /// IN instruction on a port of the device
+    fn read(&mut self, port: u8) -> u8;
+
+    /// OUT instruction on a port of the device
+    fn write(&mut self, port: u8, value: u8);
+
+    /// Called after each instruction with the total cycles executed
+    fn tick(&mut self, _cycles: u64) {}
+
+    /// The data byte put on the bus if the device requests an interrupt
+    fn interrupt_request(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+pub struct DeviceMapping {
+    pub first_port: u8,
+    pub last_port: u8,
+    pub device: Box<dyn Device>,
+}
+
+impl DeviceMapping {
+    pub fn new(first_port: u8, last_port: u8, device: Box<dyn Device>) -> DeviceMapping {
+        DeviceMapping {
+            first_port,
+            last_port,
+            device,
+        }
+    }
+}
+
+pub struct DeviceRegistry {
+    mappings: Vec<DeviceMapping>,
+    port_map: [Option<usize>; 256],
+    trace_unmapped: bool,
+    unmapped: Vec<String>,
+}
+
+impl DeviceRegistry {
+    pub fn new() -> DeviceRegistry {
+        DeviceRegistry {
+            mappings: Vec::new(),
+            port_map: [None; 256],
+            trace_unmapped: false,
+            unmapped: Vec::new(),
+        }
+    }
+
+    pub fn set_trace_unmapped(&mut self, trace: bool) {
+        self.trace_unmapped = trace;
+    }
+
+    pub fn add(&mut self, mapping: DeviceMapping) -> Result<(), String> {
+        if mapping.first_port > mapping.last_port {
+            return Err(format!("Invalid port range {:02x}-{:02x}", mapping.first_port, mapping.last_port));
+        }
+        for port in mapping.first_port..=mapping.last_port {
+            if let Some(index) = self.port_map[port as usize] {
+                return Err(format!("Port {:02x} used by {} and {}", port,
+                    self.mappings[index].device.name(), mapping.device.name()));
+            }
+        }
+        let index = self.mappings.len();
+        for port in mapping.first_port..=mapping.last_port {
+            self.port_map[port as usize] = Some(index);
+        }
+        self.mappings.push(mapping);
+        Ok(())
+    }
+
+    pub fn read(&mut self, port: u8) -> u8 {
+        match self.port_map[port as usize] {
+            Some(index) => self.mappings[index].device.read(port),
+            None => {
+                if self.trace_unmapped {
+                    self.unmapped.push(format!("[[IN from unmapped port {:02x}]]", port));
+                }
+                0
+            }
+        }
+    }
+
+    pub fn write(&mut self, port: u8, value: u8) {
+        match self.port_map[port as usize] {
+            Some(index) => self.mappings[index].device.write(port, value),
+            None => {
+                if self.trace_unmapped {
+                    self.unmapped.push(format!("[[OUT {:02x} to unmapped port {:02x}]]", value, port));
+                }
+            }
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.tick(cycles);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// The first device in the order added requesting an interrupt
+    pub fn interrupt_request(&mut self) -> Option<u8> {
+        self.mappings.iter_mut().find_map(|mapping| mapping.device.interrupt_request())
+    }
+
+    /// The unmapped accesses since the last call, for the traces
+    pub fn take_unmapped(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.unmapped)
+    }
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ValueDevice {
+    value: u8,
+}
+
+impl Device for ValueDevice {
+    fn name(&self) -> String {
+        format!("value {:02x}", self.value)
+    }
+
+    fn read(&mut self, _port: u8) -> u8 {
+        self.value
+    }
+
+    fn write(&mut self, _port: u8, _value: u8) {}
+}
+
+struct PrinterDevice {
+    filename: String,
+    file: File,
+}
+
+impl Device for PrinterDevice {
+    fn name(&self) -> String {
+        format!("printer {}", self.filename)
+    }
+
+    fn read(&mut self, _port: u8) -> u8 {
+        0xff
+    }
+
+    fn write(&mut self, _port: u8, value: u8) {
+        if self.file.write_all(&[value]).is_err() {
+            eprintln!("Error writing the printer file \"{}\"", self.filename);
+        }
+    }
+}
+
+struct ClockDevice {
+    port: u8,
+    time: [u8; 7],
+}
+
+fn bcd(value: u64) -> u8 {
+    (((value / 10) % 10) << 4 | (value % 10)) as u8
+}
+
+// Days since 1970-01-01 to (year, month, day), from the proleptic Gregorian
+// calendar algorithms by Howard Hinnant
+pub(crate) fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u64, day as u64)
+}
+
+impl ClockDevice {
+    fn latch(&mut self) {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs()).unwrap_or(0);
+        let days = seconds / 86400;
+        let (year, month, day) = civil_from_days(days as i64);
+        self.time = [
+            bcd(seconds % 60),
+            bcd((seconds / 60) % 60),
+            bcd((seconds / 3600) % 24),
+            bcd(day),
+            bcd(month),
+            bcd(year as u64 % 100),
+            ((days + 4) % 7) as u8, // 1970-01-01 was a thursday
+        ];
+    }
+}
+
+impl Device for ClockDevice {
+    fn name(&self) -> String {
+        "clock".to_string()
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        let register = port.wrapping_sub(self.port) as usize;
+        if register == 0 {
+            self.latch();
+        }
+        self.time[register]
+    }
+
+    fn write(&mut self, _port: u8, _value: u8) {}
+}
+
+/// A port number in hex, as on --device
+pub(crate) fn parse_port(text: &str) -> Option<u8> {
+    parse_hex(text).and_then(|port| u8::try_from(port).ok())
+}
+
+/// Creates a built in device from the --device option
+pub fn parse_device(spec: &str) -> Result<DeviceMapping, String> {
+    let parts: Vec<&str> = spec.splitn(3, ':').collect();
+    let kind = parts[0];
+    let ports = parts.get(1).ok_or("Missing port, use kind:port")?;
+    if kind == "sio" || kind == "8251" {
+        return parse_serial(kind, ports, parts.get(2).copied());
+    }
+    let (first_port, last_port) = match ports.split_once('-') {
+        Some((first, last)) => (parse_port(first), parse_port(last)),
+        None => (parse_port(ports), parse_port(ports)),
+    };
+    let (first_port, last_port) = match (first_port, last_port) {
+        (Some(first), Some(last)) if first <= last => (first, last),
+        _ => return Err(format!("Invalid port \"{}\"", ports)),
+    };
+    let argument = parts.get(2);
+
+    let device: Box<dyn Device> = match kind {
+        "value" => {
+            let value = argument.and_then(|value| parse_hex(value))
+                .and_then(|value| u8::try_from(value).ok())
+                .ok_or("The value device needs a byte, use value:port:NN")?;
+            Box::new(ValueDevice { value })
+        },
+        "printer" => {
+            let filename = argument.ok_or("The printer device needs a file, use printer:port:file")?;
+            let file = OpenOptions::new().create(true).append(true).open(filename)
+                .map_err(|err| format!("Error opening \"{}\": {}", filename, err))?;
+            Box::new(PrinterDevice { filename: filename.to_string(), file })
+        },
+        "clock" => {
+            if last_port as usize - first_port as usize != 0 || first_port > 0xff - 6 {
+                return Err("The clock device needs a single port with 6 more after it".to_string());
+            }
+            let mut clock = ClockDevice { port: first_port, time: [0; 7] };
+            clock.latch();
+            return Ok(DeviceMapping::new(first_port, first_port + 6, Box::new(clock)));
+        },
+        _ => return Err(format!("Unknown device \"{}\". Choose \"value\", \"printer\", \"clock\", \"sio\" or \"8251\".", kind)),
+    };
+    Ok(DeviceMapping::new(first_port, last_port, device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let mut registry = DeviceRegistry::new();
+        registry.set_trace_unmapped(true);
+        registry.add(parse_device("value:10-11:5a").unwrap()).unwrap();
+        assert!(registry.add(parse_device("value:11:00").unwrap()).is_err());
+        assert_eq!(registry.read(0x10), 0x5a);
+        assert_eq!(registry.read(0x11), 0x5a);
+        assert_eq!(registry.read(0x12), 0);
+        registry.write(0x13, 0x42);
+        assert_eq!(registry.take_unmapped(), vec!(
+            "[[IN from unmapped port 12]]".to_string(),
+            "[[OUT 42 to unmapped port 13]]".to_string()));
+        assert!(registry.take_unmapped().is_empty());
+    }
+
+    #[test]
+    fn test_parse_device_errors() {
+        assert!(parse_device("value").is_err());
+        assert!(parse_device("value:100:00").is_err());
+        assert!(parse_device("value:12-10:00").is_err());
+        assert!(parse_device("clock:fa").is_err());
+        assert!(parse_device("disk:10").is_err());
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(20744), (2026, 10, 18));
+    }
+}
@@ -0,0 +1,123 @@
+use iz80::*;
+
+/*
+Disassembly of instructions for traces and reports.
+
+iz80 disassembles with the decoder of a Cpu, and decoding changes the Cpu
+state. We can't do that on the Cpu running the program, so we use a scratch
+Cpu over a scratch machine with a copy of the instruction bytes. An index
+prefix (DD or FD) leaves the index register selected on the scratch Cpu, in
+that case it is replaced for the next disassembly.
+*/
+
+pub const MAX_INSTRUCTION_SIZE: usize = 4;
+
+pub struct Disassembler {
+    is_8080: bool,
+    cpu: Cpu,
+    machine: PlainMachine,
+    dirty: bool,
+}
+
+impl Disassembler {
+    pub fn new(is_8080: bool) -> Disassembler {
+        Disassembler {
+            is_8080,
+            cpu: new_cpu(is_8080),
+            machine: PlainMachine::new(),
+            dirty: false,
+        }
+    }
+
+    /// Disassembles the instruction with the bytes given, as if located at
+    /// the address. Returns the text and the size of the instruction.
+    pub fn disasm(&mut self, address: u16, bytes: &[u8]) -> (String, u16) {
+        if self.dirty {
+            self.cpu = new_cpu(self.is_8080);
+            self.dirty = false;
+        }
+        for (i, value) in bytes.iter().enumerate().take(MAX_INSTRUCTION_SIZE) {
+            self.machine.poke(address.wrapping_add(i as u16), *value);
+        }
+        self.cpu.registers().set_pc(address);
+        let text = self.cpu.disasm_instruction(&mut self.machine);
+        let size = instruction_size(bytes, self.is_8080);
+        if !self.is_8080 && (bytes[0] == 0xdd || bytes[0] == 0xfd) {
+            self.dirty = true;
+        }
+        (text, size)
+    }
+}
+
+fn new_cpu(is_8080: bool) -> Cpu {
+    if is_8080 {
+        Cpu::new_8080()
+    } else {
+        Cpu::new_z80()
+    }
+}
+
+/// Returns the size of the instruction. The iz80 decoder reads the
+/// immediate values and displacements on execution, not on decode, so we
+/// can't take it from the PC after disassembling.
+pub fn instruction_size(bytes: &[u8], is_8080: bool) -> u16 {
+    let opcode = bytes[0];
+    if is_8080 {
+        return match opcode {
+            0xcb | 0xdd | 0xed | 0xfd => 3, // Undocumented JP and CALL
+            0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => 1, // Undocumented NOP
+            _ => unprefixed_size(opcode),
+        };
+    }
+    match opcode {
+        0xcb => 2,
+        0xed => match bytes[1] {
+            0x43 | 0x4b | 0x53 | 0x5b | 0x63 | 0x6b | 0x73 | 0x7b => 4, // LD (nn),rr and LD rr,(nn)
+            _ => 2,
+        },
+        0xdd | 0xfd => {
+            let opcode = bytes[1];
+            let uses_hl_pointer = match opcode {
+                0x34..=0x36 => true,
+                0x76 => false,
+                0x40..=0x7f => (opcode & 0x07) == 0x06 || (opcode & 0xf8) == 0x70,
+                0x80..=0xbf => (opcode & 0x07) == 0x06,
+                _ => false,
+            };
+            match opcode {
+                0xcb => 4,
+                0xdd | 0xed | 0xfd => 1, // The prefix is ignored
+                _ => 1 + unprefixed_size(opcode) + if uses_hl_pointer {1} else {0},
+            }
+        }
+        _ => unprefixed_size(opcode),
+    }
+}
+
+fn unprefixed_size(opcode: u8) -> u16 {
+    match opcode {
+        0x01 | 0x11 | 0x21 | 0x31 | // LD rr,nn
+        0x22 | 0x2a | 0x32 | 0x3a | // LD (nn)
+        0xc2 | 0xc3 | 0xca | 0xd2 | 0xda | 0xe2 | 0xea | 0xf2 | 0xfa | // JP
+        0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc | 0xe4 | 0xec | 0xf4 | 0xfc   // CALL
+            => 3,
+        0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e | // LD r,n
+        0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | // DJNZ and JR
+        0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe | // ALU n
+        0xd3 | 0xdb // OUT and IN
+            => 2,
+        _ => 1,
+    }
+}
+
+/// Returns the target of CALL, JP and RST instructions
+pub fn branch_target(bytes: &[u8]) -> Option<u16> {
+    match bytes[0] {
+        0xcd | 0xc4 | 0xcc | 0xd4 | 0xdc | 0xe4 | 0xec | 0xf4 | 0xfc | // CALL
+        0xc3 | 0xc2 | 0xca | 0xd2 | 0xda | 0xe2 | 0xea | 0xf2 | 0xfa   // JP
+            => Some(bytes[1] as u16 + ((bytes[2] as u16) << 8)),
+        0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff // RST
+            => Some((bytes[0] & 0x38) as u16),
+        _ => None,
+    }
+}
@@ -93,12 +93,13 @@ impl Fcb {
             The record count must reflect the size of the current extent. For us
             only the final extent can have a record count less than 128.
         */
-        let extent = self.get_byte(env, FCB_EXTENT_OFFSET);
-        if (extent as u32) * (EXTENT_SIZE as u32) < record_count {
+        let first_record = (self.get_byte(env, FCB_EXTENT_OFFSET) as u32) * (EXTENT_SIZE as u32);
+        if first_record + (EXTENT_SIZE as u32) <= record_count {
             // We are not at the last extent:
             self.set_byte(env, FCB_RECORD_COUNT_OFFSET, EXTENT_SIZE);
         } else {
-            self.set_byte(env, FCB_RECORD_COUNT_OFFSET, (record_count % EXTENT_SIZE as u32) as u8);
+            // The records on the last extent, zero if we are past it
+            self.set_byte(env, FCB_RECORD_COUNT_OFFSET, record_count.saturating_sub(first_record) as u8);
         }
     }
 
@@ -176,6 +177,11 @@ impl Fcb {
         + (self.get_byte(env, FCB_CURRENT_RECORD_OFFSET) as u16)
     }
 
+    pub fn set_sequential_record_number(&mut self, env: &mut BdosEnvironment, record: u16) {
+        self.set_byte(env, FCB_EXTENT_OFFSET, (record / EXTENT_SIZE as u16) as u8);
+        self.set_byte(env, FCB_CURRENT_RECORD_OFFSET, (record % EXTENT_SIZE as u16) as u8);
+    }
+
     pub fn inc_current_record(&mut self, env: &mut BdosEnvironment) -> bool {
         let cr = 1 + (self.get_byte(env, FCB_CURRENT_RECORD_OFFSET) % EXTENT_SIZE);
         if cr == EXTENT_SIZE {
@@ -0,0 +1,348 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use iz80::Machine;
+
+use crate::bdos_drive;
+use crate::bdos_environment::*;
+use crate::constants::*;
+use crate::device::civil_from_days;
+use crate::fcb::{name_from_8_3, name_to_8_3};
+
+/*
+Transient commands implemented on the host. They are on every drive as COM
+files, unless there is a file with the same name, but they aren't shown on
+the directory. The COM file is a stub that calls the private BDOS function
+225 (E1h) with the command number in E, the command line is the command
+tail at 0080h:
+
+    EXIT [code]                 Leaves iz-cpm with the exit code given, 0 by
+                                default.
+    HOSTDIR [path]              Lists a host directory.
+    HOSTCD [path]               Shows or changes the host current directory,
+                                used for the host paths of these commands.
+    IMPORT path [d:][name] [T]  Copies a host file to a CP/M drive.
+    EXPORT [d:]name [path] [T]  Copies a file from a CP/M drive to the host.
+    MOUNT [d:=path]             Mounts host directories, see bdos_drive.rs.
+    DATE                        Shows the host date and time, in UTC.
+
+With [T] the files are converted as text: the host line ends are CR LF on
+CP/M and the file ends with ctrl-Z.
+
+The CCP converts the command line to upper case. The host paths are matched
+ignoring the case if they aren't found as they are, and the new host files
+are created in lower case.
+
+They can be disabled with --no-host-commands, then the functions 224 and
+225 return FFh.
+*/
+
+const HOST_COMMAND_FUNCTION: u8 = 225;
+const HOST_COMMANDS: [&str; 7] = ["EXIT", "HOSTDIR", "HOSTCD", "IMPORT", "EXPORT", "MOUNT", "DATE"];
+const TEXT_OPTION: &str = "[T]";
+const EOF: u8 = 26; // ctrl-Z
+
+/// The COM file of the host command, if the 8.3 name is one
+pub fn stub(cpm_name: &str) -> Option<Vec<u8>> {
+    let (name, extension) = cpm_name.split_once('.')?;
+    if extension != "COM" {
+        return None;
+    }
+    let command = HOST_COMMANDS.iter().position(|command| *command == name.trim_end())?;
+    Some(vec!(
+        0x1e, command as u8,            // ld e, command
+        0x0e, HOST_COMMAND_FUNCTION,    // ld c, 225
+        0xcd, 0x05, 0x00,               // call 5
+        0xc3, 0x00, 0x00))              // jp 0
+}
+
+pub fn execute(env: &mut BdosEnvironment, command: u8) -> ExecutionResult {
+    let length = env.machine.peek(SYSTEM_PARAMS_ADDRESS) as u16;
+    let tail: String = (0..length).map(|i| env.machine.peek(SYSTEM_PARAMS_ADDRESS + 1 + i) as char).collect();
+    let mut args: Vec<&str> = tail.split_whitespace().collect();
+    let text = args.last() == Some(&TEXT_OPTION);
+    if text {
+        args.pop();
+    }
+
+    let name = HOST_COMMANDS.get(command as usize).copied().unwrap_or("");
+    let result = match name {
+        "EXIT" => match args.as_slice() {
+            [] => return ExecutionResult::Exit(0),
+            [code] => match code.parse::<u8>() {
+                Ok(code) => return ExecutionResult::Exit(code),
+                Err(_) => Err(format!("Invalid exit code \"{}\"", code)),
+            },
+            _ => Err("Use EXIT [code]".to_string()),
+        },
+        "HOSTDIR" => host_dir(env, &args),
+        "HOSTCD" => host_cd(env, &args),
+        "IMPORT" => import(env, &args, text),
+        "EXPORT" => export(env, &args, text),
+        "MOUNT" => {
+            bdos_drive::mount(env, SYSTEM_PARAMS_ADDRESS);
+            Ok(())
+        },
+        "DATE" => {
+            let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+            env.bios.write_string(env.console, &format!("{}\r\n", format_date(seconds)));
+            Ok(())
+        },
+        _ => Err(format!("Unknown host command {}", command)),
+    };
+    if let Err(err) = result {
+        env.bios.write_string(env.console, &format!("{}: {}\r\n", name, err));
+    }
+    ExecutionResult::Continue
+}
+
+/// The host path, relative to the base, matching each component ignoring
+/// the case if it doesn't exist as it is
+pub fn find_host_path(base: &Path, path: &str) -> Option<PathBuf> {
+    let path = base.join(path);
+    if path.exists() {
+        return Some(path);
+    }
+    let mut found = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                let candidate = found.join(name);
+                if candidate.exists() {
+                    found = candidate;
+                    continue;
+                }
+                let parent = if found.as_os_str().is_empty() { Path::new(".") } else { found.as_path() };
+                let name = name.to_string_lossy();
+                let entry = fs::read_dir(parent).ok()?
+                    .filter_map(|entry| entry.ok())
+                    .find(|entry| entry.file_name().to_string_lossy().eq_ignore_ascii_case(&name))?;
+                found.push(entry.file_name());
+            },
+            other => found.push(other),
+        }
+    }
+    if found.exists() {
+        Some(found)
+    } else {
+        None
+    }
+}
+
+// A path for a new host file, the name in lower case if it doesn't exist
+fn new_host_path(base: &Path, path: &str) -> Option<PathBuf> {
+    if let Some(found) = find_host_path(base, path) {
+        return Some(found);
+    }
+    let (parent, name) = match path.rsplit_once(['/', '\\']) {
+        Some((parent, name)) => (find_host_path(base, if parent.is_empty() { "/" } else { parent })?, name),
+        None => (base.to_path_buf(), path),
+    };
+    if parent.is_dir() && !name.is_empty() {
+        Some(parent.join(name.to_ascii_lowercase()))
+    } else {
+        None
+    }
+}
+
+fn host_dir(env: &mut BdosEnvironment, args: &[&str]) -> Result<(), String> {
+    let path = match args {
+        [] => ".",
+        [path] => path,
+        _ => return Err("Use HOSTDIR [path]".to_string()),
+    };
+    let directory = find_host_path(&env.state.host_directory, path)
+        .filter(|directory| directory.is_dir())
+        .ok_or_else(|| format!("Directory \"{}\" not found", path))?;
+    let entries = fs::read_dir(&directory).map_err(|err| err.to_string())?;
+    let mut names: Vec<String> = entries.filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() { name + "/" } else { name }
+        })
+        .collect();
+    names.sort();
+    let mut text = String::new();
+    for name in names {
+        text += &name;
+        text += "\r\n";
+    }
+    env.bios.write_string(env.console, &text);
+    Ok(())
+}
+
+fn host_cd(env: &mut BdosEnvironment, args: &[&str]) -> Result<(), String> {
+    match args {
+        [] => (),
+        [path] => {
+            let directory = find_host_path(&env.state.host_directory, path)
+                .filter(|directory| directory.is_dir())
+                .ok_or_else(|| format!("Directory \"{}\" not found", path))?;
+            env.state.host_directory = fs::canonicalize(&directory).map_err(|err| err.to_string())?;
+        },
+        _ => return Err("Use HOSTCD [path]".to_string()),
+    }
+    let current = fs::canonicalize(env.state.host_directory.join(".")).map_err(|err| err.to_string())?;
+    env.bios.write_string(env.console, &format!("{}\r\n", current.display()));
+    Ok(())
+}
+
+// A file on a CP/M drive, [d:]name. The drive and the 8.3 name.
+fn parse_cpm_file(env: &BdosEnvironment, spec: &str, default_name: Option<&str>) -> Result<(u8, String), String> {
+    let (drive, name) = match spec.as_bytes() {
+        [drive, b':', ..] => match drive.to_ascii_uppercase() {
+            drive @ b'A'..=b'P' => (drive - b'A', &spec[2..]),
+            _ => return Err(format!("Invalid drive in \"{}\"", spec)),
+        },
+        _ => (env.state.drive, spec),
+    };
+    let name = match (name, default_name) {
+        ("", Some(default_name)) => default_name,
+        ("", None) => return Err("Missing the file name".to_string()),
+        (name, _) => name,
+    };
+    match name_to_8_3(name) {
+        Some(cpm_name) if cpm_name.contains(['?', '*']) => Err(format!("Wildcards are not allowed, \"{}\"", name)),
+        Some(cpm_name) if !cpm_name.starts_with(' ') => Ok((drive, cpm_name)),
+        _ => Err(format!("Invalid CP/M file name \"{}\"", name)),
+    }
+}
+
+// The directory of a drive and the host file for the 8.3 name, if it exists
+fn cpm_host_file(env: &BdosEnvironment, drive: u8, cpm_name: &str) -> Result<(PathBuf, Option<PathBuf>), String> {
+    let directory = env.state.directories[drive as usize].as_ref()
+        .ok_or_else(|| format!("Drive {}: is not mounted", (b'A' + drive) as char))?;
+    let entries = fs::read_dir(directory).map_err(|err| err.to_string())?;
+    let file = entries.filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .find(|entry| name_to_8_3(&entry.file_name().to_string_lossy()).as_deref() == Some(cpm_name))
+        .map(|entry| entry.path());
+    Ok((PathBuf::from(directory), file))
+}
+
+fn import(env: &mut BdosEnvironment, args: &[&str], text: bool) -> Result<(), String> {
+    let (source, destination) = match args {
+        [source] => (*source, ""),
+        [source, destination] => (*source, *destination),
+        _ => return Err("Use IMPORT path [d:][name] [T]".to_string()),
+    };
+    let source_path = find_host_path(&env.state.host_directory, source)
+        .filter(|path| path.is_file())
+        .ok_or_else(|| format!("File \"{}\" not found", source))?;
+    let source_name = source_path.file_name().map(|name| name.to_string_lossy().to_string());
+    let (drive, cpm_name) = parse_cpm_file(env, destination, source_name.as_deref())?;
+    if env.state.read_only_bitmap & 1 << drive != 0 {
+        return Err(format!("Drive {}: is read only", (b'A' + drive) as char));
+    }
+    let (directory, existing) = cpm_host_file(env, drive, &cpm_name)?;
+    let destination_path = existing.unwrap_or_else(|| directory.join(name_from_8_3(&cpm_name)));
+
+    let mut data = fs::read(&source_path).map_err(|err| err.to_string())?;
+    if text {
+        data = text_to_cpm(&data);
+    }
+    fs::write(&destination_path, data).map_err(|err| err.to_string())
+}
+
+fn export(env: &mut BdosEnvironment, args: &[&str], text: bool) -> Result<(), String> {
+    let (source, destination) = match args {
+        [source] => (*source, None),
+        [source, destination] => (*source, Some(*destination)),
+        _ => return Err("Use EXPORT [d:]name [path] [T]".to_string()),
+    };
+    let (drive, cpm_name) = parse_cpm_file(env, source, None)?;
+    let source_path = cpm_host_file(env, drive, &cpm_name)?.1
+        .ok_or_else(|| format!("File {}:{} not found", (b'A' + drive) as char, name_from_8_3(&cpm_name)))?;
+    let source_name = source_path.file_name().unwrap_or_default();
+    let base = &env.state.host_directory;
+    let destination_path = match destination {
+        None => base.join(source_name),
+        Some(destination) => match new_host_path(base, destination) {
+            Some(path) if path.is_dir() => path.join(source_name),
+            Some(path) => path,
+            None => return Err(format!("Can't create \"{}\"", destination)),
+        },
+    };
+
+    let mut data = fs::read(&source_path).map_err(|err| err.to_string())?;
+    if text {
+        data = text_from_cpm(&data);
+    }
+    fs::write(&destination_path, data).map_err(|err| err.to_string())
+}
+
+/// Host text to CP/M text, with CR LF line ends and a ctrl-Z at the end
+pub fn text_to_cpm(data: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(data.len() + data.len() / 32 + 1);
+    let mut previous = 0;
+    for &byte in data {
+        if byte == b'\n' && previous != b'\r' {
+            converted.push(b'\r');
+        }
+        converted.push(byte);
+        previous = byte;
+    }
+    converted.push(EOF);
+    converted
+}
+
+/// CP/M text to host text, up to the ctrl-Z and with LF line ends
+pub fn text_from_cpm(data: &[u8]) -> Vec<u8> {
+    let end = data.iter().position(|&byte| byte == EOF).unwrap_or(data.len());
+    let data = &data[..end];
+    let mut converted = Vec::with_capacity(data.len());
+    for (i, &byte) in data.iter().enumerate() {
+        if byte != b'\r' || data.get(i + 1) != Some(&b'\n') {
+            converted.push(byte);
+        }
+    }
+    converted
+}
+
+/// The date and time for the seconds since 1970, in UTC
+pub fn format_date(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day,
+        time / 3600, time / 60 % 60, time % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stub() {
+        assert_eq!(stub("EXIT    .COM").unwrap()[1], 0);
+        assert_eq!(stub("DATE    .COM").unwrap()[1], 6);
+        assert_eq!(stub("DATE    .TXT"), None);
+        assert_eq!(stub("DATES   .COM"), None);
+    }
+
+    #[test]
+    fn test_find_host_path() {
+        let base = PathBuf::new();
+        assert_eq!(find_host_path(&base, "TESTS/ARTIFACTS"), Some(PathBuf::from("tests/artifacts")));
+        assert_eq!(find_host_path(&base, "src"), Some(PathBuf::from("src")));
+        assert_eq!(find_host_path(&base, "SRC/LIB.RS"), Some(PathBuf::from("src/lib.rs")));
+        assert_eq!(find_host_path(&PathBuf::from("src"), "LIB.RS"), Some(PathBuf::from("src/lib.rs")));
+        assert_eq!(find_host_path(&base, "NOWHERE"), None);
+        assert_eq!(new_host_path(&base, "SRC/NEW.RS"), Some(PathBuf::from("src/new.rs")));
+        assert_eq!(new_host_path(&base, "NOWHERE/NEW.RS"), None);
+    }
+
+    #[test]
+    fn test_text_conversion() {
+        assert_eq!(text_to_cpm(b"AB\nC\r\nD"), b"AB\r\nC\r\nD\x1a");
+        assert_eq!(text_from_cpm(b"AB\r\nC\rD\x1a\x1a\x1a"), b"AB\nC\rD");
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_date(951782400 + 3661), "2000-02-29 01:01:01 UTC");
+        assert_eq!(format_date(1792281600), "2026-10-18 00:00:00 UTC");
+    }
+}
@@ -0,0 +1,315 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use iz80::*;
+
+use crate::cpm_machine::CpmMachine;
+
+/*
+Maskable interrupts. The CPU emulation doesn't have them, the controller
+follows the instructions that change the interrupt state (EI, DI, IM n,
+RETN and RETI) before they are executed, and when a source requests an
+interrupt with them enabled, it pushes PC and jumps as the CPU would:
+
+    IM 0 and 8080: the data byte must be a RST instruction, like FF for RST 38h.
+    IM 1: call to 0038h.
+    IM 2: call to the address in the table at I*256 + the data byte.
+
+The interrupts are accepted after the instruction following EI. The sources
+are the periodic timer (--timer hz[:data]) and the devices on the I/O ports.
+The timer uses the host time and its interrupt is cleared when accepted, the
+devices keep the request until the condition is served.
+
+With the interrupts enabled, HALT waits for the next interrupt. With them
+disabled, HALT ends the session as there is nothing that could resume it.
+
+The interrupts are not accepted with PC on the BDOS or BIOS traps. The trap
+has already run when PC gets there, and it would run again on the return
+from the interrupt.
+
+The IFF1 and IFF2 of the CPU emulation can't be changed from outside, they
+stay as EI left them when an interrupt is accepted. LD A,I and LD A,R don't
+copy IFF2 to the P/V flag and RETN is followed here with the state of the
+controller, so the difference is not seen by the programs.
+*/
+
+// Calls to check the host time for the timer
+const TIMER_CHECK_INTERVAL: u32 = 128;
+// Maximum wait in HALT before checking the sources again
+const HALT_WAIT: Duration = Duration::from_millis(1);
+
+struct Timer {
+    period: Duration,
+    next: Instant,
+    data: u8,
+    pending: bool,
+}
+
+pub struct InterruptController {
+    z80: bool,
+    iff1: bool,
+    iff2: bool,
+    mode: u8,
+    ei_delay: bool,
+    halted: bool,
+    timer: Option<Timer>,
+    calls: u32,
+}
+
+impl InterruptController {
+    pub fn new(z80: bool) -> InterruptController {
+        InterruptController {
+            z80,
+            iff1: false,
+            iff2: false,
+            mode: 0,
+            ei_delay: false,
+            halted: false,
+            timer: None,
+            calls: 0,
+        }
+    }
+
+    pub fn set_timer(&mut self, hz: f64, data: u8) {
+        let period = Duration::from_secs_f64(1.0 / hz);
+        self.timer = Some(Timer {
+            period,
+            next: Instant::now() + period,
+            data,
+            pending: false,
+        });
+    }
+
+    /// Waiting in HALT for an interrupt
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    fn update_timer(&mut self) {
+        if let Some(timer) = self.timer.as_mut() {
+            let now = Instant::now();
+            if now >= timer.next {
+                timer.pending = true;
+                timer.next += timer.period;
+                if timer.next < now {
+                    // Too late, skip the lost ticks
+                    timer.next = now + timer.period;
+                }
+            }
+        }
+    }
+
+    fn request(&mut self, machine: &mut CpmMachine) -> Option<u8> {
+        if let Some(timer) = self.timer.as_mut() {
+            if timer.pending {
+                timer.pending = false;
+                return Some(timer.data);
+            }
+        }
+        machine.devices().interrupt_request()
+    }
+
+    fn accept(&mut self, data: u8, reg: &mut Registers, machine: &mut CpmMachine) {
+        self.iff1 = false;
+        self.iff2 = false;
+        self.halted = false;
+        let address = match self.mode {
+            1 if self.z80 => 0x0038,
+            2 if self.z80 => {
+                let table = ((reg.get8(Reg8::I) as u16) << 8) | (data & 0xfe) as u16;
+                machine.peek16(table)
+            },
+            _ => (data & 0x38) as u16, // RST n
+        };
+        let sp = reg.get16(Reg16::SP).wrapping_sub(2);
+        reg.set16(Reg16::SP, sp);
+        machine.poke16(sp, reg.pc());
+        reg.set_pc(address);
+    }
+
+    /// Called before each instruction. Accepts the pending interrupts, if PC
+    /// is not on a trap, and follows the changes of the interrupt state.
+    /// While halted, waits for the host time of the timer.
+    pub fn before_instruction(&mut self, reg: &mut Registers, machine: &mut CpmMachine, on_trap: bool) {
+        if self.halted {
+            if let Some(timer) = &self.timer {
+                let wait = timer.next.saturating_duration_since(Instant::now());
+                thread::sleep(wait.min(HALT_WAIT));
+            } else {
+                thread::sleep(HALT_WAIT);
+            }
+            self.update_timer();
+        } else {
+            self.calls += 1;
+            if self.calls >= TIMER_CHECK_INTERVAL {
+                self.calls = 0;
+                self.update_timer();
+            }
+        }
+
+        if self.iff1 && !self.ei_delay && !on_trap {
+            if let Some(data) = self.request(machine) {
+                self.accept(data, reg, machine);
+            }
+        }
+        self.ei_delay = false;
+        if self.halted {
+            return;
+        }
+
+        let pc = reg.pc();
+        match machine.peek(pc) {
+            0xfb => { // EI
+                self.iff1 = true;
+                self.iff2 = true;
+                self.ei_delay = true;
+            },
+            0xf3 => { // DI
+                self.iff1 = false;
+                self.iff2 = false;
+            },
+            0x76 if self.iff1 => { // HALT, the CPU doesn't execute it
+                self.halted = true;
+                reg.set_pc(pc.wrapping_add(1));
+            },
+            0xed if self.z80 => match machine.peek(pc.wrapping_add(1)) {
+                0x46 | 0x4e | 0x66 | 0x6e => self.mode = 0,
+                0x56 | 0x76 => self.mode = 1,
+                0x5e | 0x7e => self.mode = 2,
+                0x45 | 0x4d | 0x55 | 0x5d | 0x65 | 0x6d | 0x75 | 0x7d => {
+                    // RETI and RETN
+                    self.iff1 = self.iff2;
+                },
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Parses the --timer option: frequency in Hz and optionally the data byte
+pub fn parse_timer(text: &str) -> Option<(f64, u8)> {
+    let (hz, data) = match text.split_once(':') {
+        Some((hz, data)) => (hz, u8::from_str_radix(data, 16).ok()?),
+        None => (text, 0xff),
+    };
+    let hz = hz.parse::<f64>().ok()?;
+    if hz > 0.0 && hz <= 100_000.0 {
+        Some((hz, data))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(program: &[u8]) -> (Cpu, CpmMachine) {
+        let mut machine = CpmMachine::new();
+        for (i, value) in program.iter().enumerate() {
+            machine.poke(0x100 + i as u16, *value);
+        }
+        let mut cpu = Cpu::new_z80();
+        cpu.registers().set_pc(0x100);
+        cpu.registers().set16(Reg16::SP, 0x8000);
+        (cpu, machine)
+    }
+
+    fn step(controller: &mut InterruptController, cpu: &mut Cpu, machine: &mut CpmMachine) {
+        controller.before_instruction(cpu.registers(), machine, false);
+        if !controller.is_halted() {
+            cpu.execute_instruction(machine);
+        }
+    }
+
+    #[test]
+    fn test_im2_after_ei_delay() {
+        // IM 2, LD A,80h, LD I,A, EI, NOP, NOP
+        let (mut cpu, mut machine) = setup(&[0xed, 0x5e, 0x3e, 0x80, 0xed, 0x47, 0xfb, 0x00, 0x00]);
+        machine.poke16(0x8040, 0x1234);
+        let mut controller = InterruptController::new(true);
+        controller.set_timer(1000.0, 0x40);
+        for _ in 0..4 {
+            step(&mut controller, &mut cpu, &mut machine);
+        }
+        assert_eq!(cpu.registers().pc(), 0x107);
+        controller.timer.as_mut().unwrap().pending = true;
+        // Not accepted, the instruction after EI is executed
+        step(&mut controller, &mut cpu, &mut machine);
+        assert_eq!(cpu.registers().pc(), 0x108);
+        step(&mut controller, &mut cpu, &mut machine);
+        // Accepted, then the first instruction at 1234h executed
+        assert_eq!(machine.peek16(0x7ffe), 0x108);
+        assert_eq!(cpu.registers().pc(), 0x1235);
+        assert!(!controller.iff1);
+    }
+
+    #[test]
+    fn test_halt_resumes_on_interrupt() {
+        // IM 1, EI, HALT, NOP
+        let (mut cpu, mut machine) = setup(&[0xed, 0x56, 0xfb, 0x76, 0x00]);
+        let mut controller = InterruptController::new(true);
+        controller.set_timer(1000.0, 0xff);
+        for _ in 0..3 {
+            step(&mut controller, &mut cpu, &mut machine);
+        }
+        assert!(controller.is_halted());
+        assert_eq!(cpu.registers().pc(), 0x104);
+        while controller.is_halted() {
+            step(&mut controller, &mut cpu, &mut machine);
+        }
+        assert_eq!(machine.peek16(0x7ffe), 0x104);
+        assert_eq!(cpu.registers().pc(), 0x0039);
+    }
+
+    #[test]
+    fn test_not_accepted_on_trap() {
+        // IM 1, EI, NOP, NOP
+        let (mut cpu, mut machine) = setup(&[0xed, 0x56, 0xfb, 0x00, 0x00]);
+        let mut controller = InterruptController::new(true);
+        controller.set_timer(1000.0, 0xff);
+        for _ in 0..3 {
+            step(&mut controller, &mut cpu, &mut machine);
+        }
+        controller.timer.as_mut().unwrap().pending = true;
+        controller.before_instruction(cpu.registers(), &mut machine, true);
+        assert_eq!(cpu.registers().pc(), 0x104);
+        controller.before_instruction(cpu.registers(), &mut machine, false);
+        assert_eq!(cpu.registers().pc(), 0x0038);
+    }
+
+    #[test]
+    fn test_iff_on_the_interrupt_routine() {
+        // IM 1, EI, NOP, NOP and LD A,I, RETN at 0038h
+        let (mut cpu, mut machine) = setup(&[0xed, 0x56, 0xfb, 0x00, 0x00]);
+        machine.poke(0x38, 0xed);
+        machine.poke(0x39, 0x57);
+        machine.poke(0x3a, 0xed);
+        machine.poke(0x3b, 0x45);
+        let mut controller = InterruptController::new(true);
+        controller.set_timer(1000.0, 0xff);
+        for _ in 0..3 {
+            step(&mut controller, &mut cpu, &mut machine);
+        }
+        controller.timer.as_mut().unwrap().pending = true;
+        cpu.registers().set_flag(Flag::P);
+        // Accepted, then LD A,I executed. P/V is not changed.
+        step(&mut controller, &mut cpu, &mut machine);
+        assert_eq!(cpu.registers().pc(), 0x3a);
+        assert!(!controller.iff2);
+        assert!(cpu.registers().get_flag(Flag::P));
+        // RETN keeps the interrupts disabled
+        step(&mut controller, &mut cpu, &mut machine);
+        assert_eq!(cpu.registers().pc(), 0x104);
+        assert!(!controller.iff1);
+    }
+
+    #[test]
+    fn test_parse_timer() {
+        assert_eq!(parse_timer("50"), Some((50.0, 0xff)));
+        assert_eq!(parse_timer("18.2:cf"), Some((18.2, 0xcf)));
+        assert_eq!(parse_timer("0"), None);
+        assert_eq!(parse_timer("60:zz"), None);
+    }
+}
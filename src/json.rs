@@ -0,0 +1,60 @@
+/*
+Minimal JSON output helpers. We only need to write JSON for reports and
+traces, never to parse it.
+*/
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32))
+            },
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+pub struct JsonObject {
+    fields: Vec<String>,
+}
+
+impl JsonObject {
+    pub fn new() -> JsonObject {
+        JsonObject {
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn str(mut self, key: &str, value: &str) -> JsonObject {
+        self.fields.push(format!("{}:{}", escape(key), escape(value)));
+        self
+    }
+
+    pub fn num<T: std::fmt::Display>(mut self, key: &str, value: T) -> JsonObject {
+        self.fields.push(format!("{}:{}", escape(key), value));
+        self
+    }
+
+    // The value must already be valid JSON
+    pub fn raw(mut self, key: &str, value: &str) -> JsonObject {
+        self.fields.push(format!("{}:{}", escape(key), value));
+        self
+    }
+
+    pub fn build(&self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+
+pub fn array(items: &[String]) -> String {
+    format!("[{}]", items.join(","))
+}
@@ -0,0 +1,453 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/*
+Translation of the keys of the host terminal to the keys expected by the CP/M
+programs. It is the mirror of the TerminalEmulator on the input side.
+
+The host terminal sends the cursor, editing and function keys as ANSI escape
+sequences. They are decoded to a Key and replaced with the bytes configured on
+the KeyMap. The keys without a mapping are dropped. Other sequences are passed
+as they are.
+
+    Cursor keys                 ESC[A ESC[B ESC[C ESC[D or ESCOA ESCOB ESCOC ESCOD
+    Home and End                ESC[H ESC[F ESCOH ESCOF ESC[1~ ESC[4~ ESC[7~ ESC[8~
+    Insert, Delete              ESC[2~ ESC[3~
+    Page up, Page down          ESC[5~ ESC[6~
+    F1 to F4                    ESCOP ESCOQ ESCOR ESCOS or ESC[11~ to ESC[14~
+    F5 to F12                   ESC[15~ ESC[17~ to ESC[21~ ESC[23~ ESC[24~
+    With Ctrl                   ESC[1;5A, ESC[5;5~ and so on
+
+The ESC key alone can't be told apart from the start of a sequence. If no more
+bytes come in ESC_TIMEOUT, it is sent to the program as is.
+
+Presets:
+    none        No translation, the sequences are sent as they are
+    adm3a       Cursor keys as the ADM-3A and Kaypro keyboards: ^H ^L ^K ^J, Home ^^
+    wordstar    WordStar diamond: ^S ^D ^E ^X, ^A ^F for Ctrl left and right,
+                ^R ^C for page up and down, ^QS ^QD for Home and End, ^QR ^QC
+                with Ctrl, ^V insert, ^G delete, F1 ^J help
+    turbo       Turbo Pascal editor: as WordStar, plus ^QE ^QX for Ctrl Home and
+                End, top and bottom of the screen, and without help on F1
+*/
+
+const ESC: u8 = 27;
+const ESC_TIMEOUT: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    CtrlLeft,
+    CtrlRight,
+    CtrlHome,
+    CtrlEnd,
+    CtrlPageUp,
+    CtrlPageDown,
+    F(u8),
+}
+
+const KEY_NAMES: [(Key, &str); 16] = [
+    (Key::Up, "up"),
+    (Key::Down, "down"),
+    (Key::Left, "left"),
+    (Key::Right, "right"),
+    (Key::Home, "home"),
+    (Key::End, "end"),
+    (Key::PageUp, "pgup"),
+    (Key::PageDown, "pgdn"),
+    (Key::Insert, "ins"),
+    (Key::Delete, "del"),
+    (Key::CtrlLeft, "ctrl-left"),
+    (Key::CtrlRight, "ctrl-right"),
+    (Key::CtrlHome, "ctrl-home"),
+    (Key::CtrlEnd, "ctrl-end"),
+    (Key::CtrlPageUp, "ctrl-pgup"),
+    (Key::CtrlPageDown, "ctrl-pgdn"),
+];
+
+impl Key {
+    pub fn from_name(name: &str) -> Option<Key> {
+        let name = name.to_ascii_lowercase();
+        if let Some(&(key, _)) = KEY_NAMES.iter().find(|(_, n)| *n == name) {
+            return Some(key);
+        }
+        match name.strip_prefix('f').map(|n| n.parse::<u8>()) {
+            Some(Ok(n)) if (1..=12).contains(&n) => Some(Key::F(n)),
+            _ => None,
+        }
+    }
+
+    fn with_ctrl(self) -> Key {
+        match self {
+            Key::Left => Key::CtrlLeft,
+            Key::Right => Key::CtrlRight,
+            Key::Home => Key::CtrlHome,
+            Key::End => Key::CtrlEnd,
+            Key::PageUp => Key::CtrlPageUp,
+            Key::PageDown => Key::CtrlPageDown,
+            key => key,
+        }
+    }
+}
+
+pub struct KeyMap {
+    keys: HashMap<Key, Vec<u8>>,
+}
+
+impl KeyMap {
+    pub fn new() -> KeyMap {
+        KeyMap {
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn from_preset(name: &str) -> Option<KeyMap> {
+        let mappings: &[(Key, &str)] = match name {
+            "none" => &[],
+            "adm3a" => &[
+                (Key::Up, "^K"), (Key::Down, "^J"), (Key::Left, "^H"), (Key::Right, "^L"),
+                (Key::Home, "^^"),
+            ],
+            "wordstar" => &[
+                (Key::Up, "^E"), (Key::Down, "^X"), (Key::Left, "^S"), (Key::Right, "^D"),
+                (Key::CtrlLeft, "^A"), (Key::CtrlRight, "^F"),
+                (Key::PageUp, "^R"), (Key::PageDown, "^C"),
+                (Key::Home, "^QS"), (Key::End, "^QD"),
+                (Key::CtrlPageUp, "^QR"), (Key::CtrlPageDown, "^QC"),
+                (Key::Insert, "^V"), (Key::Delete, "^G"), (Key::F(1), "^J"),
+            ],
+            "turbo" => &[
+                (Key::Up, "^E"), (Key::Down, "^X"), (Key::Left, "^S"), (Key::Right, "^D"),
+                (Key::CtrlLeft, "^A"), (Key::CtrlRight, "^F"),
+                (Key::PageUp, "^R"), (Key::PageDown, "^C"),
+                (Key::Home, "^QS"), (Key::End, "^QD"),
+                (Key::CtrlHome, "^QE"), (Key::CtrlEnd, "^QX"),
+                (Key::CtrlPageUp, "^QR"), (Key::CtrlPageDown, "^QC"),
+                (Key::Insert, "^V"), (Key::Delete, "^G"),
+            ],
+            _ => return None,
+        };
+        let mut map = KeyMap::new();
+        for (key, keystrokes) in mappings {
+            map.set(*key, parse_keystrokes(keystrokes));
+        }
+        Some(map)
+    }
+
+    pub fn set(&mut self, key: Key, keystrokes: Vec<u8>) {
+        self.keys.insert(key, keystrokes);
+    }
+
+    /// Parses a "key=keystrokes" definition, like "f1=^KD", and adds it to
+    /// the map.
+    pub fn parse_definition(&mut self, definition: &str) -> bool {
+        match definition.split_once('=') {
+            Some((name, keystrokes)) => match Key::from_name(name) {
+                Some(key) => {
+                    self.set(key, parse_keystrokes(keystrokes));
+                    true
+                },
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parses keystrokes with the caret notation for the control chars: ^QS
+/// is Ctrl-Q followed by S.
+pub fn parse_keystrokes(text: &str) -> Vec<u8> {
+    let mut keystrokes = Vec::new();
+    let mut chars = text.bytes();
+    while let Some(ch) = chars.next() {
+        if ch == b'^' {
+            match chars.next() {
+                Some(next) => keystrokes.push(next.to_ascii_uppercase() & 0x1f),
+                None => keystrokes.push(ch),
+            }
+        } else {
+            keystrokes.push(ch);
+        }
+    }
+    keystrokes
+}
+
+pub struct KeyTranslator {
+    map: KeyMap,
+    sequence: Vec<u8>, // Unfinished escape sequence
+    sequence_start: Instant,
+    ready: VecDeque<(u8, bool)>, // The bytes and if they were typed on the host
+}
+
+impl KeyTranslator {
+    pub fn new(map: KeyMap) -> KeyTranslator {
+        KeyTranslator {
+            map,
+            sequence: Vec::new(),
+            sequence_start: Instant::now(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Adds a byte received from the host keyboard
+    pub fn input(&mut self, ch: u8) {
+        if self.map.is_empty() {
+            self.ready.push_back((ch, true));
+            return;
+        }
+
+        if self.sequence.is_empty() {
+            if ch == ESC {
+                self.sequence.push(ch);
+                self.sequence_start = Instant::now();
+            } else {
+                self.ready.push_back((ch, true));
+            }
+            return;
+        }
+
+        self.sequence.push(ch);
+        match decode(&self.sequence) {
+            Decoded::Incomplete => (),
+            Decoded::Key(key) => {
+                self.sequence.clear();
+                if let Some(keystrokes) = self.map.keys.get(&key) {
+                    self.ready.extend(keystrokes.iter().map(|&ch| (ch, false)));
+                }
+            },
+            Decoded::Unknown => {
+                self.ready.extend(self.sequence.drain(..).map(|ch| (ch, true)));
+            },
+            Decoded::LoneEscape => {
+                // The ESC key followed by other key, the new byte can start
+                // a sequence.
+                self.sequence.clear();
+                self.ready.push_back((ESC, true));
+                self.input(ch);
+            },
+        }
+    }
+
+    /// Sends the unfinished sequence as it is if it has been waiting for
+    /// longer than ESC_TIMEOUT.
+    pub fn check_timeout(&mut self) {
+        if !self.sequence.is_empty() && self.sequence_start.elapsed() >= ESC_TIMEOUT {
+            self.ready.extend(self.sequence.drain(..).map(|ch| (ch, true)));
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        !self.sequence.is_empty()
+    }
+
+    /// The next byte and true if it was typed on the host, false if it
+    /// comes from a key mapping
+    pub fn peek(&self) -> Option<(u8, bool)> {
+        self.ready.front().copied()
+    }
+
+    /// As peek(), removing the byte
+    pub fn pop(&mut self) -> Option<(u8, bool)> {
+        self.ready.pop_front()
+    }
+}
+
+impl Default for KeyTranslator {
+    fn default() -> Self {
+        Self::new(KeyMap::new())
+    }
+}
+
+enum Decoded {
+    Incomplete,
+    Key(Key),
+    Unknown,
+    LoneEscape,
+}
+
+fn decode(sequence: &[u8]) -> Decoded {
+    match sequence {
+        [ESC] => Decoded::Incomplete,
+        [ESC, b'O'] | [ESC, b'['] => Decoded::Incomplete,
+        [ESC, b'O', last] => match last {
+            b'A' => Decoded::Key(Key::Up),
+            b'B' => Decoded::Key(Key::Down),
+            b'C' => Decoded::Key(Key::Right),
+            b'D' => Decoded::Key(Key::Left),
+            b'H' => Decoded::Key(Key::Home),
+            b'F' => Decoded::Key(Key::End),
+            b'P'..=b'S' => Decoded::Key(Key::F(last - b'P' + 1)),
+            _ => Decoded::Unknown,
+        },
+        [ESC, b'[', rest @ ..] => {
+            let last = rest[rest.len() - 1];
+            if last.is_ascii_digit() || last == b';' {
+                if rest.len() > 8 {
+                    Decoded::Unknown // Too long
+                } else {
+                    Decoded::Incomplete
+                }
+            } else {
+                decode_csi(&rest[..rest.len() - 1], last)
+            }
+        },
+        _ => Decoded::LoneEscape,
+    }
+}
+
+fn decode_csi(params: &[u8], last: u8) -> Decoded {
+    let params = String::from_utf8_lossy(params);
+    let mut values = params.split(';').map(|value| value.parse::<u8>().unwrap_or(0));
+    let number = values.next().unwrap_or(0);
+    let modifier = values.next().unwrap_or(1);
+
+    let key = match last {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'F' => Key::End,
+        b'~' => match number {
+            1 | 7 => Key::Home,
+            2 => Key::Insert,
+            3 => Key::Delete,
+            4 | 8 => Key::End,
+            5 => Key::PageUp,
+            6 => Key::PageDown,
+            11..=15 => Key::F(number - 10),
+            17..=21 => Key::F(number - 11),
+            23 | 24 => Key::F(number - 12),
+            _ => return Decoded::Unknown,
+        },
+        _ => return Decoded::Unknown,
+    };
+
+    // The modifier is 1 plus a bit mask: 1 shift, 2 alt, 4 ctrl
+    if modifier >= 1 && (modifier - 1) & 4 != 0 {
+        Decoded::Key(key.with_ctrl())
+    } else {
+        Decoded::Key(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate_all(translator: &mut KeyTranslator, input: &[u8]) -> Vec<u8> {
+        for &ch in input {
+            translator.input(ch);
+        }
+        let mut output = Vec::new();
+        while let Some((ch, _)) = translator.pop() {
+            output.push(ch);
+        }
+        output
+    }
+
+    #[test]
+    fn test_parse_keystrokes() {
+        assert_eq!(parse_keystrokes("^QS"), vec!(17, b'S'));
+        assert_eq!(parse_keystrokes("^^a^"), vec!(30, b'a', b'^'));
+        assert_eq!(parse_keystrokes("^k^d"), vec!(11, 4));
+    }
+
+    #[test]
+    fn test_adm3a_cursor_keys() {
+        let mut translator = KeyTranslator::new(KeyMap::from_preset("adm3a").unwrap());
+        assert_eq!(translate_all(&mut translator, b"a\x1b[A\x1b[B\x1bOC\x1bOD\x1b[Hb"), b"a\x0b\x0a\x0c\x08\x1eb");
+        // Keys not mapped are dropped
+        assert_eq!(translate_all(&mut translator, b"\x1b[5~\x1bOP"), b"");
+    }
+
+    #[test]
+    fn test_wordstar_diamond() {
+        let mut translator = KeyTranslator::new(KeyMap::from_preset("wordstar").unwrap());
+        assert_eq!(translate_all(&mut translator, b"\x1b[A\x1b[B\x1b[D\x1b[C"), b"\x05\x18\x13\x04");
+        assert_eq!(translate_all(&mut translator, b"\x1b[1~\x1b[F\x1b[3~\x1bOP"), b"\x11S\x11D\x07\x0a");
+        assert_eq!(translate_all(&mut translator, b"\x1b[1;5D\x1b[5;5~"), b"\x01\x11R");
+    }
+
+    #[test]
+    fn test_turbo_ctrl_home_end() {
+        let mut translator = KeyTranslator::new(KeyMap::from_preset("turbo").unwrap());
+        assert_eq!(translate_all(&mut translator, b"\x1b[1;5H\x1b[1;5F"), b"\x11E\x11X");
+        assert_eq!(translate_all(&mut translator, b"\x1bOP"), b"");
+    }
+
+    #[test]
+    fn test_custom_definition() {
+        let mut map = KeyMap::from_preset("none").unwrap();
+        assert!(map.parse_definition("f10=^KX"));
+        assert!(map.parse_definition("PGUP=^R"));
+        assert!(!map.parse_definition("nokey=^R"));
+        assert!(!map.parse_definition("f1"));
+        let mut translator = KeyTranslator::new(map);
+        assert_eq!(translate_all(&mut translator, b"\x1b[21~\x1b[5~"), b"\x0bX\x12");
+    }
+
+    #[test]
+    fn test_origin_of_the_bytes() {
+        // PgDn is ^C on WordStar, it is not a control-c typed
+        let mut translator = KeyTranslator::new(KeyMap::from_preset("wordstar").unwrap());
+        translator.input(3);
+        for &ch in b"\x1b[6~" {
+            translator.input(ch);
+        }
+        assert_eq!(translator.peek(), Some((3, true)));
+        assert_eq!(translator.pop(), Some((3, true)));
+        assert_eq!(translator.pop(), Some((3, false)));
+        assert_eq!(translator.pop(), None);
+    }
+
+    #[test]
+    fn test_no_translation() {
+        let mut translator = KeyTranslator::new(KeyMap::from_preset("none").unwrap());
+        assert_eq!(translate_all(&mut translator, b"\x1b[A"), b"\x1b[A");
+        assert!(!translator.is_waiting());
+    }
+
+    #[test]
+    fn test_unknown_sequences_pass() {
+        let mut translator = KeyTranslator::new(KeyMap::from_preset("adm3a").unwrap());
+        assert_eq!(translate_all(&mut translator, b"\x1b[99~\x1b[Z"), b"\x1b[99~\x1b[Z");
+    }
+
+    #[test]
+    fn test_lone_escape() {
+        let mut translator = KeyTranslator::new(KeyMap::from_preset("adm3a").unwrap());
+        // ESC followed by other key
+        assert_eq!(translate_all(&mut translator, b"\x1bx\x1b\x1b[A"), b"\x1bx\x1b\x0b");
+        // ESC alone is sent after the timeout
+        assert_eq!(translate_all(&mut translator, b"\x1b"), b"");
+        assert!(translator.is_waiting());
+        std::thread::sleep(ESC_TIMEOUT);
+        translator.check_timeout();
+        assert_eq!(translator.pop(), Some((ESC, true)));
+        assert!(!translator.is_waiting());
+    }
+}
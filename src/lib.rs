@@ -1,5 +1,7 @@
 mod bdos;
 mod bios;
+mod call_trace;
+mod charset;
 mod constants;
 mod bdos_console;
 mod bdos_drive;
@@ -8,10 +10,33 @@ mod bdos_file;
 mod console_emulator;
 mod console_test;
 mod cpm_machine;
+mod crash_report;
+mod cpu_trace;
+mod device;
+mod disassembler;
 mod fcb;
+mod host_commands;
+mod interrupts;
+mod json;
+mod keyboard;
+mod loader;
+mod memory_map;
+mod profiler;
+mod recorder;
+mod relocation;
+mod symbols;
+mod telnet;
 mod terminal;
+mod throttle;
 mod terminal_adm3a;
+mod terminal_hazeltine;
+mod terminal_televideo;
+mod terminal_vt52;
+mod rsx;
 mod run;
+mod screen;
+mod script;
+mod serial;
 
 #[cfg(windows)]
 mod console_windows;
@@ -19,6 +44,14 @@ mod console_windows;
 mod console_unix;
 
 pub use run::run as run;
+pub use run::run_with_options as run_with_options;
+pub use run::RunOptions as RunOptions;
+pub use run::Mode as Mode;
+pub use run::parse_command_line as parse_command_line;
+pub use device::Device as Device;
+pub use console_emulator::ConsoleEmulator as ConsoleEmulator;
+pub use call_trace::CallTraceEvent as CallTraceEvent;
+pub use call_trace::CallKind as CallKind;
 #[cfg(windows)]
 pub use console_windows::Console as Console;
 #[cfg(unix)]
@@ -26,3 +59,11 @@ pub use console_unix::Console as Console;
 
 pub use console_test::ConsoleTest as ConsoleTest;
 pub use console_test::Step as Step;
+pub use console_test::Timeout as Timeout;
+pub use script::Script as Script;
+pub use script::run_script_file as run_script_file;
+pub use telnet::serve as serve;
+pub use telnet::TelnetConsole as TelnetConsole;
+pub use screen::Screen as Screen;
+pub use screen::Cell as Cell;
+pub use screen::Attributes as Attributes;
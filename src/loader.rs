@@ -0,0 +1,167 @@
+use iz80::Machine;
+
+use crate::constants::*;
+use crate::cpm_machine::CpmMachine;
+use crate::memory_map::MemoryMap;
+use crate::relocation::parse_prl;
+
+/*
+Program files given on the command line. The format is chosen by the
+extension, or by the contents for the unknown extensions:
+
+    .HEX        Intel HEX, as produced by ASM, MAC or ZMAC. The data is
+                loaded at the addresses of the records. It starts at the
+                address of the start record if there is one, or at the
+                lowest address loaded.
+    .PRL        Page relocatable, moved to 0100h or to the load address.
+    Others      Binary image, like the .COM files or the .CIM core images
+                of ZMAC, loaded at 0100h or at the load address.
+
+The start address can be changed with --start-address. Everything has to be
+loaded on the TPA, from 0100h to the BDOS.
+*/
+
+/// Blocks of data and their addresses
+pub type Segments = Vec<(u16, Vec<u8>)>;
+
+pub struct Program {
+    pub segments: Segments,
+    pub start: u16,
+}
+
+impl Program {
+    pub fn load(&self, machine: &mut CpmMachine) {
+        for (address, data) in self.segments.iter() {
+            for (i, value) in data.iter().enumerate() {
+                machine.poke(address + i as u16, *value);
+            }
+        }
+    }
+}
+
+fn hex_byte(text: &str, index: usize) -> Option<u8> {
+    text.get(index * 2..index * 2 + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok())
+}
+
+/// Parses Intel HEX records. Returns the data blocks and the start address.
+pub fn parse_intel_hex(text: &str) -> Result<(Segments, Option<u16>), String> {
+    let mut segments: Segments = Vec::new();
+    let mut start = None;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim().trim_end_matches('\x1a');
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("Line {}: {}", number + 1, message);
+        let record = line.strip_prefix(':').ok_or_else(|| error("missing the colon"))?;
+        if record.len() % 2 != 0 || record.len() < 10 {
+            return Err(error("invalid record length"));
+        }
+        let bytes: Vec<u8> = (0..record.len() / 2).map(|i| hex_byte(record, i))
+            .collect::<Option<Vec<u8>>>().ok_or_else(|| error("invalid hex digits"))?;
+        let length = bytes[0] as usize;
+        if bytes.len() != length + 5 {
+            return Err(error("the byte count doesn't match the record"));
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(error("wrong checksum"));
+        }
+        let address = (bytes[1] as u16) << 8 | bytes[2] as u16;
+        let data = &bytes[4..4 + length];
+        match bytes[3] {
+            0x00 => { // Data
+                match segments.last_mut() {
+                    Some((last, block)) if *last as usize + block.len() == address as usize => {
+                        block.extend_from_slice(data);
+                    },
+                    _ => segments.push((address, data.to_vec())),
+                }
+            },
+            0x01 => break, // End of file
+            0x02 | 0x04 if data.iter().all(|&byte| byte == 0) => (), // Segment 0
+            0x03 | 0x05 if length == 4 => { // Start address, as CS:IP or linear
+                let high = (data[0] as u32) << 8 | data[1] as u32;
+                let low = (data[2] as u32) << 8 | data[3] as u32;
+                let linear = if bytes[3] == 0x03 { (high << 4) + low } else { high << 16 | low };
+                start = Some(u16::try_from(linear).map_err(|_| error("start address above 64K"))?);
+            },
+            _ => return Err(error("unsupported record type or address above 64K")),
+        }
+    }
+    Ok((segments, start))
+}
+
+fn looks_like_intel_hex(data: &[u8]) -> bool {
+    data.first() == Some(&b':') && data.iter().all(|&byte|
+        byte.is_ascii_hexdigit() || byte == b':' || byte == b'\r' || byte == b'\n' || byte == 0x1a)
+}
+
+/// Loads a program file from its contents
+pub fn load_program(name: &str, data: &[u8], load_address: Option<u16>, start_address: Option<u16>,
+        map: &MemoryMap) -> Result<Program, String> {
+    let lower = name.to_ascii_lowercase();
+    let is_hex = lower.ends_with(".hex")
+        || (!lower.ends_with(".com") && !lower.ends_with(".prl") && looks_like_intel_hex(data));
+    let (segments, start) = if is_hex {
+        if load_address.is_some() {
+            return Err("The HEX files have the load addresses".to_string());
+        }
+        let text = String::from_utf8_lossy(data);
+        let (segments, start) = parse_intel_hex(&text)?;
+        let lowest = segments.iter().map(|(address, _)| *address).min()
+            .ok_or("The HEX file has no data")?;
+        (segments, start.unwrap_or(lowest))
+    } else {
+        let address = load_address.unwrap_or(TPA_BASE_ADDRESS);
+        let code = if lower.ends_with(".prl") {
+            parse_prl(data).and_then(|prl| prl.relocated(address))
+                .map_err(|err| format!("Invalid PRL: {}", err))?
+        } else {
+            data.to_vec()
+        };
+        (vec!((address, code)), address)
+    };
+
+    for (address, block) in segments.iter() {
+        let end = *address as usize + block.len();
+        if *address < TPA_BASE_ADDRESS || end > map.bdos as usize {
+            return Err(format!("{:04x}-{:04x} is out of the TPA, {:04x}-{:04x}",
+                address, end.saturating_sub(1), TPA_BASE_ADDRESS, map.bdos - 1));
+        }
+    }
+    Ok(Program {
+        segments,
+        start: start_address.unwrap_or(start),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intel_hex() {
+        let text = ":03010000C3000138\n:020103003E00BC\n:0201200048494C\n:00000001FF\n";
+        let (segments, start) = parse_intel_hex(text).unwrap();
+        assert_eq!(segments, vec!(
+            (0x0100, vec!(0xc3, 0x00, 0x01, 0x3e, 0x00)),
+            (0x0120, vec!(0x48, 0x49))));
+        assert_eq!(start, None);
+        assert!(parse_intel_hex(":03010000C3000139\n").is_err());
+        assert!(parse_intel_hex("03010000C3000138\n").is_err());
+        let (_, start) = parse_intel_hex(":0400000500000200F5\n").unwrap();
+        assert_eq!(start, Some(0x0200));
+    }
+
+    #[test]
+    fn test_load_program() {
+        let map = MemoryMap::new();
+        let hex = b":020200003E00BE\r\n:00000001FF\r\n";
+        let program = load_program("PROG", hex, None, None, &map).unwrap();
+        assert_eq!(program.start, 0x0200);
+        let program = load_program("PROG.BIN", &[0xc9], Some(0x8000), Some(0x8000), &map).unwrap();
+        assert_eq!(program.segments, vec!((0x8000, vec!(0xc9))));
+        assert!(load_program("PROG.BIN", &[0xc9], Some(0xf800), None, &map).is_err());
+        assert!(load_program("PROG.HEX", hex, Some(0x100), None, &map).is_err());
+    }
+}
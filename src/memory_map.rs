@@ -0,0 +1,118 @@
+use crate::constants::*;
+use crate::symbols::parse_hex;
+
+/*
+Memory map of the system. By default it is a 64K system with the BDOS at
+F800h, the value at 0006h. With --tpa-top it can be a smaller system to
+reproduce the real ones, as some programs check the BDOS address to decide
+how much memory they can use. The option takes the memory size, like 48K,
+or the BDOS address in hex, like B800. The layout is kept, relative to the
+BDOS:
+
+    BDOS - 800h     CCP, 2K
+    BDOS + 80h      Stack for the programs run from the command line
+    BDOS            BDOS trap, the top of the TPA
+    BDOS + 100h     Disk parameter block
+    BDOS + 110h     Allocation vector
+    BDOS + 700h     BIOS jump table, with the traps at BIOS + 80h
+
+With a memory size, the BIOS is on the last page of that memory. The memory
+above is free, the emulator doesn't use it.
+
+The built in CCP is relocated, see relocation.rs.
+*/
+
+const CCP_OFFSET: u16 = 0x0800; // Below the BDOS
+const TPA_STACK_OFFSET: u16 = 0x0080; // On the CCP, 16 bytes for an 8 level stack
+const DPB0_OFFSET: u16 = 0x0100;
+const ALVEC0_OFFSET: u16 = 0x0110;
+const BIOS_OFFSET: u16 = 0x0700;
+
+// The TPA can't be smaller than 8K
+const MIN_BDOS_ADDRESS: u16 = 0x2100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryMap {
+    pub ccp: u16,
+    pub tpa_stack: u16,
+    pub bdos: u16,
+    pub dpb0: u16,
+    pub alvec0: u16,
+    pub bios: u16,
+}
+
+impl MemoryMap {
+    /// The 64K system
+    pub fn new() -> MemoryMap {
+        MemoryMap::with_bdos(BDOS_BASE_ADDRESS)
+    }
+
+    /// The system with the BDOS at the address given, that must be page
+    /// aligned and not above the default.
+    pub fn with_bdos(bdos: u16) -> MemoryMap {
+        let ccp = bdos - CCP_OFFSET;
+        MemoryMap {
+            ccp,
+            tpa_stack: ccp + TPA_STACK_OFFSET,
+            bdos,
+            dpb0: bdos + DPB0_OFFSET,
+            alvec0: bdos + ALVEC0_OFFSET,
+            bios: bdos + BIOS_OFFSET,
+        }
+    }
+
+    /// Parses the --tpa-top option, a memory size in K or the BDOS address
+    /// in hex.
+    pub fn parse(text: &str) -> Option<MemoryMap> {
+        let bdos = match text.strip_suffix(['K', 'k']) {
+            Some(size) => {
+                let size = size.parse::<u32>().ok()?;
+                if size > 64 {
+                    return None;
+                }
+                (size * 1024).checked_sub(0x100 + BIOS_OFFSET as u32)? as u16
+            },
+            None => parse_hex(text)?,
+        };
+        if bdos & 0xff != 0 || !(MIN_BDOS_ADDRESS..=BDOS_BASE_ADDRESS).contains(&bdos) {
+            return None;
+        }
+        Some(MemoryMap::with_bdos(bdos))
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_map() {
+        let map = MemoryMap::new();
+        assert_eq!(map.ccp, 0xf000);
+        assert_eq!(map.tpa_stack, 0xf080);
+        assert_eq!(map.dpb0, 0xf900);
+        assert_eq!(map.alvec0, 0xf910);
+        assert_eq!(map.bios, 0xff00);
+        assert_eq!(MemoryMap::parse("64K"), Some(map));
+    }
+
+    #[test]
+    fn test_parse_sizes() {
+        let map = MemoryMap::parse("48K").unwrap();
+        assert_eq!(map.bios, 0xbf00);
+        assert_eq!(map.bdos, 0xb800);
+        assert_eq!(map.ccp, 0xb000);
+        assert_eq!(MemoryMap::parse("62k").unwrap().bdos, 0xf000);
+        assert_eq!(MemoryMap::parse("B800"), Some(map));
+        assert_eq!(MemoryMap::parse("b850"), None);
+        assert_eq!(MemoryMap::parse("F900"), None);
+        assert_eq!(MemoryMap::parse("65K"), None);
+        assert_eq!(MemoryMap::parse("2K"), None);
+    }
+}
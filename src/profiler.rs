@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::time::{Duration, Instant};
+
+use iz80::*;
+
+use crate::bdos::bdos_command_name;
+use crate::bios::{bios_command, bios_command_name};
+use crate::constants::*;
+use crate::cpm_machine::CpmMachine;
+use crate::json;
+use crate::json::JsonObject;
+use crate::symbols::SymbolTable;
+
+/*
+Execution profiler.
+
+For every instruction executed we count the instructions and the T-states
+spent on its address. CALL and RST instructions are followed with a shadow
+call stack to attribute the costs to routines, the routine being identified
+by its entry address. The stack unwinds when SP goes over the value it had
+after the call, that covers RET, conditional returns and programs discarding
+stack frames. Recursive routines will count the inclusive time more than once.
+
+BDOS and BIOS calls run on the host. For them we count the calls and the
+wall time spent on the host, that includes the time waiting for input.
+
+The report formats are selected by the file name:
+    *.json          JSON document
+    *callgrind*     callgrind format, for KCachegrind and similar tools
+    anything else   text report
+*/
+
+const TOP_ADDRESSES: usize = 20;
+const TOP_ROUTINES: usize = 30;
+const DEFAULT_RANGE_SIZE: u32 = 0x100;
+
+#[derive(Default, Clone, Copy)]
+struct Cost {
+    instructions: u64,
+    cycles: u64,
+}
+
+impl Cost {
+    fn add(&mut self, cycles: u64) {
+        self.instructions += 1;
+        self.cycles += cycles;
+    }
+
+    fn accumulate(&mut self, other: &Cost) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+
+    fn since(&self, start: &Cost) -> Cost {
+        Cost {
+            instructions: self.instructions - start.instructions,
+            cycles: self.cycles - start.cycles,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Routine {
+    calls: u64,
+    self_cost: Cost,
+    inclusive: Cost,
+    lines: HashMap<u16, Cost>,
+    // Key: (call site, target)
+    callees: HashMap<(u16, u16), CallEdge>,
+}
+
+#[derive(Default)]
+struct CallEdge {
+    calls: u64,
+    inclusive: Cost,
+}
+
+struct Frame {
+    routine: u16,
+    call_site: u16,
+    sp_after_call: u16,
+    start: Cost,
+}
+
+#[derive(Default, Clone, Copy)]
+struct SystemCall {
+    count: u64,
+    host_time: Duration,
+}
+
+pub struct Profiler {
+    costs: Vec<Cost>,
+    routines: HashMap<u16, Routine>,
+    call_stack: Vec<Frame>,
+    root: u16,
+    bdos_calls: HashMap<u8, SystemCall>,
+    bios_calls: HashMap<u16, SystemCall>,
+    range_size: u32,
+    total: Cost,
+    start_time: Instant,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            costs: vec![Cost::default(); 0x10000],
+            routines: HashMap::new(),
+            call_stack: Vec::new(),
+            root: TPA_BASE_ADDRESS,
+            bdos_calls: HashMap::new(),
+            bios_calls: HashMap::new(),
+            range_size: DEFAULT_RANGE_SIZE,
+            total: Cost::default(),
+            start_time: Instant::now(),
+        }
+    }
+
+    pub fn set_range_size(&mut self, size: u32) {
+        self.range_size = size.max(1);
+    }
+
+    // The code started at an entry point without a call, like on boot.
+    pub fn start(&mut self, address: u16) {
+        self.call_stack.clear();
+        self.root = address;
+    }
+
+    /// Records the instruction just executed. Call with the PC, SP and cycle
+    /// count sampled before the execution.
+    pub fn instruction(&mut self, pc: u16, sp: u16, start_cycles: u64, cpu: &mut Cpu, machine: &CpmMachine) {
+        let end_cycles = cpu.cycle_count();
+        let cycles = end_cycles - start_cycles;
+        self.total.add(cycles);
+        self.costs[pc as usize].add(cycles);
+
+        let current = self.current_routine();
+        let routine = self.routines.entry(current).or_default();
+        routine.self_cost.add(cycles);
+        routine.lines.entry(pc).or_default().add(cycles);
+
+        let new_pc = cpu.registers().pc();
+        let new_sp = cpu.registers().get16(Reg16::SP);
+
+        // Returns: the stack is above the frames
+        while let Some(frame) = self.call_stack.last() {
+            if new_sp <= frame.sp_after_call {
+                break;
+            }
+            let frame = self.call_stack.pop().unwrap();
+            let inclusive = self.total.since(&frame.start);
+            let caller = self.current_routine();
+            let edge = self.routines.entry(caller).or_default()
+                .callees.entry((frame.call_site, frame.routine)).or_default();
+            edge.inclusive.accumulate(&inclusive);
+            self.routines.entry(frame.routine).or_default().inclusive.accumulate(&inclusive);
+        }
+
+        // Calls: CALL or RST that pushed the return address
+        let return_address = match machine.peek(pc) {
+            0xcd | 0xc4 | 0xcc | 0xd4 | 0xdc | 0xe4 | 0xec | 0xf4 | 0xfc => pc.wrapping_add(3),
+            0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => pc.wrapping_add(1),
+            _ => return,
+        };
+        if new_sp == sp.wrapping_sub(2) && machine.peek16(new_sp) == return_address {
+            let caller = self.current_routine();
+            self.routines.entry(caller).or_default()
+                .callees.entry((pc, new_pc)).or_default().calls += 1;
+            self.routines.entry(new_pc).or_default().calls += 1;
+            self.call_stack.push(Frame {
+                routine: new_pc,
+                call_site: pc,
+                sp_after_call: new_sp,
+                start: self.total,
+            });
+        }
+    }
+
+    /// Records a BDOS or BIOS call executed on the host. PC is the trap
+    /// address and function the value on the C register.
+    pub fn system_call(&mut self, pc: u16, function: u8, host_time: Duration, machine: &CpmMachine) {
+        let call = if pc == machine.memory_map().bdos {
+            self.bdos_calls.entry(function).or_default()
+        } else if let Some(command) = bios_command(machine.memory_map(), pc) {
+            self.bios_calls.entry(command).or_default()
+        } else {
+            return;
+        };
+        call.count += 1;
+        call.host_time += host_time;
+    }
+
+    fn current_routine(&self) -> u16 {
+        match self.call_stack.last() {
+            Some(frame) => frame.routine,
+            None => self.root,
+        }
+    }
+
+    pub fn save(&self, filename: &str, symbols: &SymbolTable) -> io::Result<()> {
+        let content = if filename.ends_with(".json") {
+            self.report_json(symbols)
+        } else if filename.contains("callgrind") {
+            self.report_callgrind(symbols)
+        } else {
+            self.report_text(symbols)
+        };
+        let mut file = File::create(filename)?;
+        file.write_all(content.as_bytes())
+    }
+
+    fn elapsed_seconds(&self) -> f64 {
+        self.start_time.elapsed().as_secs_f64()
+    }
+
+    fn effective_mhz(&self) -> f64 {
+        let seconds = self.elapsed_seconds();
+        if seconds > 0.0 {
+            self.total.cycles as f64 / seconds / 1_000_000.0
+        } else {
+            0.0
+        }
+    }
+
+    fn ranges(&self) -> Vec<(u32, Cost)> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < 0x10000 {
+            let end = (start + self.range_size).min(0x10000);
+            let mut cost = Cost::default();
+            for c in &self.costs[start as usize..end as usize] {
+                cost.accumulate(c);
+            }
+            if cost.instructions > 0 {
+                ranges.push((start, cost));
+            }
+            start = end;
+        }
+        ranges
+    }
+
+    fn hot_addresses(&self) -> Vec<(u16, Cost)> {
+        let mut hot: Vec<(u16, Cost)> = self.costs.iter().enumerate()
+            .filter(|(_, c)| c.instructions > 0)
+            .map(|(address, c)| (address as u16, *c))
+            .collect();
+        hot.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        hot
+    }
+
+    fn sorted_routines(&self) -> Vec<(u16, &Routine)> {
+        let mut routines: Vec<(u16, &Routine)> = self.routines.iter()
+            .map(|(address, r)| (*address, r))
+            .collect();
+        routines.sort_by(|a, b| self.routine_inclusive(b.0, b.1).cycles
+            .cmp(&self.routine_inclusive(a.0, a.1).cycles)
+            .then(a.0.cmp(&b.0)));
+        routines
+    }
+
+    fn routine_inclusive(&self, address: u16, routine: &Routine) -> Cost {
+        if address == self.root && routine.calls == 0 {
+            // The root is never returned from
+            self.total
+        } else {
+            routine.inclusive
+        }
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        if self.total.cycles == 0 {
+            0.0
+        } else {
+            cycles as f64 * 100.0 / self.total.cycles as f64
+        }
+    }
+
+    fn sorted_bdos_calls(&self) -> Vec<(u8, SystemCall)> {
+        let mut calls: Vec<(u8, SystemCall)> = self.bdos_calls.iter().map(|(f, c)| (*f, *c)).collect();
+        calls.sort_by_key(|(f, _)| *f);
+        calls
+    }
+
+    fn sorted_bios_calls(&self) -> Vec<(u16, SystemCall)> {
+        let mut calls: Vec<(u16, SystemCall)> = self.bios_calls.iter().map(|(f, c)| (*f, *c)).collect();
+        calls.sort_by_key(|(f, _)| *f);
+        calls
+    }
+
+    fn report_text(&self, symbols: &SymbolTable) -> String {
+        let mut r = String::new();
+        r += "iz-cpm execution profile\n\n";
+        r += &format!("Instructions: {}\n", self.total.instructions);
+        r += &format!("T-states:     {}\n", self.total.cycles);
+        r += &format!("Wall time:    {:.3}s\n", self.elapsed_seconds());
+        r += &format!("Effective:    {:.2} MHz\n", self.effective_mhz());
+
+        r += "\nRoutines by inclusive T-states:\n";
+        r += "  entry      calls   self instr     self T-states   incl T-states      %  symbol\n";
+        for (address, routine) in self.sorted_routines().iter().take(TOP_ROUTINES) {
+            let inclusive = self.routine_inclusive(*address, routine).cycles;
+            r += &format!("  {:04x} {:>10} {:>12} {:>17} {:>15} {:>6.2}  {}\n",
+                address, routine.calls, routine.self_cost.instructions,
+                routine.self_cost.cycles, inclusive, self.percent(inclusive),
+                symbols.lookup(*address).unwrap_or_default());
+        }
+
+        r += &format!("\nAddress ranges of {} bytes:\n", self.range_size);
+        r += "  range       instructions          T-states      %\n";
+        for (start, cost) in self.ranges() {
+            let end = (start + self.range_size - 1).min(0xffff);
+            r += &format!("  {:04x}-{:04x} {:>14} {:>17} {:>6.2}\n",
+                start, end, cost.instructions, cost.cycles, self.percent(cost.cycles));
+        }
+
+        r += "\nHot addresses:\n";
+        r += "  addr   instructions          T-states      %  symbol\n";
+        for (address, cost) in self.hot_addresses().iter().take(TOP_ADDRESSES) {
+            r += &format!("  {:04x} {:>14} {:>17} {:>6.2}  {}\n",
+                address, cost.instructions, cost.cycles, self.percent(cost.cycles),
+                symbols.lookup(*address).unwrap_or_default());
+        }
+
+        r += "\nBDOS calls:\n";
+        r += "  fn  name                calls    host time\n";
+        for (function, call) in self.sorted_bdos_calls() {
+            r += &format!("  {:>3} {:<14} {:>10} {:>11.6}s\n",
+                function, bdos_command_name(function), call.count, call.host_time.as_secs_f64());
+        }
+
+        r += "\nBIOS calls:\n";
+        r += "  fn  name                calls    host time\n";
+        for (function, call) in self.sorted_bios_calls() {
+            r += &format!("  {:>3} {:<14} {:>10} {:>11.6}s\n",
+                function, bios_command_name(function), call.count, call.host_time.as_secs_f64());
+        }
+        r
+    }
+
+    fn report_json(&self, symbols: &SymbolTable) -> String {
+        let routines: Vec<String> = self.sorted_routines().iter().map(|(address, routine)| {
+            JsonObject::new()
+                .num("address", address)
+                .str("symbol", &symbols.lookup(*address).unwrap_or_default())
+                .num("calls", routine.calls)
+                .num("self_instructions", routine.self_cost.instructions)
+                .num("self_cycles", routine.self_cost.cycles)
+                .num("inclusive_instructions", self.routine_inclusive(*address, routine).instructions)
+                .num("inclusive_cycles", self.routine_inclusive(*address, routine).cycles)
+                .build()
+        }).collect();
+
+        let ranges: Vec<String> = self.ranges().iter().map(|(start, cost)| {
+            JsonObject::new()
+                .num("start", start)
+                .num("end", (start + self.range_size - 1).min(0xffff))
+                .num("instructions", cost.instructions)
+                .num("cycles", cost.cycles)
+                .build()
+        }).collect();
+
+        let addresses: Vec<String> = self.hot_addresses().iter().map(|(address, cost)| {
+            JsonObject::new()
+                .num("address", address)
+                .str("symbol", &symbols.lookup(*address).unwrap_or_default())
+                .num("instructions", cost.instructions)
+                .num("cycles", cost.cycles)
+                .build()
+        }).collect();
+
+        let bdos: Vec<String> = self.sorted_bdos_calls().iter().map(|(function, call)| {
+            JsonObject::new()
+                .num("function", function)
+                .str("name", bdos_command_name(*function))
+                .num("calls", call.count)
+                .num("host_seconds", call.host_time.as_secs_f64())
+                .build()
+        }).collect();
+
+        let bios: Vec<String> = self.sorted_bios_calls().iter().map(|(function, call)| {
+            JsonObject::new()
+                .num("function", function)
+                .str("name", bios_command_name(*function))
+                .num("calls", call.count)
+                .num("host_seconds", call.host_time.as_secs_f64())
+                .build()
+        }).collect();
+
+        JsonObject::new()
+            .num("instructions", self.total.instructions)
+            .num("cycles", self.total.cycles)
+            .num("wall_seconds", self.elapsed_seconds())
+            .num("effective_mhz", self.effective_mhz())
+            .num("range_size", self.range_size)
+            .raw("routines", &json::array(&routines))
+            .raw("ranges", &json::array(&ranges))
+            .raw("addresses", &json::array(&addresses))
+            .raw("bdos", &json::array(&bdos))
+            .raw("bios", &json::array(&bios))
+            .build() + "\n"
+    }
+
+    fn report_callgrind(&self, symbols: &SymbolTable) -> String {
+        // See https://valgrind.org/docs/manual/cl-format.html
+        let mut r = String::new();
+        r += "# callgrind format\n";
+        r += "version: 1\n";
+        r += "creator: iz-cpm\n";
+        r += "positions: instr\n";
+        r += "events: Instructions Cycles\n";
+        r += &format!("summary: {} {}\n\n", self.total.instructions, self.total.cycles);
+
+        let mut routines = self.sorted_routines();
+        routines.sort_by_key(|(address, _)| *address);
+        for (address, routine) in routines {
+            r += &format!("fn={}\n", routine_name(address, symbols));
+            let mut lines: Vec<(&u16, &Cost)> = routine.lines.iter().collect();
+            lines.sort_by_key(|(pc, _)| **pc);
+            for (pc, cost) in lines {
+                r += &format!("0x{:04x} {} {}\n", pc, cost.instructions, cost.cycles);
+            }
+            let mut callees: Vec<(&(u16, u16), &CallEdge)> = routine.callees.iter().collect();
+            callees.sort_by_key(|(key, _)| **key);
+            for ((call_site, target), edge) in callees {
+                r += &format!("cfn={}\n", routine_name(*target, symbols));
+                r += &format!("calls={} 0x{:04x}\n", edge.calls, target);
+                r += &format!("0x{:04x} {} {}\n", call_site, edge.inclusive.instructions, edge.inclusive.cycles);
+            }
+            r += "\n";
+        }
+        r
+    }
+}
+
+fn routine_name(address: u16, symbols: &SymbolTable) -> String {
+    match symbols.lookup(address) {
+        Some(symbol) => format!("{} 0x{:04x}", symbol, address),
+        None => format!("0x{:04x}", address),
+    }
+}
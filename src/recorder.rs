@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::console_emulator::ConsoleEmulator;
+use crate::json;
+use crate::screen::{SCREEN_COLUMNS, SCREEN_ROWS};
+
+/*
+Recording of the console session. The Recorder goes in front of the console
+and copies what is written with put(), after the terminal translation, to:
+
+    A plain text transcript. The escape sequences and the control chars but
+    LF and TAB are removed.
+
+    An asciinema v2 cast file. The first line is a header with the size of
+    the screen, then an event per line with the time in seconds, "o" for the
+    output and the data. As the host terminal converts LF to CR+LF, a bare
+    LF gets a CR on the cast, the CR+LF sent by CP/M is kept. Optionally,
+    the keystrokes received by read() are added as "i" events.
+
+See https://docs.asciinema.org/manual/asciicast/v2/
+*/
+
+enum StripState {
+    Text,
+    Escape,
+    Csi,
+}
+
+pub struct Recorder<'a> {
+    console: &'a mut dyn ConsoleEmulator,
+    transcript: Option<BufWriter<File>>,
+    cast: Option<BufWriter<File>>,
+    cast_input: bool,
+    start: Instant,
+    strip_state: StripState,
+    last_cr: bool,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(console: &'a mut dyn ConsoleEmulator) -> Recorder<'a> {
+        Recorder {
+            console,
+            transcript: None,
+            cast: None,
+            cast_input: false,
+            start: Instant::now(),
+            strip_state: StripState::Text,
+            last_cr: false,
+        }
+    }
+
+    pub fn set_transcript(&mut self, filename: &str) -> std::io::Result<()> {
+        self.transcript = Some(BufWriter::new(File::create(filename)?));
+        Ok(())
+    }
+
+    pub fn set_cast(&mut self, filename: &str, with_input: bool) -> std::io::Result<()> {
+        let mut file = BufWriter::new(File::create(filename)?);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs()).unwrap_or(0);
+        let env = json::JsonObject::new()
+            .str("TERM", "xterm-256color")
+            .build();
+        let header = json::JsonObject::new()
+            .num("version", 2)
+            .num("width", SCREEN_COLUMNS)
+            .num("height", SCREEN_ROWS)
+            .num("timestamp", timestamp)
+            .str("title", "iz-cpm")
+            .raw("env", &env)
+            .build();
+        writeln!(file, "{}", header)?;
+        self.cast = Some(file);
+        self.cast_input = with_input;
+        self.start = Instant::now();
+        Ok(())
+    }
+
+    fn cast_event(&mut self, code: &str, data: &str) {
+        if let Some(cast) = &mut self.cast {
+            let time = self.start.elapsed().as_secs_f64();
+            let event = json::array(&[format!("{:.6}", time), json::escape(code), json::escape(data)]);
+            if writeln!(cast, "{}", event).is_err() {
+                eprintln!("Error writing the cast file");
+                self.cast = None;
+            }
+        }
+    }
+
+    // LF to CR+LF, unless the CR was there, maybe on a previous sequence
+    fn cast_output(&mut self, sequence: &str) -> String {
+        let mut text = String::new();
+        for ch in sequence.chars() {
+            if ch == '\n' && !self.last_cr {
+                text.push('\r');
+            }
+            text.push(ch);
+            self.last_cr = ch == '\r';
+        }
+        text
+    }
+
+    fn strip(&mut self, sequence: &str) -> String {
+        let mut text = String::new();
+        for ch in sequence.chars() {
+            self.strip_state = match self.strip_state {
+                StripState::Text => match ch {
+                    '\x1b' => StripState::Escape,
+                    '\n' | '\t' => {
+                        text.push(ch);
+                        StripState::Text
+                    },
+                    c if (c as u32) < 0x20 || c == '\x7f' => StripState::Text,
+                    c => {
+                        text.push(c);
+                        StripState::Text
+                    },
+                },
+                StripState::Escape => match ch {
+                    '[' => StripState::Csi,
+                    _ => StripState::Text,
+                },
+                StripState::Csi => match ch {
+                    '\x40'..='\x7e' => StripState::Text, // Final byte
+                    _ => StripState::Csi,
+                },
+            }
+        }
+        text
+    }
+}
+
+impl<'a> ConsoleEmulator for Recorder<'a> {
+    fn status(&mut self) -> bool {
+        self.console.status()
+    }
+
+    fn read(&mut self) -> u8 {
+        let ch = self.console.read();
+        if self.cast_input {
+            self.cast_event("i", &(ch as char).to_string());
+        }
+        ch
+    }
+
+    fn put(&mut self, sequence: Option<String>) {
+        if let Some(sequence) = &sequence {
+            if self.transcript.is_some() {
+                let text = self.strip(sequence);
+                if let Some(transcript) = &mut self.transcript {
+                    if transcript.write_all(text.as_bytes()).is_err() {
+                        eprintln!("Error writing the transcript file");
+                        self.transcript = None;
+                    }
+                }
+            }
+            if self.cast.is_some() && !sequence.is_empty() {
+                let data = self.cast_output(sequence);
+                self.cast_event("o", &data);
+            }
+        }
+        self.console.put(sequence);
+    }
+
+    fn terminated(&self) -> bool {
+        self.console.terminated()
+    }
+
+    fn tick(&mut self, instructions: u32) {
+        self.console.tick(instructions);
+    }
+
+    fn message(&mut self, text: &str) {
+        self.console.message(text);
+    }
+
+    fn trace(&mut self, text: &str) {
+        self.console.trace(text);
+    }
+}
+
+impl<'a> Drop for Recorder<'a> {
+    fn drop(&mut self) {
+        if let Some(transcript) = &mut self.transcript {
+            let _ = transcript.flush();
+        }
+        if let Some(cast) = &mut self.cast {
+            let _ = cast.flush();
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::fs;
+
+use regex::Regex;
+
+use crate::symbols::parse_hex;
+
+/*
+Relocation of code to another page. A relocation is the offset of a byte
+that has the high byte of an address; moving the code n pages adds n to it.
+
+The CCP can be given, with --ccp, in these forms:
+
+    ccp.prl             PRL (or SPR) file. A 256 bytes header with the code
+                        size on bytes 1 and 2, then the code assembled at
+                        0100h and a bitmap with a bit for each byte of the
+                        code, the most significant bit first. A set bit is
+                        a byte to relocate.
+    ccp.bin[@origin]    Binary image assembled at the origin given in hex,
+                        F000 if missing. Without relocation information it
+                        is loaded as it is, it has to match the memory map.
+                        The relocations can be given with:
+      --ccp-bitmap file     A bitmap as the one on the PRL files, as used by
+                            MOVCPM. If it is the same file as the image, the
+                            bitmap follows the code.
+      --ccp-pair file@origin    The same code assembled at another origin.
+                            The bytes that differ are the relocations.
+
+The relocations for the built in CCP are found comparing the binary, linked
+at F000h, with the image on the ZMAC listing, where the code is at 0000h, as
+it was assembled relocatable.
+
+Listing lines with code are like:
+      357:    0+10	0000' C30501  		JMP	CPR
+    and the continuation lines with more bytes of the same statement:
+     	              20202020
+*/
+
+const PRL_HEADER_SIZE: usize = 0x100;
+const PRL_ORIGIN: u16 = 0x0100;
+
+/// Code with the offsets to relocate, if known
+pub struct RelocatableImage {
+    pub code: Vec<u8>,
+    pub origin: u16,
+    pub relocations: Option<Vec<usize>>,
+}
+
+impl RelocatableImage {
+    /// The code moved to the address given, on the same offset of a page
+    /// as the origin.
+    pub fn relocated(&self, address: u16) -> Result<Vec<u8>, String> {
+        let mut code = self.code.clone();
+        if address == self.origin {
+            return Ok(code);
+        }
+        if address & 0xff != self.origin & 0xff {
+            return Err(format!("Can't move code from {:04x} to {:04x}, it has to move whole pages", self.origin, address));
+        }
+        match &self.relocations {
+            Some(relocations) => {
+                let pages = ((address >> 8) as u8).wrapping_sub((self.origin >> 8) as u8);
+                relocate(&mut code, relocations, pages);
+                Ok(code)
+            },
+            None => Err(format!("The code is for {:04x} and there is no relocation information to move it to {:04x}", self.origin, address)),
+        }
+    }
+}
+
+/// The relocations marked on a bitmap, the most significant bit first
+pub fn relocations_from_bitmap(bitmap: &[u8], size: usize) -> Result<Vec<usize>, String> {
+    if bitmap.len() < size.div_ceil(8) {
+        return Err(format!("The bitmap has {} bytes, {} are needed for {} bytes of code", bitmap.len(), size.div_ceil(8), size));
+    }
+    Ok((0..size).filter(|&offset| bitmap[offset / 8] & (0x80 >> (offset % 8)) != 0).collect())
+}
+
+/// Parses a PRL or SPR file
+pub fn parse_prl(data: &[u8]) -> Result<RelocatableImage, String> {
+    if data.len() < PRL_HEADER_SIZE {
+        return Err("Missing the PRL header".to_string());
+    }
+    let size = data[1] as usize | (data[2] as usize) << 8;
+    if data.len() < PRL_HEADER_SIZE + size {
+        return Err(format!("The PRL header says {} bytes of code, the file is too short", size));
+    }
+    let code = data[PRL_HEADER_SIZE..PRL_HEADER_SIZE + size].to_vec();
+    let relocations = relocations_from_bitmap(&data[PRL_HEADER_SIZE + size..], size)?;
+    Ok(RelocatableImage {
+        code,
+        origin: PRL_ORIGIN,
+        relocations: Some(relocations),
+    })
+}
+
+// Splits code followed by its bitmap
+fn split_bitmap(data: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    // The total size grows with the code size, only one can match
+    (0..=data.len()).find(|size| size + size.div_ceil(8) == data.len())
+        .map(|size| data.split_at(size))
+        .ok_or_else(|| format!("{} bytes can't be code followed by its bitmap", data.len()))
+}
+
+fn read_file(name: &str) -> Result<Vec<u8>, String> {
+    fs::read(name).map_err(|err| format!("Error loading \"{}\": {}", name, err))
+}
+
+fn split_origin(spec: &str, default_origin: u16) -> Result<(&str, u16), String> {
+    match spec.rsplit_once('@') {
+        None => Ok((spec, default_origin)),
+        Some((name, origin)) => match parse_hex(origin) {
+            Some(origin) => Ok((name, origin)),
+            None => Err(format!("Invalid origin in \"{}\"", spec)),
+        }
+    }
+}
+
+/// Loads the CCP from the --ccp, --ccp-bitmap and --ccp-pair options
+pub fn load_ccp(spec: &str, bitmap: Option<&str>, pair: Option<&str>, default_origin: u16) -> Result<RelocatableImage, String> {
+    let lower = spec.to_ascii_lowercase();
+    if lower.ends_with(".prl") || lower.ends_with(".spr") {
+        if bitmap.is_some() || pair.is_some() {
+            return Err("The PRL files have the relocation information".to_string());
+        }
+        return parse_prl(&read_file(spec)?)
+            .map_err(|err| format!("Invalid PRL \"{}\": {}", spec, err));
+    }
+
+    let (name, origin) = split_origin(spec, default_origin)?;
+    let data = read_file(name)?;
+    let (code, relocations) = match (bitmap, pair) {
+        (None, None) => (data, None),
+        (Some(bitmap_name), None) if bitmap_name == name => {
+            let (code, bitmap) = split_bitmap(&data)?;
+            (code.to_vec(), Some(relocations_from_bitmap(bitmap, code.len())?))
+        },
+        (Some(bitmap_name), None) => {
+            let relocations = relocations_from_bitmap(&read_file(bitmap_name)?, data.len())?;
+            (data, Some(relocations))
+        },
+        (None, Some(pair_spec)) => {
+            let (pair_name, pair_origin) = split_origin(pair_spec, default_origin)?;
+            if pair_origin & 0xff != origin & 0xff || pair_origin == origin {
+                return Err("The pair has to be assembled at an origin whole pages away".to_string());
+            }
+            let pages = ((pair_origin >> 8) as u8).wrapping_sub((origin >> 8) as u8);
+            let relocations = relocations_from_diff(&data, &read_file(pair_name)?, pages)?;
+            (data, Some(relocations))
+        },
+        (Some(_), Some(_)) => return Err("Use a bitmap or a pair, not both".to_string()),
+    };
+    Ok(RelocatableImage {
+        code,
+        origin,
+        relocations,
+    })
+}
+
+/// The memory image of the code on a ZMAC listing
+pub fn listing_image(listing: &str) -> Vec<u8> {
+    let code = Regex::new(r"^\s*\d+:[^\t]*\t([0-9A-F]{4})'? ([0-9A-F]+)").unwrap();
+    let continuation = Regex::new(r"^\t {14}([0-9A-F]+)\s*$").unwrap();
+
+    let mut image = Vec::new();
+    let mut address = 0;
+    for line in listing.lines() {
+        if line.starts_with("Symbol Table:") {
+            break;
+        }
+        let bytes = if let Some(captures) = code.captures(line) {
+            address = usize::from_str_radix(&captures[1], 16).unwrap();
+            captures.get(2).unwrap().as_str()
+        } else if let Some(captures) = continuation.captures(line) {
+            captures.get(1).unwrap().as_str()
+        } else {
+            continue;
+        };
+        for i in (0..bytes.len() - 1).step_by(2) {
+            let value = u8::from_str_radix(&bytes[i..i + 2], 16).unwrap();
+            if address >= image.len() {
+                image.resize(address + 1, 0);
+            }
+            image[address] = value;
+            address += 1;
+        }
+    }
+    image
+}
+
+/// The relocations of two images of the same code, the second one moved
+/// the number of pages given.
+pub fn relocations_from_diff(image: &[u8], moved: &[u8], pages: u8) -> Result<Vec<usize>, String> {
+    if image.len() != moved.len() {
+        return Err(format!("The images have different sizes, {} and {} bytes", image.len(), moved.len()));
+    }
+    let mut relocations = Vec::new();
+    for (offset, (a, b)) in image.iter().zip(moved.iter()).enumerate() {
+        if a != b {
+            if b.wrapping_sub(*a) != pages {
+                return Err(format!("The byte at offset {:04x} is not an address, {:02x} and {:02x}", offset, a, b));
+            }
+            relocations.push(offset);
+        }
+    }
+    Ok(relocations)
+}
+
+/// Moves the code the number of pages given, wrapping
+pub fn relocate(image: &mut [u8], relocations: &[usize], pages: u8) {
+    for &offset in relocations {
+        image[offset] = image[offset].wrapping_add(pages);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing_image() {
+        let listing = "\
+   1:\t\t\t\tTITLE\t'TEST'
+ 357:    0+10\t0000' C30501  \t\tJMP\tCPR
+ 410:     -\t0003'         \tCOMMSG:
+ 425:     -\t0003' 20202020\t\tDB\t'     '
+\t              20
+ 426:     -\t0008' 4100    \t\tDB\t'A',0
+";
+        assert_eq!(listing_image(listing), vec!(0xc3, 0x05, 0x01, 0x20, 0x20, 0x20, 0x20, 0x20, 0x41, 0x00));
+    }
+
+    #[test]
+    fn test_relocations() {
+        let image = [0xc3, 0x05, 0x01, 0x3e, 0x01];
+        let moved = [0xc3, 0x05, 0xf1, 0x3e, 0x01];
+        let relocations = relocations_from_diff(&image, &moved, 0xf0).unwrap();
+        assert_eq!(relocations, vec!(2));
+        let mut code = moved;
+        relocate(&mut code, &relocations, 0xc0);
+        assert_eq!(code, [0xc3, 0x05, 0xb1, 0x3e, 0x01]);
+        assert!(relocations_from_diff(&image, &[0xc3, 0x06, 0xf1, 0x3e, 0x01], 0xf0).is_err());
+    }
+
+    #[test]
+    fn test_prl() {
+        let mut data = vec![0; PRL_HEADER_SIZE];
+        data[1] = 5;
+        data.extend_from_slice(&[0xc3, 0x05, 0x01, 0x3e, 0x01, 0b00100000]);
+        let image = parse_prl(&data).unwrap();
+        assert_eq!(image.origin, 0x0100);
+        assert_eq!(image.relocations, Some(vec!(2)));
+        assert_eq!(image.relocated(0xe400).unwrap(), vec!(0xc3, 0x05, 0xe4, 0x3e, 0x01));
+        assert!(image.relocated(0xe480).is_err());
+        assert!(parse_prl(&data[..PRL_HEADER_SIZE + 5]).is_err());
+    }
+
+    #[test]
+    fn test_split_bitmap() {
+        let data = [0u8; 18];
+        let (code, bitmap) = split_bitmap(&data).unwrap();
+        assert_eq!((code.len(), bitmap.len()), (16, 2));
+        assert!(split_bitmap(&[0u8; 10]).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use iz80::Machine;
+
+use crate::constants::*;
+use crate::cpm_machine::CpmMachine;
+use crate::relocation::{RelocatableImage, parse_prl};
+
+/*
+CP/M 3 COM files with RSXs (Resident System Extensions) attached by GENCOM.
+The file starts with a 256 bytes header, the first byte is a RET so CP/M 2
+just returns to the CCP:
+
+    00h     C9h
+    01h     Length of the program, that follows the header
+    0Fh     Number of RSX records
+    10h     RSX records, 16 bytes each:
+                00h offset of the RSX in the file
+                02h length of the RSX code
+                04h FFh to load only on non banked systems
+                06h name, 8 bytes
+
+The RSXs are PRL images. When the file is loaded at 0100h, directly or by
+the CCP, before running it the RSXs are relocated to the top of the TPA, one
+below the other, and the program is moved down to 0100h. Each RSX starts
+with a prefix:
+
+    00h     Serial number, copied from the BDOS
+    06h     Entry point, JP to the RSX code
+    09h     JP to the next RSX or to the BDOS
+    0Ch     Address of the JP to this RSX, the previous one
+    0Eh     Remove flag, FFh to remove it on the next warm boot
+    0Fh     Non banked flag
+    10h     Name, 8 bytes
+
+The BDOS entry at 0005h jumps to the last RSX loaded, that is the top of the
+TPA, and the RSXs pass the calls they don't handle down the chain. On warm
+boot the RSXs with the remove flag set are unlinked.
+
+While the CCP is used, it is resident below the BDOS and the RSXs are placed
+below it.
+*/
+
+const HEADER_SIZE: u16 = 0x100;
+const HEADER_RSX_COUNT: u16 = 0x0f;
+const HEADER_RSX_RECORDS: u16 = 0x10;
+const RSX_RECORD_SIZE: u16 = 0x10;
+const MAX_RSX: u8 = 15;
+
+const RSX_ENTRY: u16 = 0x06;
+const RSX_NEXT: u16 = 0x09;
+const RSX_PREVIOUS: u16 = 0x0c;
+const RSX_REMOVE: u16 = 0x0e;
+const RSX_NAME: u16 = 0x10;
+const RSX_PREFIX_SIZE: u16 = 0x1b;
+const SERIAL_SIZE: u16 = 6;
+
+/// The program loaded at 0100h starts with a CP/M 3 header
+pub fn has_header(machine: &CpmMachine) -> bool {
+    machine.peek(TPA_BASE_ADDRESS) == 0xc9
+        && machine.peek(TPA_BASE_ADDRESS + HEADER_RSX_COUNT) <= MAX_RSX
+        && machine.peek16(TPA_BASE_ADDRESS + 1) != 0
+}
+
+fn name_at(machine: &CpmMachine, address: u16) -> String {
+    (0..8).map(|i| (machine.peek(address + i) & 0x7f) as char).collect::<String>()
+        .trim_end().to_string()
+}
+
+/// Installs the RSXs of the CP/M 3 COM file loaded at 0100h, not above top,
+/// and moves the program to 0100h. Returns the names and addresses of the
+/// RSXs installed.
+pub fn load_cpm3_com(machine: &mut CpmMachine, top: u16) -> Result<Vec<(String, u16)>, String> {
+    let length = machine.peek16(TPA_BASE_ADDRESS + 1);
+    let count = machine.peek(TPA_BASE_ADDRESS + HEADER_RSX_COUNT) as u16;
+    let program_end = TPA_BASE_ADDRESS + length;
+
+    // Read all the RSXs before writing, they could be over the file
+    let mut images = Vec::new();
+    for i in 0..count {
+        let record = TPA_BASE_ADDRESS + HEADER_RSX_RECORDS + i * RSX_RECORD_SIZE;
+        let offset = machine.peek16(record);
+        let code_length = machine.peek16(record + 2) as usize;
+        let name = name_at(machine, record + 6);
+        if offset == 0 {
+            // Already resident
+            continue;
+        }
+        let size = HEADER_SIZE as usize + code_length + code_length.div_ceil(8);
+        let start = TPA_BASE_ADDRESS as usize + offset as usize;
+        if start + size > 0x10000 {
+            return Err(format!("The RSX {} is out of memory", name));
+        }
+        let data: Vec<u8> = (0..size).map(|i| machine.peek((start + i) as u16)).collect();
+        let image = parse_prl(&data).map_err(|err| format!("Invalid RSX {}: {}", name, err))?;
+        images.push((name, image));
+    }
+
+    let mut installed = Vec::new();
+    for (name, image) in images {
+        let base = install_rsx(machine, &image, top, program_end + HEADER_SIZE)
+            .map_err(|err| format!("Error loading the RSX {}: {}", name, err))?;
+        installed.push((name, base));
+    }
+
+    for i in 0..length {
+        let value = machine.peek(TPA_BASE_ADDRESS + HEADER_SIZE + i);
+        machine.poke(TPA_BASE_ADDRESS + i, value);
+    }
+    Ok(installed)
+}
+
+/// Installs an RSX on the top of the chain, below the current BDOS entry
+/// and not above top nor below bottom. Returns the base address.
+pub fn install_rsx(machine: &mut CpmMachine, image: &RelocatableImage, top: u16, bottom: u16) -> Result<u16, String> {
+    if image.code.len() < RSX_PREFIX_SIZE as usize {
+        return Err("Too short for the RSX prefix".to_string());
+    }
+    let entry = machine.peek16(BDOS_ENTRY_ADDRESS + 1);
+    let limit = top.min(entry.wrapping_sub(SERIAL_SIZE));
+    let base = match limit.checked_sub(image.code.len() as u16) {
+        Some(base) if base & 0xff00 >= bottom => base & 0xff00,
+        _ => return Err("Not enough memory".to_string()),
+    };
+    let code = image.relocated(base)?;
+    for (i, value) in code.iter().enumerate() {
+        machine.poke(base + i as u16, *value);
+    }
+
+    for i in 0..SERIAL_SIZE {
+        let value = machine.peek(entry.wrapping_sub(SERIAL_SIZE) + i);
+        machine.poke(base + i, value);
+    }
+    machine.poke(base + RSX_NEXT, 0xc3 /* jp nnnn */);
+    machine.poke16(base + RSX_NEXT + 1, entry);
+    machine.poke16(base + RSX_PREVIOUS, BDOS_ENTRY_ADDRESS);
+    if entry != machine.memory_map().bdos {
+        machine.poke16(entry - RSX_ENTRY + RSX_PREVIOUS, base + RSX_NEXT);
+    }
+    machine.poke16(BDOS_ENTRY_ADDRESS + 1, base + RSX_ENTRY);
+    Ok(base)
+}
+
+/// The RSXs on the chain from the BDOS entry, the last loaded first. None
+/// if the chain is broken.
+fn rsx_chain(machine: &CpmMachine) -> Option<Vec<u16>> {
+    let bdos = machine.memory_map().bdos;
+    let mut chain = Vec::new();
+    let mut entry = machine.peek16(BDOS_ENTRY_ADDRESS + 1);
+    while entry != bdos {
+        if chain.len() > 16 || entry < TPA_BASE_ADDRESS + RSX_ENTRY || entry > bdos {
+            return None;
+        }
+        let base = entry - RSX_ENTRY;
+        if machine.peek(base + RSX_NEXT) != 0xc3 {
+            return None;
+        }
+        chain.push(base);
+        entry = machine.peek16(base + RSX_NEXT + 1);
+    }
+    Some(chain)
+}
+
+/// Unlinks the RSXs with the remove flag set, on warm boot. Returns the
+/// names of the RSXs that stay.
+pub fn remove_rsxs(machine: &mut CpmMachine) -> Vec<String> {
+    let kept: Vec<u16> = rsx_chain(machine).unwrap_or_default().into_iter()
+        .filter(|&base| machine.peek(base + RSX_REMOVE) == 0)
+        .collect();
+
+    let mut next = machine.memory_map().bdos;
+    for &base in kept.iter().rev() {
+        machine.poke16(base + RSX_NEXT + 1, next);
+        next = base + RSX_ENTRY;
+    }
+    machine.poke16(BDOS_ENTRY_ADDRESS + 1, next);
+    let mut previous = BDOS_ENTRY_ADDRESS;
+    for &base in kept.iter() {
+        machine.poke16(base + RSX_PREVIOUS, previous);
+        previous = base + RSX_NEXT;
+    }
+    kept.iter().map(|&base| name_at(machine, base + RSX_NAME)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Prefix and "ld a,c; ret", the start jumps to the code
+    fn test_rsx(remove: u8) -> RelocatableImage {
+        let mut code = vec![0; RSX_PREFIX_SIZE as usize];
+        code[6] = 0xc3;
+        code[7] = 0x1b;
+        code[8] = 0x01;
+        code[RSX_REMOVE as usize] = remove;
+        code[0x10..0x18].copy_from_slice(b"TEST    ");
+        code.extend_from_slice(&[0x79, 0xc9]);
+        RelocatableImage { code, origin: 0x0100, relocations: Some(vec!(8)) }
+    }
+
+    #[test]
+    fn test_rsx_chain() {
+        let mut machine = CpmMachine::new();
+        let bdos = machine.memory_map().bdos;
+        machine.poke16(BDOS_ENTRY_ADDRESS + 1, bdos);
+
+        let first = install_rsx(&mut machine, &test_rsx(0), 0xf000, 0x1000).unwrap();
+        assert_eq!(first, 0xef00);
+        assert_eq!(machine.peek16(0xef07), 0xef1b);
+        let second = install_rsx(&mut machine, &test_rsx(0xff), 0xf000, 0x1000).unwrap();
+        assert_eq!(second, 0xee00);
+        assert_eq!(machine.peek16(BDOS_ENTRY_ADDRESS + 1), 0xee06);
+        assert_eq!(machine.peek16(0xee0a), 0xef06);
+        assert_eq!(machine.peek16(0xef0a), bdos);
+        assert_eq!(machine.peek16(0xee0c), BDOS_ENTRY_ADDRESS);
+        assert_eq!(machine.peek16(0xef0c), 0xee09);
+
+        assert_eq!(remove_rsxs(&mut machine), vec!("TEST"));
+        assert_eq!(machine.peek16(BDOS_ENTRY_ADDRESS + 1), 0xef06);
+        assert_eq!(machine.peek16(0xef0c), BDOS_ENTRY_ADDRESS);
+        assert!(install_rsx(&mut machine, &test_rsx(0), 0xf000, 0xef00).is_err());
+    }
+}
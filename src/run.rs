@@ -1,8 +1,6 @@
 use std::fs;
-use std::fs::File;
-use std::io::prelude::*;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::{Arg, App};
 use iz80::*;
@@ -10,14 +8,33 @@ use iz80::*;
 
 use crate::bdos::Bdos;
 use crate::bdos::execute_bdos;
-use crate::console_emulator::ConsoleEmulator;
-use crate::bios::Bios;
+use crate::console_emulator::{ConsoleEmulator, TICK_INSTRUCTIONS};
+use crate::bios::{Bios, bios_command, bios_command_name};
+use crate::call_trace::*;
+use crate::charset::{Charset, CharsetLayer};
 use crate::constants::*;
 use crate::cpm_machine::CpmMachine;
+use crate::cpu_trace::CpuTracer;
+use crate::device::{Device, DeviceMapping, parse_device};
+use crate::crash_report::*;
+use crate::disassembler::MAX_INSTRUCTION_SIZE;
 use crate::fcb::*;
+use crate::memory_map::MemoryMap;
+use crate::loader::{Program, load_program};
+use crate::relocation::{RelocatableImage, listing_image, load_ccp, relocations_from_diff};
+use crate::rsx;
+use crate::interrupts::{InterruptController, parse_timer};
+use crate::keyboard::{KeyMap, KeyTranslator};
+use crate::profiler::Profiler;
+use crate::recorder::Recorder;
+use crate::symbols::{SymbolTable, parse_hex};
 use crate::terminal::TerminalEmulator;
+use crate::throttle::Throttle;
 use crate::terminal::Transparent;
 use crate::terminal_adm3a::Adm3aToAnsi;
+use crate::terminal_hazeltine::HazeltineToAnsi;
+use crate::terminal_televideo::TelevideoToAnsi;
+use crate::terminal_vt52::Vt52ToAnsi;
 
 // Welcome message
 const WELCOME: &str =
@@ -25,13 +42,62 @@ const WELCOME: &str =
 CP/M 2.2 Emulation
 Press ctrl-c ctrl-c Y to return to host";
 
+const DEFAULT_MAX_SESSIONS: usize = 16;
+
+// The Windows console always sent the cursor keys as the ADM-3A ones
+#[cfg(windows)]
+const DEFAULT_KEYS: &str = "adm3a";
+#[cfg(not(windows))]
+const DEFAULT_KEYS: &str = "none";
+
 static CCP_BINARY: &[u8] = include_bytes!("../third-party/bin/zcpr.bin");
+static CCP_LISTING: &str = include_str!("../third-party/bin/zcpr.lst");
 
-pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulator) {
-    // Parse arguments
-    let app = App::new(WELCOME)
+// The built in CCP, relocatable with the listing
+fn builtin_ccp() -> Result<RelocatableImage, String> {
+    let mut image = listing_image(CCP_LISTING);
+    image.truncate(CCP_BINARY.len());
+    let relocations = relocations_from_diff(&image, CCP_BINARY, (CCP_BASE_ADDRESS >> 8) as u8)?;
+    Ok(RelocatableImage {
+        code: CCP_BINARY.to_vec(),
+        origin: CCP_BASE_ADDRESS,
+        relocations: Some(relocations),
+    })
+}
+
+/// Options for the library users that can't be given on the command line
+pub struct RunOptions {
+    /// Receives the BDOS and BIOS call trace events, the --call-trace-*
+    /// filters apply.
+    pub call_trace_callback: Option<CallTraceCallback>,
+    /// Devices on the I/O ports, added to the ones given with --device.
+    pub devices: Vec<DeviceMapping>,
+}
+
+impl RunOptions {
+    pub fn new() -> RunOptions {
+        RunOptions {
+            call_trace_callback: None,
+            devices: Vec::new(),
+        }
+    }
+
+    pub fn add_device(&mut self, first_port: u8, last_port: u8, device: Box<dyn Device>) {
+        self.devices.push(DeviceMapping::new(first_port, last_port, device));
+    }
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The command line options
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new(WELCOME)
     .arg(Arg::with_name("CMD")
-        .help("The binary image to run, usually a .COM file")
+        .help("The program to run, usually a .COM file. Also Intel HEX, PRL or any binary image with --load-address")
         .required(false)
         .index(1))
         .arg(Arg::with_name("ARGS")
@@ -46,14 +112,67 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
         .short("T")
         .long("call-trace-all")
         .help("Traces BDOS and BIOS calls"))
+    .arg(Arg::with_name("call_trace_output")
+        .long("call-trace-output")
+        .value_name("file")
+        .help("Writes the BDOS calls as JSON lines to a file, use - for stderr"))
+    .arg(Arg::with_name("call_trace_bios")
+        .long("call-trace-bios")
+        .help("Adds the BIOS calls to the --call-trace-output trace"))
+    .arg(Arg::with_name("call_trace_function")
+        .long("call-trace-function")
+        .value_name("function")
+        .multiple(true)
+        .number_of_values(1)
+        .help("Filters the --call-trace-output trace by BDOS function number or BDOS or BIOS name"))
+    .arg(Arg::with_name("call_trace_drive")
+        .long("call-trace-drive")
+        .value_name("drive")
+        .multiple(true)
+        .number_of_values(1)
+        .help("Filters the --call-trace-output trace by drive letter"))
+    .arg(Arg::with_name("call_trace_file")
+        .long("call-trace-file")
+        .value_name("pattern")
+        .help("Filters the --call-trace-output trace by file name, wildcards allowed"))
     .arg(Arg::with_name("cpu_trace")
         .short("z")
         .long("cpu-trace")
         .help("Traces CPU instructions execution"))
+    .arg(Arg::with_name("symbols")
+        .long("symbols")
+        .value_name("file[@base]")
+        .multiple(true)
+        .number_of_values(1)
+        .help("Symbol file to annotate traces, profiles and reports: ZMAC .lst, M80/L80 or SLR .SYM, or name=addr lines. The hex base is added to relocatable symbols"))
+    .arg(Arg::with_name("profile")
+        .long("profile")
+        .value_name("file")
+        .multiple(true)
+        .number_of_values(1)
+        .help("Profiles the execution and writes a report on exit. Use .json for JSON, a name with 'callgrind' for callgrind format or anything else for text"))
+    .arg(Arg::with_name("profile_range")
+        .long("profile-range")
+        .value_name("bytes")
+        .default_value("256")
+        .help("Size of the address ranges aggregated on the profile report"))
+    .arg(Arg::with_name("history")
+        .long("history")
+        .value_name("size")
+        .help("Number of instructions and BDOS calls kept for the crash report, written to stderr if not 0 [default: 32 with --crash-report, 0 otherwise]"))
+    .arg(Arg::with_name("crash_report")
+        .long("crash-report")
+        .value_name("file")
+        .help("Writes the crash report to a file instead of stderr"))
     .arg(Arg::with_name("slow")
         .short("s")
         .long("slow")
         .help("Runs slower"))
+    .arg(Arg::with_name("mhz")
+        .long("mhz")
+        .value_name("mhz")
+        .conflicts_with("slow")
+        .help("Paces the CPU to the clock rate given, like 2.5 or 4. With 'max' it runs unthrottled and reports the effective speed at exit"))
     .arg(Arg::with_name("cpu")
         .long("cpu")
         .value_name("model")
@@ -62,11 +181,110 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
     .arg(Arg::with_name("terminal")
         .long("terminal")
         .default_value("adm3a")
-        .help("Terminal emulation ADM-3A or ANSI"))
+        .help("Terminal emulation ADM-3A, VT52 (with the H19 extensions), Televideo 910/920/950, Hazeltine 1500 or ANSI"))
+    .arg(Arg::with_name("charset")
+        .long("charset")
+        .value_name("charset")
+        .help("Graphics character set shown with Unicode: kaypro ('84 pixel graphics on video mode) or osborne"))
+    .arg(Arg::with_name("tilde_literal")
+        .long("tilde-literal")
+        .help("For the Hazeltine emulation, ESC is the lead-in and ~ is written as is"))
+    .arg(Arg::with_name("keys")
+        .long("keys")
+        .value_name("preset")
+        .help("Translation of the host cursor, editing and function keys: adm3a, wordstar, turbo or none [default: none, adm3a on Windows]"))
+    .arg(Arg::with_name("key")
+        .long("key")
+        .value_name("key=keystrokes")
+        .multiple(true)
+        .number_of_values(1)
+        .help("Maps a host key to CP/M keystrokes with ^ for control, like f1=^KD or home=^QS. Keys: up, down, left, right, home, end, pgup, pgdn, ins, del, f1 to f12 and ctrl- with left, right, home, end, pgup or pgdn"))
+    .arg(Arg::with_name("transcript")
+        .long("transcript")
+        .value_name("file")
+        .help("Writes the console output as plain text, without the control codes, to the file"))
+    .arg(Arg::with_name("record")
+        .long("record")
+        .value_name("file.cast")
+        .help("Records the session to an asciinema v2 cast file"))
+    .arg(Arg::with_name("record_input")
+        .long("record-input")
+        .requires("record")
+        .help("Adds the keystrokes to the --record cast file"))
+    .arg(Arg::with_name("script")
+        .long("script")
+        .value_name("file")
+        .help("Runs the test script instead of using the console, exits with an error if it fails"))
+    .arg(Arg::with_name("listen")
+        .long("listen")
+        .value_name("address")
+        .help("Accepts telnet connections, like 127.0.0.1:2323, with a session for each one"))
+    .arg(Arg::with_name("session_dir")
+        .long("session-dir")
+        .value_name("path")
+        .requires("listen")
+        .help("Asks the telnet users a name and maps A: to a directory for each one"))
+    .arg(Arg::with_name("max_sessions")
+        .long("max-sessions")
+        .value_name("count")
+        .requires("listen")
+        .help("Maximum number of telnet sessions at the same time, 16 by default"))
+    .arg(Arg::with_name("device")
+        .long("device")
+        .value_name("kind:port[:argument]")
+        .multiple(true)
+        .number_of_values(1)
+        .help("Device on the I/O ports, like value:10-13:ff, printer:12:lpt.txt, clock:20, sio:80:pty or 8251:10:unix=/tmp/serial. Port in hex"))
+    .arg(Arg::with_name("timer")
+        .long("timer")
+        .value_name("hz[:data]")
+        .help("Periodic timer interrupt, like 50 or 60:cf. The data byte in hex is the RST for IM 0 or the vector for IM 2, ff by default"))
+    .arg(Arg::with_name("tpa_top")
+        .long("tpa-top")
+        .value_name("size|address")
+        .help("Smaller system, with the memory size like 48K or the BDOS address in hex like B800. The CCP, BDOS and BIOS are moved below it"))
+    .arg(Arg::with_name("banks")
+        .long("banks")
+        .value_name("count")
+        .help("Banked memory with this number of 64K banks, up to 16, selected with the BIOS SELMEM or the bank port"))
+    .arg(Arg::with_name("common_base")
+        .long("common-base")
+        .value_name("address")
+        .requires("banks")
+        .help("Start of the common area for all the banks, in hex. C000 by default"))
+    .arg(Arg::with_name("bank_port")
+        .long("bank-port")
+        .value_name("port")
+        .requires("banks")
+        .help("I/O port to select the bank, in hex"))
+    .arg(Arg::with_name("load_address")
+        .long("load-address")
+        .value_name("address")
+        .requires("CMD")
+        .help("Address in hex to load a binary program, 0100 by default"))
+    .arg(Arg::with_name("start_address")
+        .long("start-address")
+        .value_name("address")
+        .requires("CMD")
+        .help("Address in hex to start the program, by default the load address or the HEX start record"))
+    .arg(Arg::with_name("no_host_commands")
+        .long("no-host-commands")
+        .help("Disables the host commands EXIT, HOSTDIR, HOSTCD, IMPORT, EXPORT, MOUNT and DATE"))
     .arg(Arg::with_name("ccp")
         .long("ccp")
         .value_name("ccp")
-        .help("Alternative CPP binary, it must be compiled with CCP_BASE=$f000"))
+        .help("Alternative CCP, a PRL file or a binary with the origin like ccp.bin@e400. The binary is relocated with --ccp-bitmap or --ccp-pair, without them it must be for the CCP address, f000 by default"))
+    .arg(Arg::with_name("ccp_bitmap")
+        .long("ccp-bitmap")
+        .value_name("file")
+        .requires("ccp")
+        .conflicts_with("ccp_pair")
+        .help("Relocation bitmap for the --ccp binary, the same file if it follows the code"))
+    .arg(Arg::with_name("ccp_pair")
+        .long("ccp-pair")
+        .value_name("file@origin")
+        .requires("ccp")
+        .help("The --ccp binary assembled at another origin, to find the relocations"))
     .arg(Arg::with_name("disk_a").long("disk-a").value_name("path").short("a").default_value(".").help("directory to map disk A:"))
     .arg(Arg::with_name("disk_b").long("disk-b").value_name("path").short("b").help("directory to map disk B:"))
     .arg(Arg::with_name("disk_c").long("disk-c").value_name("path").short("c").help("directory to map disk C:"))
@@ -82,7 +300,94 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
     .arg(Arg::with_name("disk_m").long("disk-m").value_name("path").help("directory to map disk M:"))
     .arg(Arg::with_name("disk_n").long("disk-n").value_name("path").help("directory to map disk N:"))
     .arg(Arg::with_name("disk_o").long("disk-o").value_name("path").help("directory to map disk O:"))
-    .arg(Arg::with_name("disk_p").long("disk-p").value_name("path").help("directory to map disk P:"));
+    .arg(Arg::with_name("disk_p").long("disk-p").value_name("path").help("directory to map disk P:"))
+}
+
+/// What to do with the command line, see parse_command_line
+pub enum Mode {
+    /// Run on the console with the arguments
+    Console(Vec<String>),
+    /// Run the test script with the other arguments
+    Script(String, Vec<String>),
+    /// Serve telnet sessions with the other arguments
+    Listen {
+        address: String,
+        session_dir: Option<String>,
+        max_sessions: usize,
+        args: Vec<String>,
+    },
+}
+
+// The arguments without an option and its value, as "--name value" or
+// "--name=value"
+fn without_option(args: &[String], name: &str) -> Vec<String> {
+    let prefix = format!("{}=", name);
+    let mut result = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == name {
+            iter.next();
+        } else if !arg.starts_with(&prefix) {
+            result.push(arg.clone());
+        }
+    }
+    result
+}
+
+/// Parses the command line, without the program name, to choose between
+/// the console, a test script or the telnet server. Returns the exit code on
+/// errors or after the help.
+pub fn parse_command_line(args: Vec<String>) -> Result<Mode, i32> {
+    let mut args_complete = vec!("iz-cpm".to_string());
+    args_complete.extend(args.iter().cloned());
+    let matches = match app().get_matches_from_safe(args_complete) {
+        Ok(m) => m,
+        Err(e) => {
+            eprint!("{}", e);
+            return Err(if e.use_stderr() { 1 } else { 0 });
+        }
+    };
+
+    let mut others = args;
+    for option in ["--script", "--listen", "--session-dir", "--max-sessions"] {
+        others = without_option(&others, option);
+    }
+    if let Some(script) = matches.value_of("script") {
+        if matches.is_present("listen") {
+            eprintln!("Use --script or --listen, not both.");
+            return Err(1);
+        }
+        return Ok(Mode::Script(script.to_string(), others));
+    }
+    if let Some(address) = matches.value_of("listen") {
+        let max_sessions = match matches.value_of("max_sessions").map(|text| text.parse::<usize>()) {
+            None => DEFAULT_MAX_SESSIONS,
+            Some(Ok(count)) if count > 0 => count,
+            Some(_) => {
+                eprintln!("Invalid number of sessions.");
+                return Err(1);
+            }
+        };
+        return Ok(Mode::Listen {
+            address: address.to_string(),
+            session_dir: matches.value_of("session_dir").map(|dir| dir.to_string()),
+            max_sessions,
+            args: others,
+        });
+    }
+    Ok(Mode::Console(others))
+}
+
+/// Returns the exit code, given with EXIT or 1 on errors. It was () before
+/// the EXIT host command.
+pub fn run(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulator) -> i32 {
+    run_with_options(command_line, console, RunOptions::new())
+}
+
+/// As run(), with the options for the library users. Returns the exit code.
+pub fn run_with_options(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulator, options: RunOptions) -> i32 {
+    // Parse arguments
+    let app = app();
 
     let matches = match command_line {
         None => app.get_matches_safe(),
@@ -97,9 +402,13 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
         Ok(m) => m,
         Err(e) => {
             eprint!("{}", e);
-            return;
+            return if e.use_stderr() { 1 } else { 0 };
         }
     };
+    if matches.is_present("script") || matches.is_present("listen") {
+        eprintln!("--script and --listen are only for the command line.");
+        return 1;
+    }
         
     let filename = matches.value_of("CMD");
     let params = matches.value_of("ARGS");
@@ -107,29 +416,193 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
     let call_trace = matches.is_present("call_trace") || matches.is_present("call_trace_all");
     let call_trace_all = matches.is_present("call_trace_all");
     let slow = matches.is_present("slow");
+    let mhz = match matches.value_of("mhz") {
+        None => None,
+        Some("max") => Some(None),
+        Some(text) => match text.parse::<f64>() {
+            Ok(mhz) if mhz > 0.0 && mhz.is_finite() => Some(Some(mhz)),
+            _ => {
+                eprintln!("Invalid clock rate \"{}\", use a number of MHz or 'max'.", text);
+                return 1;
+            }
+        }
+    };
     let cpu_model = matches.value_of("cpu");
     let terminal = matches.value_of("terminal");
     let ccp_filename = matches.value_of("ccp");
     let use_tpa = filename.is_none();
+    let symbol_files: Vec<&str> = matches.values_of("symbols").map(|v| v.collect()).unwrap_or_default();
+    let profile_files: Vec<&str> = matches.values_of("profile").map(|v| v.collect()).unwrap_or_default();
+    let profile_range = match matches.value_of("profile_range").map(parse_number) {
+        Some(Some(size)) if size > 0 => size,
+        _ => {
+            eprintln!("Invalid profile range size.");
+            return 1;
+        }
+    };
+
+    let crash_report_file = matches.value_of("crash_report");
+    let history_size = match matches.value_of("history").map(parse_number) {
+        None if crash_report_file.is_some() => DEFAULT_HISTORY_SIZE,
+        None => 0,
+        Some(Some(size)) => size as usize,
+        Some(None) => {
+            eprintln!("Invalid history size.");
+            return 1;
+        }
+    };
+    // The crash report is optional, it's written with a history or a file
+    let crash_report = history_size > 0 || crash_report_file.is_some();
+    let mut program_addresses = [None, None];
+    for (i, option) in ["load_address", "start_address"].iter().enumerate() {
+        if let Some(text) = matches.value_of(option) {
+            match parse_hex(text) {
+                Some(address) => program_addresses[i] = Some(address),
+                None => {
+                    eprintln!("Invalid address \"{}\", use hex like 8000.", text);
+                    return 1;
+                }
+            }
+        }
+    }
+    let [load_address, start_address] = program_addresses;
+
+    // Init the structured call trace
+    let mut call_tracer = None;
+    let call_trace_output = matches.value_of("call_trace_output");
+    if call_trace_output.is_some() || options.call_trace_callback.is_some() {
+        let mut filter = CallTraceFilter::new();
+        for function in matches.values_of("call_trace_function").into_iter().flatten() {
+            if !filter.add_function(function) {
+                eprintln!("Unknown BDOS or BIOS function \"{}\".", function);
+                return 1;
+            }
+        }
+        for drive in matches.values_of("call_trace_drive").into_iter().flatten() {
+            if !filter.add_drive(drive) {
+                eprintln!("Invalid drive \"{}\".", drive);
+                return 1;
+            }
+        }
+        if let Some(pattern) = matches.value_of("call_trace_file") {
+            if !filter.set_file_pattern(pattern) {
+                eprintln!("Invalid file pattern \"{}\".", pattern);
+                return 1;
+            }
+        }
+        let mut tracer = CallTracer::new(filter, matches.is_present("call_trace_bios"));
+        if let Some(name) = call_trace_output {
+            match CallTraceSink::open(name) {
+                Ok(sink) => tracer.add_sink(sink),
+                Err(err) => {
+                    eprintln!("Error creating call trace \"{}\": {}", name, err);
+                    return 1;
+                }
+            }
+        }
+        if let Some(callback) = options.call_trace_callback {
+            tracer.add_sink(CallTraceSink::Callback(callback));
+        }
+        call_tracer = Some(tracer);
+    }
+
+    // Init the session recording
+    let mut recorder;
+    let console: &mut dyn ConsoleEmulator = if matches.is_present("transcript") || matches.is_present("record") {
+        recorder = Recorder::new(console);
+        if let Some(name) = matches.value_of("transcript") {
+            if let Err(err) = recorder.set_transcript(name) {
+                eprintln!("Error creating transcript \"{}\": {}", name, err);
+                return 1;
+            }
+        }
+        if let Some(name) = matches.value_of("record") {
+            if let Err(err) = recorder.set_cast(name, matches.is_present("record_input")) {
+                eprintln!("Error creating cast file \"{}\": {}", name, err);
+                return 1;
+            }
+        }
+        &mut recorder
+    } else {
+        console
+    };
 
     // Init device
     let mut machine = CpmMachine::new();
+    machine.devices().set_trace_unmapped(call_trace);
+    if let Some(text) = matches.value_of("tpa_top") {
+        match MemoryMap::parse(text) {
+            Some(map) => machine.set_memory_map(map),
+            None => {
+                eprintln!("Invalid TPA top \"{}\", use a memory size like 48K or a page aligned BDOS address from 2100 to {:04x}.", text, BDOS_BASE_ADDRESS);
+                return 1;
+            }
+        }
+    }
+    let map = *machine.memory_map();
+    if let Some(text) = matches.value_of("banks") {
+        let banks = match text.parse::<usize>() {
+            Ok(banks) if (1..=16).contains(&banks) => banks,
+            _ => {
+                eprintln!("Invalid number of banks \"{}\", use 1 to 16.", text);
+                return 1;
+            }
+        };
+        let common_base = match matches.value_of("common_base").map(parse_hex) {
+            None => DEFAULT_COMMON_BASE.min(map.ccp),
+            Some(Some(address)) if address & 0xff == 0 && (0x1000..=map.ccp).contains(&address) => address,
+            Some(_) => {
+                eprintln!("Invalid common base, use a page address from 1000 to {:04x}, the CCP must be on the common area.", map.ccp);
+                return 1;
+            }
+        };
+        machine.set_banks(banks, common_base);
+        if let Some(text) = matches.value_of("bank_port") {
+            match parse_hex(text).and_then(|port| u8::try_from(port).ok()) {
+                Some(port) => machine.set_bank_port(port),
+                None => {
+                    eprintln!("Invalid bank port \"{}\".", text);
+                    return 1;
+                }
+            }
+        }
+    }
+    let mut devices = Vec::new();
+    for spec in matches.values_of("device").into_iter().flatten() {
+        match parse_device(spec) {
+            Ok(mapping) => devices.push(mapping),
+            Err(err) => {
+                eprintln!("Invalid device \"{}\": {}", spec, err);
+                return 1;
+            }
+        }
+    }
+    devices.extend(options.devices);
+    for mapping in devices {
+        if let Err(err) = machine.devices().add(mapping) {
+            eprintln!("Error adding device: {}", err);
+            return 1;
+        }
+    }
     let mut cpu = match cpu_model {
         Some("z80") => Cpu::new_z80(),
         Some("8080") => Cpu::new_8080(),
         _ => {
             eprintln!("Invalid CPU model. Choose \"z80\" or \"8080\" as the CPU.");
-            return;
+            return 1;
         }
     };
 
     // Init BIOS
-    let term_emu: Box<dyn TerminalEmulator> = match terminal {
+    let mut term_emu: Box<dyn TerminalEmulator> = match terminal {
         Some("adm3a") => Box::new(Adm3aToAnsi::new()),
+        Some("vt52") | Some("h19") => Box::new(Vt52ToAnsi::new()),
+        Some("televideo") | Some("tvi") => Box::new(TelevideoToAnsi::new()),
+        Some("hazeltine") => Box::new(HazeltineToAnsi::new(matches.is_present("tilde_literal"))),
         Some("ansi") => Box::new(Transparent::new()),
         _ => {
-            eprintln!("Unkown terminal emulation. Choose \"adm3a\" or \"ansi\".");
-            return;
+            eprintln!("Unkown terminal emulation. Choose \"adm3a\", \"vt52\", \"televideo\", \"hazeltine\" or \"ansi\".");
+            return 1;
         }
     };
 /*     let console = match console {
@@ -137,11 +610,36 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
         Some(console) => *console
     };
 */
+    if let Some(name) = matches.value_of("charset") {
+        match Charset::from_name(name) {
+            Some(charset) => term_emu = Box::new(CharsetLayer::new(charset, term_emu)),
+            None => {
+                eprintln!("Unknown character set. Choose \"kaypro\" or \"osborne\".");
+                return 1;
+            }
+        }
+    }
     let mut bios = Bios::new(/*console, */term_emu);
+    let mut key_map = match KeyMap::from_preset(matches.value_of("keys").unwrap_or(DEFAULT_KEYS)) {
+        Some(key_map) => key_map,
+        None => {
+            eprintln!("Unknown keys preset. Choose \"adm3a\", \"wordstar\", \"turbo\" or \"none\".");
+            return 1;
+        }
+    };
+    for definition in matches.values_of("key").map(|v| v.collect()).unwrap_or_else(Vec::new) {
+        if !key_map.parse_definition(definition) {
+            eprintln!("Invalid key definition \"{}\", use key=keystrokes like f1=^KD.", definition);
+            return 1;
+        }
+    }
+    bios.set_keyboard(KeyTranslator::new(key_map));
     bios.setup(&mut machine);
 
     // Init BDOS
     let mut bdos = Bdos::new();
+    bdos.set_call_trace(call_trace || call_trace_all, call_trace && !call_trace_all);
+    bdos.set_host_commands(!matches.is_present("no_host_commands"));
     bdos.reset(&mut machine);
 
     // Assign drives
@@ -150,95 +648,100 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
         if let Some(path) = res {
             if let Err(err) = fs::read_dir(path) {
                 eprintln!("Error with directory \"{}\": {}", path, err);
-                return;
+                return 1;
             }
             bdos.assign_drive(i, path.to_string());
         }
     }
 
+    // Load symbols
+    let mut symbols = SymbolTable::new();
+    bios.add_symbols(&map, &mut symbols);
+    bdos.add_symbols(&map, &mut symbols);
+    if use_tpa && ccp_filename.is_none() {
+        symbols.parse(CCP_LISTING, map.ccp);
+    }
+    for spec in symbol_files {
+        let (name, base) = match spec.rsplit_once('@') {
+            None => (spec, 0),
+            Some((name, base)) => match parse_hex(base) {
+                Some(base) => (name, base),
+                None => {
+                    eprintln!("Invalid base address for symbols \"{}\"", spec);
+                    return 1;
+                }
+            }
+        };
+        if let Err(err) = symbols.load(name, base) {
+            eprintln!("Error loading symbols \"{}\": {}", name, err);
+            return 1;
+        }
+    }
+
     // Load CCP or program
-    let binary: &[u8];
-    let binary_address: u16;
-    let binary_size: usize;
-    let mut buf = [0u8;65536 - (TPA_BASE_ADDRESS as usize)];
+    let program: Program;
     match filename {
         None => {
             // Load TPA
-            binary = match ccp_filename {
-                None => {
-                    binary_size = CCP_BINARY.len();
-                    CCP_BINARY
+            let ccp = match ccp_filename {
+                None => builtin_ccp(),
+                Some(spec) => load_ccp(spec, matches.value_of("ccp_bitmap"),
+                    matches.value_of("ccp_pair"), CCP_BASE_ADDRESS),
+            };
+            let ccp_binary = match ccp.and_then(|ccp| ccp.relocated(map.ccp)) {
+                Ok(ccp_binary) if ccp_binary.len() > (map.bdos - map.ccp) as usize => {
+                    eprintln!("The CCP has {} bytes, it doesn't fit below the BDOS", ccp_binary.len());
+                    return 1;
                 },
-                Some(name) =>{
-                    match File::open(name) {
-                        Err(err) => {
-                            eprintln!("Error opening ccp \"{}\": {}", name, err);
-                            return; //process::exit(1);
-                        },
-                        Ok(mut file) => {
-                            match file.read(&mut buf) {
-                                Err(err) => {
-                                    eprintln!("Error loading ccp \"{}\": {}", name, err);
-                                    return; //process::exit(1);
-                                },
-                                Ok(size) => {
-                                    binary_size = size;
-                                    &buf
-                                }
-                            }
-                        }
-                    }
+                Ok(ccp_binary) => ccp_binary,
+                Err(err) => {
+                    eprintln!("Error loading the CCP: {}", err);
+                    return 1;
                 }
             };
-            binary_address = CCP_BASE_ADDRESS;
-            println!("{}", WELCOME);
+            program = Program {
+                segments: vec!((map.ccp, ccp_binary)),
+                start: map.ccp,
+            };
+            console.message(&format!("{}\n", WELCOME));
         },
         Some(name) => {
             /*
             If the file is found, it is assumed to be a memory image of a
             program that executes in the TPA and thus implicity originates
-            at TBASE in memory.
+            at TBASE in memory. Other formats are detected, see loader.rs.
             */
-            match File::open(name) {
+            let data = match fs::read(name) {
+                Ok(data) => data,
                 Err(err) => {
-                    eprintln!("Error opening \"{}\": {}", name, err);
-                    return; //process::exit(1);
-                },
-                Ok(mut file) => {
-                    match file.read(&mut buf) {
-                        Err(err) => {
-                            eprintln!("Error loading \"{}\": {}", name, err);
-                            return; //process::exit(1);
-                        },
-                        Ok(size) => {
-                            binary = &buf;
-                            binary_address = TPA_BASE_ADDRESS;
-                            binary_size = size;
-                        }
-                    };
+                    eprintln!("Error loading \"{}\": {}", name, err);
+                    return 1;
                 }
-            }
+            };
+            program = match load_program(name, &data, load_address, start_address, &map) {
+                Ok(program) => program,
+                Err(err) => {
+                    eprintln!("Error loading \"{}\": {}", name, err);
+                    return 1;
+                }
+            };
         }
     }
 
     // Load the code in memory
-    for i in 0..binary_size {
-        machine.poke(binary_address + i as u16, binary[i]);
-    }
+    program.load(&mut machine);
 
     if !use_tpa {
         // Upon entry to a transient program, the CCP leaves the stack pointer
         // set to an eight-level stack area with the CCP return address pushed
         // onto the stack, leaving seven levels before overflow occurs.
-        if binary_address == TPA_BASE_ADDRESS {
-            let mut sp = TPA_STACK_ADDRESS;
-            // Push 0x0000
-            machine.poke(sp, (0x0000 >> 8) as u8);
-            sp -= 1;
-            machine.poke(sp, 0x0000_u8);
-            sp -= 1;
-            cpu.registers().set16(Reg16::SP, sp);
-        }
+        let mut sp = map.tpa_stack;
+        // Push 0x0000
+        machine.poke(sp, (0x0000 >> 8) as u8);
+        sp -= 1;
+        machine.poke(sp, 0x0000_u8);
+        sp -= 1;
+        cpu.registers().set16(Reg16::SP, sp);
 
         // Copy parameters As an added convenience, the default buffer area at
         // location BOOT+0080H is initialized to the command line tail typed by
@@ -257,9 +760,8 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
                 }
                 machine.poke(SYSTEM_PARAMS_ADDRESS, (len + 1) as u8);
                 machine.poke(SYSTEM_PARAMS_ADDRESS + 1, b' ');
-                let p_bytes = p.as_bytes();
-                for i in 0..len {
-                    machine.poke(SYSTEM_PARAMS_ADDRESS + (i as u16) + 2, p_bytes[i]);
+                for (i, ch) in p.bytes().take(len).enumerate() {
+                    machine.poke(SYSTEM_PARAMS_ADDRESS + (i as u16) + 2, ch);
                 }
 
                 // As a convenience, the CCP takes the first two parameters that
@@ -282,7 +784,7 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
                 if let Some(arg1) = parts.next() {
                     if let Some(file1) = name_to_8_3(arg1) {
                         if call_trace {
-                            println!("[[FCB1 loaded with {}]]", file1);
+                            console.trace(&format!("[[FCB1 loaded with {}]]\n", file1));
                         }
                         Fcb::new(FCB1_ADDRESS).set_name_direct(&mut machine, file1);
                     }
@@ -290,7 +792,7 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
                 if let Some(arg2) = parts.next() {
                     if let Some(file2) = name_to_8_3(arg2) {
                         if call_trace {
-                            println!("[[FCB2 loaded with {}]]", file2);
+                            console.trace(&format!("[[FCB2 loaded with {}]]\n", file2));
                         }
                         Fcb::new(FCB2_ADDRESS).set_name_direct(&mut machine, file2);
                     }
@@ -300,64 +802,218 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
     }
 
     // Run the emulation
-    cpu.registers().set_pc(binary_address);
-    cpu.set_trace(cpu_trace);
+    cpu.registers().set_pc(program.start);
+    let mut cpu_tracer = if cpu_trace {
+        Some(CpuTracer::new(cpu_model == Some("8080")))
+    } else {
+        None
+    };
+    let mut profiler = if profile_files.is_empty() {
+        None
+    } else {
+        let mut p = Profiler::new();
+        p.set_range_size(profile_range);
+        p.start(program.start);
+        Some(p)
+    };
+    let mut history = History::new(history_size, cpu_model == Some("8080"));
+    let mut crash_reason = None;
+    let mut exit_code = 0;
+    let mut throttle = mhz.map(|mhz| Throttle::new(mhz, cpu.cycle_count()));
     let mut n = 0;
+    let mut instructions = 0;
+    let mut cpm3_loaded = false;
+    // Init interrupts, only if there is a source
+    let mut interrupts = None;
+    if let Some(text) = matches.value_of("timer") {
+        let mut controller = InterruptController::new(cpu_model != Some("8080"));
+        match parse_timer(text) {
+            Some((hz, data)) => controller.set_timer(hz, data),
+            None => {
+                eprintln!("Invalid timer \"{}\", use a frequency in Hz and optionally the data byte, like 50:ff.", text);
+                return 1;
+            }
+        }
+        interrupts = Some(controller);
+    } else if !machine.devices().is_empty() {
+        interrupts = Some(InterruptController::new(cpu_model != Some("8080")));
+    }
+
     loop {
+        instructions += 1;
+        if instructions == TICK_INSTRUCTIONS {
+            console.tick(instructions);
+            instructions = 0;
+        }
+
+        if let Some(controller) = interrupts.as_mut() {
+            let pc = cpu.registers().pc();
+            let on_trap = pc == map.bdos || bios_command(&map, pc).is_some();
+            controller.before_instruction(cpu.registers(), &mut machine, on_trap);
+            if controller.is_halted() {
+                machine.devices().tick(cpu.cycle_count());
+                if bios.poll_halted(console) == ExecutionResult::StopConfirm && confirm_stop(&mut bios, console) {
+                    crash_reason = Some(format!("Aborted by the user at {}", symbols.describe(cpu.registers().pc())));
+                    break;
+                }
+                if console.terminated() {
+                    break;
+                }
+                continue;
+            }
+        }
+
+        if !cpm3_loaded && cpu.registers().pc() == TPA_BASE_ADDRESS && rsx::has_header(&machine) {
+            // CP/M 3 COM file, with RSXs. They go below the CCP if used
+            cpm3_loaded = true;
+            let top = if use_tpa { map.ccp } else { map.bdos };
+            match rsx::load_cpm3_com(&mut machine, top) {
+                Ok(installed) => {
+                    if call_trace || call_trace_all {
+                        for (name, base) in installed {
+                            console.trace(&format!("[[RSX {} loaded at {:04x}]]\n", name, base));
+                        }
+                    }
+                },
+                Err(err) => {
+                    console.message(&format!("{}\n", err));
+                    cpu.registers().set_pc(0x0000); // Warm boot
+                }
+            }
+        }
+
+        let pc = cpu.registers().pc();
+        let profiler_start = match profiler {
+            Some(_) => (cpu.registers().get16(Reg16::SP), cpu.cycle_count()),
+            None => (0, 0),
+        };
+        let mut bytes = [0; MAX_INSTRUCTION_SIZE];
+        if cpu_tracer.is_some() || history.is_enabled() {
+            for (i, value) in bytes.iter_mut().enumerate() {
+                *value = machine.peek(pc.wrapping_add(i as u16));
+            }
+        }
+
         cpu.execute_instruction(&mut machine);
+        machine.devices().tick(cpu.cycle_count());
+        if call_trace {
+            for access in machine.devices().take_unmapped() {
+                console.trace(&format!("{}\n", access));
+            }
+        }
+
+        if let Some(tracer) = cpu_tracer.as_mut() {
+            console.trace(&format!("{}\n", tracer.trace(pc, &bytes, &cpu, &symbols)));
+        }
+        if let Some(profiler) = profiler.as_mut() {
+            profiler.instruction(pc, profiler_start.0, profiler_start.1, &mut cpu, &machine);
+        }
+        history.instruction(pc, &bytes, cpu.registers());
 
         if cpu.is_halted() {
-            println!("HALT instruction");
+            // Not repeated if the crash report goes to the console
+            if !crash_report || crash_report_file.is_some() {
+                console.message(&format!("HALT instruction at {}\n", symbols.describe(pc)));
+            }
+            crash_reason = Some(format!("HALT instruction at {}", symbols.describe(pc)));
             break;
         }
 
-        let mut er = bios.execute(console, cpu.registers(), call_trace_all);
+        let pc = cpu.registers().pc();
+        let is_bdos = pc == map.bdos;
+        // The time on the host is measured only for the profiler and the
+        // throttle, and only on the BDOS and BIOS calls
+        let host_start = if (profiler.is_some() || throttle.is_some())
+                && (is_bdos || bios_command(&map, pc).is_some()) {
+            Some((Instant::now(), cpu.registers().get8(Reg8::C)))
+        } else {
+            None
+        };
+        if let Some(tracer) = call_tracer.as_mut() {
+            tracer.before(&machine, cpu.registers(), &bdos, &symbols);
+        }
+        if is_bdos {
+            history.bdos_call(cpu.registers(), &machine);
+        }
+        let mut er = bios.execute(console, &mut machine, cpu.registers(), call_trace_all);
         if er == ExecutionResult::Continue {
             er = execute_bdos(&mut bdos, &mut bios, console, &mut machine,
-                cpu.registers(), call_trace || call_trace_all, call_trace && !call_trace_all);
+                cpu.registers(), &symbols);
+        }
+        if let Some(tracer) = call_tracer.as_mut() {
+            tracer.after(cpu.registers());
+        }
+        if is_bdos && er == ExecutionResult::Continue {
+            history.bdos_result(cpu.registers());
+        }
+        if let Some((host_start, function)) = host_start {
+            let host_time = host_start.elapsed();
+            if let Some(profiler) = profiler.as_mut() {
+                profiler.system_call(pc, function, host_time, &machine);
+            }
+            if let Some(throttle) = throttle.as_mut() {
+                throttle.host_time(host_time);
+            }
         }
 
         match er {
             ExecutionResult::Continue => (),
             ExecutionResult::Stop => {
+                crash_reason = Some(if is_bdos {
+                    format!("BDOS function {} not implemented", cpu.registers().get8(Reg8::C))
+                } else {
+                    format!("BIOS function {} not implemented at {}",
+                        bios_command_name(bios_command(&map, pc).unwrap_or(0xffff)), symbols.describe(pc))
+                });
+                break;
+            },
+            ExecutionResult::Exit(code) => {
+                exit_code = code as i32;
                 break;
             },
             ExecutionResult::StopConfirm => {
-                eprintln!();
-                eprintln!("Press Y to exit iz-cpm. Any other key to continue.");
-                let ch = bios.read(console) as char;
-                if ch == 'Y' || ch == 'y' {
+                if confirm_stop(&mut bios, console) {
+                    crash_reason = Some(format!("Aborted by the user at {}", symbols.describe(pc)));
                     break;
                 }
             },
             ExecutionResult::WarmBoot => {
                 if call_trace || call_trace_all {
-                    print!("[[Warm boot]]");
+                    console.trace("[[Warm boot]]");
                 }
+                cpm3_loaded = false;
                 if use_tpa {
-                    bdos.warm_reset(&mut machine);
-                    for i in 0..binary_size {
-                        machine.poke(binary_address + i as u16, binary[i]);
+                    let resident = bdos.warm_reset(&mut machine);
+                    if call_trace || call_trace_all {
+                        for name in resident {
+                            console.trace(&format!("[[RSX {} resident]]\n", name));
+                        }
                     }
-                    cpu.registers().set_pc(binary_address);
+                    program.load(&mut machine);
+                    cpu.registers().set_pc(program.start);
                     let user_drive = machine.peek(CCP_USER_DRIVE_ADDRESS);
                     cpu.registers().set8(Reg8::C, user_drive);
+                    if let Some(profiler) = profiler.as_mut() {
+                        profiler.start(program.start);
+                    }
                 } else {
                     break;
                 }
             },
             ExecutionResult::ColdBoot => {
                 if call_trace || call_trace_all {
-                    print!("[[Cold boot]]");
+                    console.trace("[[Cold boot]]");
                 }
+                cpm3_loaded = false;
                 if use_tpa {
                     bdos.reset(&mut machine);
-                    for i in 0..binary_size {
-                        machine.poke(binary_address + i as u16, binary[i]);
-                    }
-                    cpu.registers().set_pc(binary_address);
+                    program.load(&mut machine);
+                    cpu.registers().set_pc(program.start);
                     let user_drive = machine.peek(CCP_USER_DRIVE_ADDRESS);
                     cpu.registers().set8(Reg8::C, user_drive);
+                    if let Some(profiler) = profiler.as_mut() {
+                        profiler.start(program.start);
+                    }
                     bdos.reset(&mut machine); // Reset Bdos
                 } else {
                     break;
@@ -369,6 +1025,10 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
             break;
         }
 
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.pace(cpu.cycle_count());
+        }
+
         if slow {
             n += 1;
             if n > 20 {
@@ -377,4 +1037,50 @@ pub fn run<'a>(command_line: Option<Vec<&str>>, console: &mut dyn ConsoleEmulato
             }
         }
     }
-}
\ No newline at end of file
+
+    if let Some(throttle) = throttle {
+        if !throttle.is_throttled() {
+            eprintln!("{}", throttle.report(cpu.cycle_count()));
+        }
+    }
+
+    if let Some(reason) = crash_reason.filter(|_| crash_report) {
+        let report = history.report(&reason, &cpu, &machine, &symbols);
+        if let Err(err) = write_report(&report, crash_report_file) {
+            eprintln!("Error writing crash report: {}", err);
+        }
+    }
+
+    if let Some(mut tracer) = call_tracer {
+        tracer.flush();
+    }
+
+    if let Some(profiler) = profiler {
+        for file in profile_files {
+            if let Err(err) = profiler.save(file, &symbols) {
+                eprintln!("Error writing profile \"{}\": {}", file, err);
+            }
+        }
+    }
+
+    exit_code
+}
+
+// Asks the user after the two control-c
+fn confirm_stop(bios: &mut Bios, console: &mut dyn ConsoleEmulator) -> bool {
+    console.message("\nPress Y to exit iz-cpm. Any other key to continue.\n");
+    let ch = bios.read(console) as char;
+    ch == 'Y' || ch == 'y'
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    // Decimal, 0x1234 or 1234h
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        lower.parse::<u32>().ok()
+    }
+}
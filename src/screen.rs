@@ -0,0 +1,402 @@
+/*
+In-memory model of the screen, fed with the ANSI output of the terminal
+emulators. It is used by the tests to check the content of the screen of
+full screen programs that position the cursor and redraw.
+
+Rows and columns are 1-based, as on the ANSI cursor position sequences.
+
+Supported:
+    Printable chars, with deferred wrap on the last column and scroll
+    CR, LF, BS, TAB. BEL and other control chars are ignored
+    ESC7, ESC8: save and restore the cursor
+    ESCM: reverse line feed
+    ESCc: reset
+    ESC[n;mH, ESC[n;mf: cursor position
+    ESC[nA, ESC[nB, ESC[nC, ESC[nD: cursor movement
+    ESC[nJ, ESC[nK: erase display and line
+    ESC[nL, ESC[nM: insert and delete lines
+    ESC[n@, ESC[nP: insert and delete chars
+    ESC[4h, ESC[4l: insert mode
+    ESC[?25h, ESC[?25l: cursor visible
+    ESC[s, ESC[u: save and restore the cursor
+    ESC[...m: attributes bold, dim, underline, blink, reverse and invisible
+*/
+
+pub const SCREEN_ROWS: usize = 24;
+pub const SCREEN_COLUMNS: usize = 80;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Attributes {
+    pub bold: bool,
+    pub dim: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub reverse: bool,
+    pub invisible: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cell {
+    pub ch: char,
+    pub attributes: Attributes,
+}
+
+const BLANK: Cell = Cell {
+    ch: ' ',
+    attributes: Attributes {
+        bold: false,
+        dim: false,
+        underline: false,
+        blink: false,
+        reverse: false,
+        invisible: false,
+    },
+};
+
+enum ParserState {
+    Normal,
+    Escape,
+    Csi(String),
+}
+
+pub struct Screen {
+    cells: Vec<[Cell; SCREEN_COLUMNS]>,
+    row: usize, // 0 based
+    column: usize, // 0 based, SCREEN_COLUMNS when a wrap is pending
+    saved_cursor: (usize, usize),
+    attributes: Attributes,
+    insert_mode: bool,
+    cursor_visible: bool,
+    state: ParserState,
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen {
+            cells: vec![[BLANK; SCREEN_COLUMNS]; SCREEN_ROWS],
+            row: 0,
+            column: 0,
+            saved_cursor: (0, 0),
+            attributes: Attributes::default(),
+            insert_mode: false,
+            cursor_visible: true,
+            state: ParserState::Normal,
+        }
+    }
+
+    pub fn feed(&mut self, text: &str) {
+        for ch in text.chars() {
+            self.feed_char(ch);
+        }
+    }
+
+    /// Text of the row, with the trailing spaces removed.
+    pub fn row_text(&self, row: usize) -> String {
+        match self.cells.get(row.wrapping_sub(1)) {
+            Some(cells) => cells.iter().map(|cell| cell.ch).collect::<String>().trim_end().to_string(),
+            None => String::new(),
+        }
+    }
+
+    pub fn cell(&self, row: usize, column: usize) -> Option<Cell> {
+        self.cells.get(row.wrapping_sub(1))
+            .and_then(|cells| cells.get(column.wrapping_sub(1)))
+            .copied()
+    }
+
+    /// Cursor position as (row, column)
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row + 1, self.column.min(SCREEN_COLUMNS - 1) + 1)
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    /// All the rows with the trailing spaces removed, and without the
+    /// trailing empty rows.
+    pub fn snapshot(&self) -> String {
+        let mut rows: Vec<String> = (1..=SCREEN_ROWS).map(|row| self.row_text(row)).collect();
+        while rows.last().map(|row| row.is_empty()).unwrap_or(false) {
+            rows.pop();
+        }
+        rows.join("\n")
+    }
+
+    /// Screen with a frame and the cursor position, for failure messages.
+    pub fn dump(&self) -> String {
+        let (row, column) = self.cursor();
+        let mut text = format!("Screen, cursor at row {} column {}:\n", row, column);
+        let border = format!("+{}+\n", "-".repeat(SCREEN_COLUMNS));
+        text += &border;
+        for cells in self.cells.iter() {
+            let line: String = cells.iter().map(|cell| cell.ch).collect();
+            text += &format!("|{}|\n", line);
+        }
+        text += &border;
+        text
+    }
+
+    fn feed_char(&mut self, ch: char) {
+        match std::mem::replace(&mut self.state, ParserState::Normal) {
+            ParserState::Normal => match ch {
+                '\x1b' => self.state = ParserState::Escape,
+                '\r' => self.column = 0,
+                '\n' => self.line_feed(),
+                '\x08' => self.column = self.column.min(SCREEN_COLUMNS - 1).saturating_sub(1),
+                '\t' => self.column = ((self.column / 8 + 1) * 8).min(SCREEN_COLUMNS - 1),
+                c if (c as u32) < 0x20 || c == '\x7f' => (), // Ignored
+                c => self.put_char(c),
+            },
+            ParserState::Escape => match ch {
+                '[' => self.state = ParserState::Csi(String::new()),
+                '7' => self.saved_cursor = (self.row, self.column),
+                '8' => (self.row, self.column) = self.saved_cursor,
+                'M' => self.reverse_line_feed(),
+                'c' => *self = Screen::new(),
+                _ => (), // Unknown, ignored
+            },
+            ParserState::Csi(mut params) => {
+                if ch.is_ascii_digit() || ch == ';' || ch == '?' {
+                    params.push(ch);
+                    self.state = ParserState::Csi(params);
+                } else {
+                    self.csi(&params, ch);
+                }
+            },
+        }
+    }
+
+    fn csi(&mut self, params: &str, command: char) {
+        let private = params.starts_with('?');
+        let values: Vec<usize> = params.trim_start_matches('?').split(';')
+            .map(|value| value.parse().unwrap_or(0)).collect();
+        let first = values[0];
+        let count = first.max(1);
+        match command {
+            'H' | 'f' => {
+                let row = first.max(1);
+                let column = values.get(1).copied().unwrap_or(1).max(1);
+                self.row = row.min(SCREEN_ROWS) - 1;
+                self.column = column.min(SCREEN_COLUMNS) - 1;
+            },
+            'A' => self.row = self.row.saturating_sub(count),
+            'B' => self.row = (self.row + count).min(SCREEN_ROWS - 1),
+            'C' => self.column = (self.column + count).min(SCREEN_COLUMNS - 1),
+            'D' => self.column = self.column.min(SCREEN_COLUMNS - 1).saturating_sub(count),
+            'J' => self.erase_display(first),
+            'K' => self.erase_line(first),
+            'L' => {
+                for _ in 0..count {
+                    self.cells.insert(self.row, [BLANK; SCREEN_COLUMNS]);
+                    self.cells.truncate(SCREEN_ROWS);
+                }
+            },
+            'M' => {
+                for _ in 0..count {
+                    self.cells.remove(self.row);
+                    self.cells.push([BLANK; SCREEN_COLUMNS]);
+                }
+            },
+            '@' => {
+                let column = self.column.min(SCREEN_COLUMNS - 1);
+                let cells = &mut self.cells[self.row][column..];
+                let count = count.min(cells.len());
+                cells.rotate_right(count);
+                cells[..count].fill(BLANK);
+            },
+            'P' => {
+                let column = self.column.min(SCREEN_COLUMNS - 1);
+                let cells = &mut self.cells[self.row][column..];
+                let count = count.min(cells.len());
+                cells.rotate_left(count);
+                let len = cells.len();
+                cells[len - count..].fill(BLANK);
+            },
+            'h' | 'l' => {
+                let set = command == 'h';
+                match (private, first) {
+                    (false, 4) => self.insert_mode = set,
+                    (true, 25) => self.cursor_visible = set,
+                    _ => (),
+                }
+            },
+            's' => self.saved_cursor = (self.row, self.column),
+            'u' => (self.row, self.column) = self.saved_cursor,
+            'm' => {
+                for value in values {
+                    self.set_attribute(value);
+                }
+            },
+            _ => (), // Unknown, ignored
+        }
+    }
+
+    fn set_attribute(&mut self, value: usize) {
+        let a = &mut self.attributes;
+        match value {
+            0 => *a = Attributes::default(),
+            1 => a.bold = true,
+            2 => a.dim = true,
+            4 => a.underline = true,
+            5 => a.blink = true,
+            7 => a.reverse = true,
+            8 => a.invisible = true,
+            22 => {
+                a.bold = false;
+                a.dim = false;
+            },
+            24 => a.underline = false,
+            25 => a.blink = false,
+            27 => a.reverse = false,
+            28 => a.invisible = false,
+            _ => (), // Colors and others are ignored
+        }
+    }
+
+    fn put_char(&mut self, ch: char) {
+        if self.column >= SCREEN_COLUMNS {
+            // Deferred wrap
+            self.column = 0;
+            self.line_feed();
+        }
+        let cell = Cell {
+            ch,
+            attributes: self.attributes,
+        };
+        let row = &mut self.cells[self.row];
+        if self.insert_mode {
+            row[self.column..].rotate_right(1);
+        }
+        row[self.column] = cell;
+        self.column += 1;
+    }
+
+    fn line_feed(&mut self) {
+        if self.row + 1 < SCREEN_ROWS {
+            self.row += 1;
+        } else {
+            self.cells.remove(0);
+            self.cells.push([BLANK; SCREEN_COLUMNS]);
+        }
+    }
+
+    fn reverse_line_feed(&mut self) {
+        if self.row > 0 {
+            self.row -= 1;
+        } else {
+            self.cells.insert(0, [BLANK; SCREEN_COLUMNS]);
+            self.cells.truncate(SCREEN_ROWS);
+        }
+    }
+
+    fn erase_display(&mut self, mode: usize) {
+        match mode {
+            0 => {
+                self.erase_line(0);
+                for row in self.row + 1..SCREEN_ROWS {
+                    self.cells[row] = [BLANK; SCREEN_COLUMNS];
+                }
+            },
+            1 => {
+                self.erase_line(1);
+                for row in 0..self.row {
+                    self.cells[row] = [BLANK; SCREEN_COLUMNS];
+                }
+            },
+            2 => {
+                for row in 0..SCREEN_ROWS {
+                    self.cells[row] = [BLANK; SCREEN_COLUMNS];
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn erase_line(&mut self, mode: usize) {
+        let column = self.column.min(SCREEN_COLUMNS - 1);
+        let cells = &mut self.cells[self.row];
+        match mode {
+            0 => cells[column..].fill(BLANK),
+            1 => cells[..=column].fill(BLANK),
+            2 => cells.fill(BLANK),
+            _ => (),
+        }
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_and_cursor() {
+        let mut screen = Screen::new();
+        screen.feed("Hello\r\nWorld");
+        assert_eq!(screen.row_text(1), "Hello");
+        assert_eq!(screen.row_text(2), "World");
+        assert_eq!(screen.cursor(), (2, 6));
+        assert_eq!(screen.snapshot(), "Hello\nWorld");
+    }
+
+    #[test]
+    fn test_cursor_position_and_erase() {
+        let mut screen = Screen::new();
+        screen.feed("xxxxxxxx\x1b[2J\x1b[5;10HHELLO\x1b[5;12H\x1b[K");
+        assert_eq!(screen.row_text(1), "");
+        assert_eq!(screen.row_text(5), "         HE");
+        assert_eq!(screen.cursor(), (5, 12));
+    }
+
+    #[test]
+    fn test_wrap_and_scroll() {
+        let mut screen = Screen::new();
+        screen.feed(&"a".repeat(SCREEN_COLUMNS));
+        assert_eq!(screen.cursor(), (1, SCREEN_COLUMNS));
+        screen.feed("b");
+        assert_eq!(screen.row_text(2), "b");
+        screen.feed("\x1b[24;1Hlast\n");
+        assert_eq!(screen.row_text(1), "b");
+        assert_eq!(screen.row_text(23), "last");
+        assert_eq!(screen.row_text(24), "");
+    }
+
+    #[test]
+    fn test_insert_and_delete() {
+        let mut screen = Screen::new();
+        screen.feed("one\r\ntwo\r\nthree\x1b[2;1H\x1b[L");
+        assert_eq!(screen.snapshot(), "one\n\ntwo\nthree");
+        screen.feed("\x1b[M\x1b[M");
+        assert_eq!(screen.snapshot(), "one\nthree");
+        screen.feed("\x1b[1;2H\x1b[P");
+        assert_eq!(screen.row_text(1), "oe");
+        screen.feed("\x1b[@");
+        assert_eq!(screen.row_text(1), "o e");
+        screen.feed("\x1b[4hn\x1b[4l");
+        assert_eq!(screen.row_text(1), "on e");
+    }
+
+    #[test]
+    fn test_attributes() {
+        let mut screen = Screen::new();
+        screen.feed("a\x1b[7mb\x1b[0;2mc\x1b[22md");
+        assert!(!screen.cell(1, 1).unwrap().attributes.reverse);
+        assert!(screen.cell(1, 2).unwrap().attributes.reverse);
+        assert_eq!(screen.cell(1, 3).unwrap().attributes, Attributes {dim: true, ..Default::default()});
+        assert_eq!(screen.cell(1, 4).unwrap().attributes, Attributes::default());
+    }
+
+    #[test]
+    fn test_save_cursor_and_reverse_line_feed() {
+        let mut screen = Screen::new();
+        screen.feed("\x1b[3;4H\x1b7\x1b[H\x1bMtop\x1b8x");
+        assert_eq!(screen.row_text(1), "top");
+        assert_eq!(screen.row_text(3), "   x");
+    }
+}
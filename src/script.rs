@@ -0,0 +1,224 @@
+use std::fs;
+use std::time::Duration;
+
+use crate::console_test::{ConsoleTest, Step, Timeout};
+
+/*
+Test scripts in text files, to write regression tests without Rust. A line
+per step, empty lines and lines starting with # are ignored. The texts are
+in double quotes with the escapes \r \n \t \e \\ \" and \xNN.
+
+    args -b tests/artifacts       Arguments for the emulator, as on the command line
+    timeout 100 polls             Timeout for the next expectations: calls to the
+    timeout 2000000 instructions  console status, instructions executed or wall
+    timeout 500ms                 time in ms or s
+    timeout 2s
+    send "DIR\r"                  Keys to type, ${name} is replaced with a capture
+    key ctrl-c                    Key by name: ctrl-x, ^X, enter, esc, tab, bs, del...
+    expect "A>"                   Waits for the text on the output
+    expect-regex "(?P<n>\d+) K"   Waits for the regular expression and captures the groups
+    expect-not "Error"            Fails if the text is written before the timeout
+    row 3 "WordStar"              Waits until the row of the screen contains the text
+    cursor 10 5                   Waits until the cursor is at the row and column
+    screen "A>\nB>"               Waits until the screen snapshot is the text
+
+Run it with "iz-cpm --script file" or with run_script_file().
+*/
+
+enum ScriptStep {
+    Input(String),
+    Key(String),
+    Expect(String),
+    ExpectRegex(String),
+    ExpectNot(String),
+    ExpectRow(usize, String),
+    ExpectCursor(usize, usize),
+    ExpectScreen(String),
+    SetTimeout(Timeout),
+}
+
+pub struct Script {
+    args: Vec<String>,
+    steps: Vec<ScriptStep>,
+}
+
+impl Script {
+    pub fn load(filename: &str) -> Result<Script, String> {
+        let content = fs::read_to_string(filename)
+            .map_err(|err| format!("Error reading script \"{}\": {}", filename, err))?;
+        Script::parse(&content)
+            .map_err(|err| format!("Error in script \"{}\": {}", filename, err))
+    }
+
+    pub fn parse(content: &str) -> Result<Script, String> {
+        let mut script = Script {
+            args: Vec::new(),
+            steps: Vec::new(),
+        };
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            script.parse_line(line).map_err(|err| format!("line {}: {}", i + 1, err))?;
+        }
+        Ok(script)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        let step = match command {
+            "args" => {
+                self.args.extend(argument.split_whitespace().map(|arg| arg.to_string()));
+                return Ok(());
+            },
+            "timeout" => ScriptStep::SetTimeout(parse_timeout(argument)?),
+            "send" => ScriptStep::Input(parse_text(argument)?),
+            "key" => ScriptStep::Key(argument.to_string()),
+            "expect" => ScriptStep::Expect(parse_text(argument)?),
+            "expect-regex" => ScriptStep::ExpectRegex(parse_text(argument)?),
+            "expect-not" => ScriptStep::ExpectNot(parse_text(argument)?),
+            "row" => {
+                let (row, text) = argument.split_once(char::is_whitespace)
+                    .ok_or("row needs a number and a text")?;
+                ScriptStep::ExpectRow(parse_position(row)?, parse_text(text.trim())?)
+            },
+            "cursor" => {
+                let (row, column) = argument.split_once(char::is_whitespace)
+                    .ok_or("cursor needs a row and a column")?;
+                ScriptStep::ExpectCursor(parse_position(row)?, parse_position(column.trim())?)
+            },
+            "screen" => ScriptStep::ExpectScreen(parse_text(argument)?),
+            _ => return Err(format!("unknown command \"{}\"", command)),
+        };
+        self.steps.push(step);
+        Ok(())
+    }
+
+    pub fn args(&self) -> Vec<&str> {
+        self.args.iter().map(|arg| arg.as_str()).collect()
+    }
+
+    pub fn steps(&self) -> Vec<Step<'_>> {
+        self.steps.iter().map(|step| match step {
+            ScriptStep::Input(text) => Step::Input(text),
+            ScriptStep::Key(name) => Step::Key(name),
+            ScriptStep::Expect(text) => Step::Expect(text),
+            ScriptStep::ExpectRegex(pattern) => Step::ExpectRegex(pattern),
+            ScriptStep::ExpectNot(text) => Step::ExpectNot(text),
+            ScriptStep::ExpectRow(row, text) => Step::ExpectRow(*row, text),
+            ScriptStep::ExpectCursor(row, column) => Step::ExpectCursor(*row, *column),
+            ScriptStep::ExpectScreen(snapshot) => Step::ExpectScreen(snapshot),
+            ScriptStep::SetTimeout(timeout) => Step::SetTimeout(*timeout),
+        }).collect()
+    }
+}
+
+fn parse_position(text: &str) -> Result<usize, String> {
+    match text.parse::<usize>() {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Err(format!("invalid position \"{}\"", text)),
+    }
+}
+
+fn parse_timeout(text: &str) -> Result<Timeout, String> {
+    let invalid = || format!("invalid timeout \"{}\", use polls, instructions, ms or s", text);
+    let (value, unit) = match text.split_once(char::is_whitespace) {
+        Some((value, unit)) => (value, unit.trim()),
+        None => {
+            let digits = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+            (&text[..digits], &text[digits..])
+        }
+    };
+    let value = value.parse::<u64>().map_err(|_| invalid())?;
+    match unit {
+        "polls" => Ok(Timeout::StatusCalls(value.try_into().map_err(|_| invalid())?)),
+        "instructions" => Ok(Timeout::Instructions(value)),
+        "ms" => Ok(Timeout::WallTime(Duration::from_millis(value))),
+        "s" => Ok(Timeout::WallTime(Duration::from_secs(value))),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_text(text: &str) -> Result<String, String> {
+    let inner = text.strip_prefix('"').and_then(|t| t.strip_suffix('"'))
+        .filter(|_| text.len() >= 2)
+        .ok_or_else(|| format!("expected a text in double quotes, found {}", text))?;
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('r') => result.push('\r'),
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('e') => result.push('\x1b'),
+            Some('\\') => result.push('\\'),
+            Some('"') => result.push('"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(value) => result.push(value as char),
+                    Err(_) => return Err(format!("invalid escape \\x{}", hex)),
+                }
+            },
+            Some(other) => return Err(format!("invalid escape \\{}", other)),
+            None => return Err("escape at the end of the text".to_string()),
+        }
+    }
+    Ok(result)
+}
+
+/// Runs the test script in the file with the arguments given plus the
+/// ones on the script. Returns the failure message if the test fails.
+pub fn run_script_file(filename: &str, args: Vec<&str>) -> Result<(), String> {
+    let script = Script::load(filename)?;
+    let mut all_args = script.args();
+    all_args.extend(args);
+
+    let mut console = ConsoleTest::new(script.steps());
+    console.set_panic_on_failure(false);
+    crate::run(Some(all_args), &mut console);
+    match console.failure() {
+        Some(failure) => Err(failure.to_string()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_text() {
+        assert_eq!(parse_text(r#""DIR\r""#), Ok("DIR\r".to_string()));
+        assert_eq!(parse_text(r#""\e[A \x41 \"q\" \\""#), Ok("\x1b[A A \"q\" \\".to_string()));
+        assert!(parse_text("DIR").is_err());
+        assert!(parse_text("\"").is_err());
+        assert!(parse_text(r#""\q""#).is_err());
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("100 polls"), Ok(Timeout::StatusCalls(100)));
+        assert_eq!(parse_timeout("2000 instructions"), Ok(Timeout::Instructions(2000)));
+        assert_eq!(parse_timeout("500ms"), Ok(Timeout::WallTime(Duration::from_millis(500))));
+        assert_eq!(parse_timeout("2 s"), Ok(Timeout::WallTime(Duration::from_secs(2))));
+        assert!(parse_timeout("2 minutes").is_err());
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let script = Script::parse("# Test\nargs -b dir\n\nsend \"B:\\r\"\nkey ctrl-c\nrow 3 \"A>\"\n").unwrap();
+        assert_eq!(script.args(), vec!("-b", "dir"));
+        assert_eq!(script.steps().len(), 3);
+        let error = Script::parse("send \"A\"\nfoo \"B\"").err().unwrap();
+        assert_eq!(error, "line 2: unknown command \"foo\"");
+    }
+}
@@ -0,0 +1,548 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use crate::device::{Device, DeviceMapping, parse_port};
+
+/*
+Serial ports for the communication programs: a channel of a Z80 SIO/DART and
+an Intel 8251 USART. Each one uses two adjacent ports, data and control, and
+is connected to a host link:
+
+    --device sio:PORTS[:OPTIONS]
+    --device 8251:PORTS[:OPTIONS]
+
+PORTS is the data port, with the control port the next one, or "data,control"
+like 81,80. The options are separated by commas:
+
+    pty             A host pseudo terminal, the path of the slave is printed,
+                    connect the host program to it. This is the default.
+    unix=path       Listens on a Unix socket for a connection.
+    in=file         Received data, read from the file.
+    out=file        Sent data, written to the file.
+    irq=NN          For the 8251, the byte put on the bus for the interrupt
+                    when a char is received, like ff for RST 38h. The SIO
+                    interrupts are configured by the program with WR1 and WR2.
+
+The data is sent without delay, the transmitter is always ready and the
+modem lines DCD, CTS and DSR are always active.
+
+SIO/DART control: writes go to WR0 with the register pointer in bits 0-2 and
+the commands in bits 3-5, the next write goes to the register pointed. WR1
+has the interrupt enables, WR2 the vector. Reads return RR0 with bit 0 for
+a char available and bit 2 for the transmit buffer empty, RR1 or RR2 if
+pointed.
+
+8251 control: after reset the first write is the mode and then commands.
+Bit 6 of a command is the internal reset. The status has bit 0 TxRDY, bit 1
+RxRDY, bit 2 TxEMPTY and bit 7 DSR.
+*/
+
+// Calls to the host to check for received data, in CPU cycles
+const POLL_CYCLES: u64 = 1000;
+
+pub trait SerialLink {
+    fn receive(&mut self) -> Option<u8>;
+    fn send(&mut self, value: u8);
+}
+
+struct FileLink {
+    input: Option<File>,
+    output: Option<File>,
+}
+
+impl SerialLink for FileLink {
+    fn receive(&mut self) -> Option<u8> {
+        let mut buf = [0];
+        match self.input.as_mut().map(|file| file.read(&mut buf)) {
+            Some(Ok(1)) => Some(buf[0]),
+            _ => None,
+        }
+    }
+
+    fn send(&mut self, value: u8) {
+        if let Some(output) = self.output.as_mut() {
+            if output.write_all(&[value]).is_err() {
+                eprintln!("Error writing the serial output file");
+                self.output = None;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::CStr;
+    use std::fs::{self, File};
+    use std::io::{ErrorKind, Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    use termios::{Termios, cfmakeraw, tcsetattr, TCSANOW};
+
+    use super::SerialLink;
+
+    pub struct PtyLink {
+        master: File,
+    }
+
+    impl PtyLink {
+        pub fn open() -> Result<(PtyLink, String), String> {
+            let error = |what: &str| format!("Error creating the pseudo terminal in {}", what);
+            unsafe {
+                let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+                if fd < 0 {
+                    return Err(error("posix_openpt"));
+                }
+                let master = File::from_raw_fd(fd);
+                if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                    return Err(error("grantpt"));
+                }
+                let name = libc::ptsname(fd);
+                if name.is_null() {
+                    return Err(error("ptsname"));
+                }
+                let path = CStr::from_ptr(name).to_string_lossy().to_string();
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) != 0 {
+                    return Err(error("fcntl"));
+                }
+                // Binary data, without echo or line conversions
+                if let Ok(mut settings) = Termios::from_fd(fd) {
+                    cfmakeraw(&mut settings);
+                    let _ = tcsetattr(fd, TCSANOW, &settings);
+                }
+                Ok((PtyLink { master }, path))
+            }
+        }
+    }
+
+    impl SerialLink for PtyLink {
+        fn receive(&mut self) -> Option<u8> {
+            // Without a program on the slave side, the read fails with EIO
+            let mut buf = [0];
+            match self.master.read(&mut buf) {
+                Ok(1) => Some(buf[0]),
+                _ => None,
+            }
+        }
+
+        fn send(&mut self, value: u8) {
+            // The data is lost if the host program doesn't read it
+            let _ = self.master.write_all(&[value]);
+        }
+    }
+
+    pub struct UnixSocketLink {
+        listener: UnixListener,
+        stream: Option<UnixStream>,
+    }
+
+    impl UnixSocketLink {
+        pub fn open(path: &str) -> Result<UnixSocketLink, String> {
+            let _ = fs::remove_file(path);
+            let listener = UnixListener::bind(path)
+                .map_err(|err| format!("Error listening on \"{}\": {}", path, err))?;
+            listener.set_nonblocking(true)
+                .map_err(|err| format!("Error listening on \"{}\": {}", path, err))?;
+            Ok(UnixSocketLink { listener, stream: None })
+        }
+
+        fn connected(&mut self) -> bool {
+            if self.stream.is_none() {
+                if let Ok((stream, _)) = self.listener.accept() {
+                    if stream.set_nonblocking(true).is_ok() {
+                        self.stream = Some(stream);
+                    }
+                }
+            }
+            self.stream.is_some()
+        }
+    }
+
+    impl SerialLink for UnixSocketLink {
+        fn receive(&mut self) -> Option<u8> {
+            if !self.connected() {
+                return None;
+            }
+            let mut buf = [0];
+            match self.stream.as_mut()?.read(&mut buf) {
+                Ok(1) => Some(buf[0]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => None,
+                _ => {
+                    // Disconnected, wait for another connection
+                    self.stream = None;
+                    None
+                }
+            }
+        }
+
+        fn send(&mut self, value: u8) {
+            if self.connected() {
+                if let Some(stream) = self.stream.as_mut() {
+                    if let Err(err) = stream.write_all(&[value]) {
+                        if err.kind() != ErrorKind::WouldBlock {
+                            self.stream = None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The common part of the serial devices: the link and the received chars
+struct SerialPort {
+    link: Box<dyn SerialLink>,
+    received: VecDeque<u8>,
+    last_poll: u64,
+}
+
+impl SerialPort {
+    fn new(link: Box<dyn SerialLink>) -> SerialPort {
+        SerialPort {
+            link,
+            received: VecDeque::new(),
+            last_poll: 0,
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.received.is_empty() {
+            if let Some(value) = self.link.receive() {
+                self.received.push_back(value);
+            }
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if cycles.wrapping_sub(self.last_poll) >= POLL_CYCLES {
+            self.last_poll = cycles;
+            self.poll();
+        }
+    }
+
+    fn rx_available(&mut self) -> bool {
+        self.poll();
+        !self.received.is_empty()
+    }
+}
+
+pub struct Sio {
+    port: SerialPort,
+    data_port: u8,
+    pointer: usize,
+    wr: [u8; 8],
+    tx_interrupt_pending: bool,
+}
+
+impl Sio {
+    pub fn new(link: Box<dyn SerialLink>, data_port: u8) -> Sio {
+        Sio {
+            port: SerialPort::new(link),
+            data_port,
+            pointer: 0,
+            wr: [0; 8],
+            tx_interrupt_pending: false,
+        }
+    }
+
+    fn rx_interrupt_enabled(&self) -> bool {
+        self.wr[1] & 0x18 != 0
+    }
+
+    fn tx_interrupt_enabled(&self) -> bool {
+        self.wr[1] & 0x02 != 0
+    }
+
+    fn vector(&self, rx: bool) -> u8 {
+        if self.wr[1] & 0x04 == 0 {
+            self.wr[2]
+        } else {
+            // Status affects vector, channel A: 110 for receive, 100 for transmit
+            let status = if rx {0b110} else {0b100};
+            (self.wr[2] & 0xf1) | (status << 1)
+        }
+    }
+}
+
+impl Device for Sio {
+    fn name(&self) -> String {
+        "sio".to_string()
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        if port == self.data_port {
+            return self.port.received.pop_front().unwrap_or(0);
+        }
+        let register = self.pointer;
+        self.pointer = 0;
+        match register {
+            0 => {
+                let rx = if self.port.rx_available() {0x01} else {0};
+                rx | 0x04 | 0x08 | 0x20 // Tx empty, DCD and CTS
+            },
+            1 => 0x01, // All sent
+            2 => self.wr[2],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        if port == self.data_port {
+            self.port.link.send(value);
+            self.tx_interrupt_pending = self.tx_interrupt_enabled();
+            return;
+        }
+        if self.pointer != 0 {
+            self.wr[self.pointer] = value;
+            self.pointer = 0;
+            return;
+        }
+        self.pointer = (value & 0x07) as usize;
+        match (value >> 3) & 0x07 {
+            3 => { // Channel reset
+                self.wr = [0; 8];
+                self.pointer = 0;
+                self.tx_interrupt_pending = false;
+            },
+            5 => self.tx_interrupt_pending = false, // Reset TxINT pending
+            _ => (),
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.port.tick(cycles);
+    }
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        if self.rx_interrupt_enabled() && !self.port.received.is_empty() {
+            Some(self.vector(true))
+        } else if self.tx_interrupt_pending {
+            Some(self.vector(false))
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Usart8251 {
+    port: SerialPort,
+    data_port: u8,
+    expect_mode: bool,
+    sync_chars_left: u8,
+    command: u8,
+    interrupt: Option<u8>,
+}
+
+impl Usart8251 {
+    pub fn new(link: Box<dyn SerialLink>, data_port: u8, interrupt: Option<u8>) -> Usart8251 {
+        Usart8251 {
+            port: SerialPort::new(link),
+            data_port,
+            expect_mode: true,
+            sync_chars_left: 0,
+            command: 0,
+            interrupt,
+        }
+    }
+}
+
+impl Device for Usart8251 {
+    fn name(&self) -> String {
+        "8251".to_string()
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        if port == self.data_port {
+            return self.port.received.pop_front().unwrap_or(0);
+        }
+        let rx = if self.port.rx_available() {0x02} else {0};
+        rx | 0x01 | 0x04 | 0x80 // TxRDY, TxEMPTY and DSR
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        if port == self.data_port {
+            if self.command & 0x01 != 0 { // TxEN
+                self.port.link.send(value);
+            }
+        } else if self.sync_chars_left > 0 {
+            self.sync_chars_left -= 1;
+        } else if self.expect_mode {
+            self.expect_mode = false;
+            if value & 0x03 == 0 {
+                // Synchronous mode, one or two sync chars follow
+                self.sync_chars_left = if value & 0x80 != 0 {1} else {2};
+            }
+        } else if value & 0x40 != 0 {
+            // Internal reset
+            self.expect_mode = true;
+            self.command = 0;
+        } else {
+            self.command = value;
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.port.tick(cycles);
+    }
+
+    fn interrupt_request(&mut self) -> Option<u8> {
+        // RxRDY is active with the receiver enabled
+        if self.command & 0x04 != 0 && !self.port.received.is_empty() {
+            self.interrupt
+        } else {
+            None
+        }
+    }
+}
+
+/// Creates a serial device from the ports and options of --device
+pub fn parse_serial(kind: &str, ports: &str, options: Option<&str>) -> Result<DeviceMapping, String> {
+    let (data_port, control_port) = match ports.split_once(',') {
+        Some((data, control)) => (parse_port(data), parse_port(control)),
+        None => {
+            let data = parse_port(ports);
+            (data, data.and_then(|port| port.checked_add(1)))
+        }
+    };
+    let (data_port, control_port) = match (data_port, control_port) {
+        (Some(data), Some(control)) if data.abs_diff(control) == 1 => (data, control),
+        _ => return Err(format!("Invalid ports \"{}\", use the data port or data,control adjacent", ports)),
+    };
+
+    let mut pty = false;
+    let mut unix_path = None;
+    let mut input = None;
+    let mut output = None;
+    let mut interrupt = None;
+    for option in options.unwrap_or("pty").split(',') {
+        match option.split_once('=') {
+            None if option == "pty" => pty = true,
+            Some(("unix", path)) => unix_path = Some(path),
+            Some(("in", file)) => input = Some(File::open(file)
+                .map_err(|err| format!("Error opening \"{}\": {}", file, err))?),
+            Some(("out", file)) => output = Some(File::create(file)
+                .map_err(|err| format!("Error creating \"{}\": {}", file, err))?),
+            Some(("irq", value)) if kind == "8251" => interrupt = Some(parse_port(value)
+                .ok_or_else(|| format!("Invalid interrupt byte \"{}\"", value))?),
+            _ => return Err(format!("Invalid serial option \"{}\", use pty, unix=path, in=file, out=file or irq=NN", option)),
+        }
+    }
+
+    let links = pty as usize + unix_path.is_some() as usize + (input.is_some() || output.is_some()) as usize;
+    if links != 1 {
+        return Err("Use one serial link: pty, unix=path or in=file,out=file".to_string());
+    }
+    let link: Box<dyn SerialLink> = if pty {
+        open_pty()?
+    } else if let Some(path) = unix_path {
+        open_unix(path)?
+    } else {
+        Box::new(FileLink { input, output })
+    };
+
+    let device: Box<dyn Device> = match kind {
+        "sio" => Box::new(Sio::new(link, data_port)),
+        _ => Box::new(Usart8251::new(link, data_port, interrupt)),
+    };
+    Ok(DeviceMapping::new(data_port.min(control_port), data_port.max(control_port), device))
+}
+
+#[cfg(unix)]
+fn open_pty() -> Result<Box<dyn SerialLink>, String> {
+    let (link, path) = unix::PtyLink::open()?;
+    eprintln!("Serial port connected to {}", path);
+    Ok(Box::new(link))
+}
+
+#[cfg(unix)]
+fn open_unix(path: &str) -> Result<Box<dyn SerialLink>, String> {
+    Ok(Box::new(unix::UnixSocketLink::open(path)?))
+}
+
+#[cfg(not(unix))]
+fn open_pty() -> Result<Box<dyn SerialLink>, String> {
+    Err("Pseudo terminals are not available on this system".to_string())
+}
+
+#[cfg(not(unix))]
+fn open_unix(_path: &str) -> Result<Box<dyn SerialLink>, String> {
+    Err("Unix sockets are not available on this system".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct TestLink {
+        input: VecDeque<u8>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl SerialLink for TestLink {
+        fn receive(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+
+        fn send(&mut self, value: u8) {
+            self.output.borrow_mut().push(value);
+        }
+    }
+
+    fn test_link(input: &[u8]) -> (Box<dyn SerialLink>, Rc<RefCell<Vec<u8>>>) {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        (Box::new(TestLink { input: input.iter().copied().collect(), output: output.clone() }), output)
+    }
+
+    #[test]
+    fn test_sio() {
+        let (link, output) = test_link(b"AB");
+        let mut sio = Sio::new(link, 0x81);
+        sio.write(0x80, 0x18); // Channel reset
+        sio.write(0x80, 0x02); // WR2 vector
+        sio.write(0x80, 0x40);
+        sio.write(0x80, 0x01); // WR1 Rx interrupts
+        sio.write(0x80, 0x18);
+        assert_eq!(sio.read(0x80) & 0x05, 0x05);
+        assert_eq!(sio.interrupt_request(), Some(0x40));
+        assert_eq!(sio.read(0x81), b'A');
+        sio.write(0x80, 0x02); // RR2
+        assert_eq!(sio.read(0x80), 0x40);
+        assert_eq!(sio.read(0x80) & 0x01, 0x01);
+        assert_eq!(sio.read(0x81), b'B');
+        assert_eq!(sio.read(0x80) & 0x01, 0x00);
+        assert_eq!(sio.interrupt_request(), None);
+        sio.write(0x81, b'Z');
+        assert_eq!(*output.borrow(), b"Z");
+    }
+
+    #[test]
+    fn test_8251() {
+        let (link, output) = test_link(b"Q");
+        let mut usart = Usart8251::new(link, 0x10, Some(0xff));
+        usart.write(0x11, 0x4e); // Mode: async, 8 bits, x16
+        usart.write(0x10, b'-'); // Not sent, TxEN off
+        usart.write(0x11, 0x37); // Command: TxEN, RxE, reset errors
+        assert_eq!(usart.read(0x11) & 0x87, 0x87);
+        assert_eq!(usart.interrupt_request(), Some(0xff));
+        assert_eq!(usart.read(0x10), b'Q');
+        assert_eq!(usart.read(0x11) & 0x02, 0x00);
+        usart.write(0x10, b'Y');
+        usart.write(0x11, 0x40); // Internal reset
+        usart.write(0x11, 0x4e);
+        usart.write(0x11, 0x05);
+        usart.write(0x10, b'N');
+        assert_eq!(*output.borrow(), b"YN");
+    }
+
+    #[test]
+    fn test_parse_serial_errors() {
+        assert!(parse_serial("sio", "10,12", Some("in=/dev/null")).is_err());
+        assert!(parse_serial("sio", "10", Some("unix=/tmp/x,in=/dev/null")).is_err());
+        assert!(parse_serial("sio", "10", Some("irq=ff")).is_err());
+        assert!(parse_serial("8251", "11,10", Some("in=/dev/null,irq=ff")).is_ok());
+    }
+}
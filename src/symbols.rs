@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+/*
+Symbol tables to annotate addresses on traces, profiles and crash reports.
+
+Supported formats, detected by content:
+    ZMAC listing (.lst): the "Symbol Table:" section at the end of the file.
+        Relocatable symbols, marked with a quote, are offset by the base
+        address given. Symbols defined with equ (marked with =) are not
+        addresses and are skipped unless relocatable.
+    M80/L80 and SLR .SYM: pairs of hex address and name, several per line:
+        0100 START     0103 LOOP     0110 MSG
+    Simple assignments, one per line, with hex values:
+        START=0100
+        LOOP = 0x0103
+        MSG = 110h
+*/
+
+const MAX_OFFSET: u16 = 0x1000; // Don't annotate addresses too far away of a symbol
+
+pub struct SymbolTable {
+    symbols: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, address: u16, name: &str) {
+        // Keep the first name found for an address
+        self.symbols.entry(address).or_insert_with(|| name.to_string());
+    }
+
+    /// Loads a file. The base is added to relocatable symbols.
+    pub fn load(&mut self, filename: &str, base: u16) -> io::Result<usize> {
+        let content = fs::read(filename)?;
+        let text = String::from_utf8_lossy(&content);
+        let count = self.parse(&text, base);
+        if count == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "No symbols found"));
+        }
+        Ok(count)
+    }
+
+    pub fn parse(&mut self, text: &str, base: u16) -> usize {
+        let before = self.symbols.len();
+        if let Some(index) = text.find("Symbol Table:") {
+            self.parse_zmac_listing(&text[index..], base);
+        } else if text.lines().any(|line| !is_comment(line) && line.contains('=')) {
+            self.parse_assignments(text);
+        } else {
+            self.parse_address_name_pairs(text);
+        }
+        self.symbols.len() - before
+    }
+
+    fn parse_zmac_listing(&mut self, text: &str, base: u16) {
+        // Lines like:
+        //    ADDAH            339'     825
+        //    BASE           =00        0
+        //    RNGSUB         = 12A'     298
+        for line in text.lines().skip(1) {
+            let mut tokens = line.split_whitespace();
+            let name = match tokens.next() {
+                Some(name) => name,
+                None => continue,
+            };
+            let mut value = match tokens.next() {
+                Some(value) => value.to_string(),
+                None => continue,
+            };
+            let is_equate = value.starts_with('=');
+            if value == "=" {
+                match tokens.next() {
+                    Some(v) => value = v.to_string(),
+                    None => continue,
+                }
+            }
+            let value = value.trim_start_matches('=');
+            let relocatable = value.ends_with('\'');
+            if is_equate && !relocatable {
+                continue;
+            }
+            if let Ok(address) = u32::from_str_radix(value.trim_end_matches('\''), 16) {
+                if address > 0xffff {
+                    continue;
+                }
+                let address = if relocatable {
+                    base.wrapping_add(address as u16)
+                } else {
+                    address as u16
+                };
+                self.add(address, name);
+            }
+        }
+    }
+
+    fn parse_address_name_pairs(&mut self, text: &str) {
+        for line in text.lines() {
+            let tokens: Vec<&str> = line.trim_end_matches('\x1a').split_whitespace().collect();
+            for pair in tokens.chunks(2) {
+                if pair.len() == 2 && pair[0].len() == 4 {
+                    if let Ok(address) = u16::from_str_radix(pair[0], 16) {
+                        self.add(address, pair[1]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn parse_assignments(&mut self, text: &str) {
+        for line in text.lines() {
+            if is_comment(line) {
+                continue;
+            }
+            if let Some((name, value)) = line.split_once('=') {
+                let name = name.trim();
+                if name.is_empty() {
+                    continue;
+                }
+                if let Some(address) = parse_hex(value.trim()) {
+                    self.add(address, name);
+                }
+            }
+        }
+    }
+
+    /// Returns the name of the symbol at the address, if any.
+    pub fn exact(&self, address: u16) -> Option<&str> {
+        self.symbols.get(&address).map(|name| name.as_str())
+    }
+
+    /// Returns the nearest symbol at or below the address as NAME or
+    /// NAME+0x12.
+    pub fn lookup(&self, address: u16) -> Option<String> {
+        let (symbol_address, name) = self.symbols.range(..=address).next_back()?;
+        let offset = address - symbol_address;
+        if offset == 0 {
+            Some(name.clone())
+        } else if offset < MAX_OFFSET {
+            Some(format!("{}+0x{:x}", name, offset))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the address followed by the symbol if there is one.
+    pub fn describe(&self, address: u16) -> String {
+        match self.lookup(address) {
+            Some(symbol) => format!("{:04x} ({})", address, symbol),
+            None => format!("{:04x}", address),
+        }
+    }
+}
+
+fn is_comment(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with(';') || line.starts_with('#')
+}
+
+// Accepts 1234, 0x1234, $1234 and 1234h. Always hex.
+pub fn parse_hex(text: &str) -> Option<u16> {
+    let lower = text.to_ascii_lowercase();
+    let digits = if let Some(hex) = lower.strip_prefix("0x") {
+        hex
+    } else if let Some(hex) = lower.strip_prefix('$') {
+        hex
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        hex
+    } else {
+        &lower
+    };
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zmac_listing() {
+        let text = "\
+  1:    0000'           start:  ld a, 1
+Symbol Table:
+
+ADDAH            339'     825
+BASE           =00        0
+RNGSUB         = 12A'     298
+ABS              120      288
+SHORT
+BAD              XYZ'
+BIG            12345
+";
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.parse(text, 0x100), 3);
+        assert_eq!(symbols.exact(0x439), Some("ADDAH"));
+        assert_eq!(symbols.exact(0x22a), Some("RNGSUB"));
+        assert_eq!(symbols.exact(0x120), Some("ABS"));
+        // The label before the table is not a symbol
+        assert_eq!(symbols.exact(0x100), None);
+        // Equates without a quote are values, not addresses
+        assert_eq!(symbols.exact(0x000), None);
+    }
+
+    #[test]
+    fn test_address_name_pairs() {
+        let text = "0100 START     0103 LOOP     0110 MSG\n012 SHORT  XYZW BAD  0200\n0300 END\x1a";
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.parse(text, 0), 4);
+        assert_eq!(symbols.exact(0x100), Some("START"));
+        assert_eq!(symbols.exact(0x103), Some("LOOP"));
+        assert_eq!(symbols.exact(0x110), Some("MSG"));
+        assert_eq!(symbols.exact(0x300), Some("END"));
+        assert_eq!(symbols.exact(0x200), None);
+    }
+
+    #[test]
+    fn test_assignments() {
+        let text = "; Comment = not a symbol\n# Other = comment\nSTART=0100\nLOOP = 0x0103\nMSG = 110h\nDATA=$200\n= 0300\nBAD = XYZ\nWORDS\n";
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.parse(text, 0x8000), 4);
+        assert_eq!(symbols.exact(0x100), Some("START"));
+        assert_eq!(symbols.exact(0x103), Some("LOOP"));
+        assert_eq!(symbols.exact(0x110), Some("MSG"));
+        assert_eq!(symbols.exact(0x200), Some("DATA"));
+        assert_eq!(symbols.exact(0x300), None);
+    }
+
+    #[test]
+    fn test_first_name_kept() {
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.parse("START=0100\nOTHER=0100\n", 0), 1);
+        assert_eq!(symbols.exact(0x100), Some("START"));
+    }
+
+    #[test]
+    fn test_lookup_and_describe() {
+        let mut symbols = SymbolTable::new();
+        symbols.parse("START=0100\n", 0);
+        assert_eq!(symbols.lookup(0x100), Some("START".to_string()));
+        assert_eq!(symbols.lookup(0x112), Some("START+0x12".to_string()));
+        assert_eq!(symbols.lookup(0x00ff), None);
+        assert_eq!(symbols.lookup(0x1100), None);
+        assert_eq!(symbols.describe(0x103), "0103 (START+0x3)");
+        assert_eq!(symbols.describe(0x0050), "0050");
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("1234"), Some(0x1234));
+        assert_eq!(parse_hex("0xFF"), Some(0xff));
+        assert_eq!(parse_hex("$e000"), Some(0xe000));
+        assert_eq!(parse_hex("100h"), Some(0x100));
+        assert_eq!(parse_hex("10000"), None);
+        assert_eq!(parse_hex("xyz"), None);
+        assert_eq!(parse_hex(""), None);
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::console_emulator::ConsoleEmulator;
+
+/*
+Telnet server to run iz-cpm as a service, "iz-cpm --listen 127.0.0.1:2323".
+Each connection gets its own thread with an emulator instance started with
+the same command line. The session console is the TelnetConsole.
+
+Telnet commands start with IAC (255). On connection the server offers to
+echo and to suppress go ahead, and asks the client not to use linemode, that
+puts the usual clients in character mode. The option negotiations from the
+client are discarded, IAC IAC is a 255 data byte and the CR NUL or CR LF
+sent by the clients for the enter key are received as a single CR.
+
+With a sessions directory (--session-dir), the server asks for a user name
+and maps A: to a directory with that name inside, created if needed.
+
+The host commands and the mounting of host directories are disabled on the
+sessions, they could reach any host path. The output files, like the
+transcript or the crash report, get the session number in the name, as
+"crash-3.txt", not to mix the sessions. There is a maximum of sessions at the
+same time (--max-sessions), the connections over it are closed.
+
+See RFC 854 (Telnet), RFC 857 (Echo), RFC 858 (Suppress go ahead) and
+RFC 1184 (Linemode)
+*/
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const OPTION_ECHO: u8 = 1;
+const OPTION_SUPPRESS_GO_AHEAD: u8 = 3;
+const OPTION_LINEMODE: u8 = 34;
+
+const MAX_USER_NAME: usize = 16;
+
+// Options with a file written by each session
+const OUTPUT_FILE_OPTIONS: [&str; 5] = ["--transcript", "--record", "--crash-report", "--profile", "--call-trace-output"];
+
+enum TelnetState {
+    Data,
+    Command,
+    Option,
+    Subnegotiation,
+    SubnegotiationCommand,
+    CarriageReturn,
+}
+
+pub struct TelnetConsole {
+    stream: TcpStream,
+    state: TelnetState,
+    input: VecDeque<u8>,
+    closed: bool,
+    last_sent: u8,
+}
+
+impl TelnetConsole {
+    pub fn new(stream: TcpStream) -> TelnetConsole {
+        let mut console = TelnetConsole {
+            stream,
+            state: TelnetState::Data,
+            input: VecDeque::new(),
+            closed: false,
+            last_sent: 0,
+        };
+        console.send(&[
+            IAC, WILL, OPTION_ECHO,
+            IAC, WILL, OPTION_SUPPRESS_GO_AHEAD,
+            IAC, DO, OPTION_SUPPRESS_GO_AHEAD,
+            IAC, DONT, OPTION_LINEMODE]);
+        console
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        if !self.closed && self.stream.write_all(data).is_err() {
+            self.closed = true;
+        }
+    }
+
+    fn send_text(&mut self, text: &str) {
+        // The host terminals convert LF to CR+LF, the telnet clients don't
+        let mut data = Vec::with_capacity(text.len());
+        for &byte in text.as_bytes() {
+            match byte {
+                b'\n' if self.last_sent != b'\r' => data.extend_from_slice(b"\r\n"),
+                IAC => data.extend_from_slice(&[IAC, IAC]),
+                _ => data.push(byte),
+            }
+            self.last_sent = byte;
+        }
+        self.send(&data);
+    }
+
+    fn receive(&mut self, blocking: bool) {
+        if self.closed {
+            return;
+        }
+        if self.stream.set_nonblocking(!blocking).is_err() {
+            self.closed = true;
+            return;
+        }
+        let mut buf = [0; 64];
+        match self.stream.read(&mut buf) {
+            Ok(0) => self.closed = true,
+            Ok(size) => {
+                for &byte in &buf[..size] {
+                    self.decode(byte);
+                }
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock
+                || err.kind() == ErrorKind::Interrupted => (),
+            Err(_) => self.closed = true,
+        }
+    }
+
+    fn decode(&mut self, byte: u8) {
+        self.state = match self.state {
+            TelnetState::Data | TelnetState::CarriageReturn => {
+                let after_cr = matches!(self.state, TelnetState::CarriageReturn);
+                match byte {
+                    IAC => TelnetState::Command,
+                    0 | b'\n' if after_cr => TelnetState::Data,
+                    b'\r' => {
+                        self.input.push_back(byte);
+                        TelnetState::CarriageReturn
+                    },
+                    _ => {
+                        self.input.push_back(byte);
+                        TelnetState::Data
+                    },
+                }
+            },
+            TelnetState::Command => match byte {
+                IAC => {
+                    self.input.push_back(IAC);
+                    TelnetState::Data
+                },
+                WILL | WONT | DO | DONT => TelnetState::Option,
+                SB => TelnetState::Subnegotiation,
+                _ => TelnetState::Data,
+            },
+            TelnetState::Option => TelnetState::Data,
+            TelnetState::Subnegotiation => match byte {
+                IAC => TelnetState::SubnegotiationCommand,
+                _ => TelnetState::Subnegotiation,
+            },
+            TelnetState::SubnegotiationCommand => match byte {
+                SE => TelnetState::Data,
+                _ => TelnetState::Subnegotiation,
+            },
+        }
+    }
+
+    fn read_line(&mut self, max_length: usize) -> Option<String> {
+        let mut line = String::new();
+        loop {
+            let ch = self.read();
+            if self.closed {
+                return None;
+            }
+            match ch {
+                b'\r' | b'\n' => {
+                    self.send_text("\n");
+                    return Some(line);
+                },
+                8 | 127 if line.pop().is_some() => self.send(b"\x08 \x08"),
+                32..=126 if line.len() < max_length => {
+                    line.push(ch as char);
+                    self.send(&[ch]);
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+impl ConsoleEmulator for TelnetConsole {
+    fn status(&mut self) -> bool {
+        if self.input.is_empty() {
+            self.receive(false);
+        }
+        if self.input.is_empty() {
+            // Avoid 100% CPU usage waiting for input.
+            thread::sleep(Duration::from_nanos(100));
+            false
+        } else {
+            true
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        while self.input.is_empty() && !self.closed {
+            self.receive(true);
+        }
+        // Once closed, the run loop ends the session after this instruction
+        self.input.pop_front().unwrap_or(b'\r')
+    }
+
+    fn put(&mut self, sequence: Option<String>) {
+        if let Some(sequence) = sequence {
+            self.send_text(&sequence);
+        }
+    }
+
+    fn terminated(&self) -> bool {
+        self.closed
+    }
+
+    fn message(&mut self, text: &str) {
+        self.send_text(text);
+    }
+}
+
+fn valid_user_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-')
+}
+
+// The output file name with the session number
+fn session_file(name: &str, session: usize) -> String {
+    let path = Path::new(name);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let file = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, session, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, session),
+    };
+    path.with_file_name(file).to_string_lossy().to_string()
+}
+
+// The device with the session number on its output file or socket, the
+// printer file, the serial out=file and unix=path
+fn session_device(spec: &str, session: usize) -> String {
+    let parts: Vec<&str> = spec.splitn(3, ':').collect();
+    match parts.as_slice() {
+        ["printer", port, file] => format!("printer:{}:{}", port, session_file(file, session)),
+        [kind @ ("sio" | "8251"), ports, options] => {
+            let options: Vec<String> = options.split(',').map(|option| match option.split_once('=') {
+                Some((name @ ("out" | "unix"), path)) => format!("{}={}", name, session_file(path, session)),
+                _ => option.to_string(),
+            }).collect();
+            format!("{}:{}:{}", kind, ports, options.join(","))
+        },
+        _ => spec.to_string(),
+    }
+}
+
+fn session_args(args: &[String], disk_a: Option<&str>, session: usize) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut skip_next = false;
+    let mut output_next = false;
+    let mut device_next = false;
+    for arg in args {
+        if skip_next {
+            skip_next = false;
+        } else if output_next {
+            output_next = false;
+            result.push(session_file(arg, session));
+        } else if device_next {
+            device_next = false;
+            result.push(session_device(arg, session));
+        } else if disk_a.is_some() && (arg == "-a" || arg == "--disk-a") {
+            skip_next = true;
+        } else if disk_a.is_some() && arg.starts_with("--disk-a=") {
+            // Replaced with the user directory
+        } else if OUTPUT_FILE_OPTIONS.contains(&arg.as_str()) {
+            output_next = true;
+            result.push(arg.clone());
+        } else if arg == "--device" {
+            device_next = true;
+            result.push(arg.clone());
+        } else {
+            match arg.split_once('=') {
+                Some((option, name)) if OUTPUT_FILE_OPTIONS.contains(&option) =>
+                    result.push(format!("{}={}", option, session_file(name, session))),
+                Some(("--device", spec)) =>
+                    result.push(format!("--device={}", session_device(spec, session))),
+                _ => result.push(arg.clone()),
+            }
+        }
+    }
+    if let Some(path) = disk_a {
+        result.push("-a".to_string());
+        result.push(path.to_string());
+    }
+    result.push("--no-host-commands".to_string());
+    result
+}
+
+fn session(stream: TcpStream, args: Vec<String>, session_dir: Option<String>, number: usize) {
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    eprintln!("Session from {} started", peer);
+    let mut console = TelnetConsole::new(stream);
+
+    let mut user_dir = None;
+    if let Some(base) = session_dir {
+        let user = loop {
+            console.send_text("User: ");
+            match console.read_line(MAX_USER_NAME) {
+                None => return,
+                Some(name) if valid_user_name(&name) => break name,
+                Some(_) => console.send_text("Use letters, digits, - and _\n"),
+            }
+        };
+        let path = Path::new(&base).join(&user);
+        if let Err(err) = fs::create_dir_all(&path) {
+            eprintln!("Error creating directory \"{}\": {}", path.display(), err);
+            console.send_text("Error creating the user directory\n");
+            return;
+        }
+        eprintln!("Session from {} is user {}", peer, user);
+        user_dir = Some(path.to_string_lossy().to_string());
+    }
+
+    let args = session_args(&args, user_dir.as_deref(), number);
+    crate::run(Some(args.iter().map(|arg| arg.as_str()).collect()), &mut console);
+    if !console.closed {
+        console.send_text("\nSession ended\n");
+        let _ = console.stream.shutdown(std::net::Shutdown::Both);
+    }
+    eprintln!("Session from {} ended", peer);
+}
+
+/// Listens for telnet connections on the address and runs a session for
+/// each one with the arguments given, up to max_sessions at the same time.
+/// With a session directory, each user gets a directory inside for A:.
+/// Returns only on errors.
+pub fn serve(address: &str, args: Vec<String>, session_dir: Option<String>, max_sessions: usize) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("iz-cpm listening on {}", listener.local_addr()?);
+    let active = Arc::new(AtomicUsize::new(0));
+    let mut count = 0;
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                if active.load(Ordering::SeqCst) >= max_sessions {
+                    eprintln!("Connection rejected, {} sessions running", max_sessions);
+                    let _ = stream.write_all(b"Too many sessions, try later\r\n");
+                    continue;
+                }
+                active.fetch_add(1, Ordering::SeqCst);
+                count += 1;
+                let args = args.clone();
+                let session_dir = session_dir.clone();
+                let active = active.clone();
+                thread::spawn(move || {
+                    session(stream, args, session_dir, count);
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            },
+            Err(err) => eprintln!("Error accepting connection: {}", err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_args() {
+        let args: Vec<String> = ["-a", "old", "-b", "dir"].iter().map(|a| a.to_string()).collect();
+        assert_eq!(session_args(&args, None, 1), vec!("-a", "old", "-b", "dir", "--no-host-commands"));
+        assert_eq!(session_args(&args, Some("users/ivan"), 1), vec!("-b", "dir", "-a", "users/ivan", "--no-host-commands"));
+        let args: Vec<String> = ["--transcript", "logs/out.txt", "--crash-report=crash", "x.com"].iter().map(|a| a.to_string()).collect();
+        assert_eq!(session_args(&args, None, 3),
+            vec!("--transcript", "logs/out-3.txt", "--crash-report=crash-3", "x.com", "--no-host-commands"));
+        let args: Vec<String> = ["--device", "printer:12:lpt.txt", "--device=sio:80:unix=/tmp/serial",
+            "--device", "8251:10:in=in.txt,out=out.txt,irq=cf", "--device", "value:10:ff"].iter().map(|a| a.to_string()).collect();
+        assert_eq!(session_args(&args, None, 2),
+            vec!("--device", "printer:12:lpt-2.txt", "--device=sio:80:unix=/tmp/serial-2",
+                "--device", "8251:10:in=in.txt,out=out-2.txt,irq=cf", "--device", "value:10:ff", "--no-host-commands"));
+    }
+
+    #[test]
+    fn test_valid_user_name() {
+        assert!(valid_user_name("ivan_2"));
+        assert!(!valid_user_name(""));
+        assert!(!valid_user_name("../etc"));
+    }
+}
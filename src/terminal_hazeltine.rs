@@ -0,0 +1,162 @@
+use crate::terminal::TerminalEmulator;
+
+/*
+See:
+    http://bitsavers.org/pdf/hazeltine/Hazeltine_1500_Reference_Manual.pdf
+    https://invisible-island.net/ncurses/terminfo.src.html#tic-hz1500
+    https://www.xfree86.org/current/ctlseqs.html
+
+The Hazeltine 1500 uses ~ as lead-in for its commands. It can be switched to
+use ESC instead, then ~ is a regular char. Use the tilde_literal option for
+programs configured for that.
+
+Control characters:
+    Ring Bell                      07 -> 07
+    Cursor left (non-destructive)  08 -> ESC[D
+    Cursor Right                   16 -> ESC[C
+
+Lead-in sequences:
+    Cursor address                              ~,DC1,col,row -> ESC[n;mH
+    Home cursor                                 ~,DC2   -> ESC[H
+    Cursor up                                   ~,FF    -> ESC[A
+    Cursor down                                 ~,VT    -> ESC[B
+    Clear screen, home cursor                   ~,FS    -> ESC[2J + ESC[H
+    Clear foreground, home cursor               ~,GS    -> ESC[2J + ESC[H
+    Clear to end of line                        ~,SI    -> ESC[K
+    Clear to end of screen                      ~,CAN   -> ESC[J
+    Clear foreground to end of screen           ~,ETB   -> ESC[J
+    Insert line                                 ~,SUB   -> ESC[L
+    Delete line                                 ~,DC3   -> ESC[M
+    Foreground (normal intensity) follows       ~,EM    -> ESC[22m
+    Background (half intensity) follows         ~,US    -> ESC[2m
+    Other sequences                             ignored
+
+The coordinates of the cursor address are sent as the value or as the value
+plus 96, the terminal takes both.
+*/
+
+const LEAD_IN: u8 = b'~';
+const ESC: u8 = 27;
+
+pub struct HazeltineToAnsi {
+    buffer: [u8;4],
+    buffer_len: usize,
+    lead_in: u8,
+}
+
+impl HazeltineToAnsi {
+    pub fn new(tilde_literal: bool) -> HazeltineToAnsi {
+        HazeltineToAnsi {
+            buffer: [0,0,0,0],
+            buffer_len: 0,
+            lead_in: if tilde_literal {ESC} else {LEAD_IN},
+        }
+    }
+
+    fn conversion(&mut self) -> Option<String> {
+        match self.buffer[0] {
+            // Control characters
+            8  => Some("\x1b[D".to_string()),    // Cursor left (non-destructive)
+            16 => Some("\x1b[C".to_string()),    // Cursor Right
+            // Lead-in sequences
+            ch if ch == self.lead_in => {
+                if self.buffer_len < 2 {
+                    None // We need more buffer
+                } else {
+                    match self.buffer[1] {
+                        0x11 => { // DC1
+                            if self.buffer_len < 4 {
+                                None // We need more buffer
+                            } else {
+                                let col = coordinate(self.buffer[2]);
+                                let row = coordinate(self.buffer[3]);
+                                Some(format!("\x1b[{};{}H", row + 1, col + 1))
+                            }
+                        },
+                        0x12 => Some("\x1b[H".to_string()),   // DC2: Home cursor
+                        0x0c => Some("\x1b[A".to_string()),   // FF: Cursor up
+                        0x0b => Some("\x1b[B".to_string()),   // VT: Cursor down
+                        0x1c | 0x1d => Some("\x1b[2J\x1b[H".to_string()), // FS, GS: Clear screen
+                        0x0f => Some("\x1b[K".to_string()),   // SI: Clear to end of line
+                        0x18 | 0x17 => Some("\x1b[J".to_string()), // CAN, ETB: Clear to end of screen
+                        0x1a => Some("\x1b[L".to_string()),   // SUB: Insert line
+                        0x13 => Some("\x1b[M".to_string()),   // DC3: Delete line
+                        0x19 => Some("\x1b[22m".to_string()), // EM: Foreground follows
+                        0x1f => Some("\x1b[2m".to_string()),  // US: Background follows
+                        _  => Some("".to_string()) // Unknown, ignore
+                    }
+                }
+            },
+            _ => Some(format!("{}", self.buffer[0] as char)) // Write the char
+        }
+    }
+}
+
+fn coordinate(value: u8) -> u8 {
+    if value >= 96 {
+        value - 96
+    } else {
+        value
+    }
+}
+
+impl TerminalEmulator for HazeltineToAnsi {
+    fn translate(&mut self, ch: u8) -> Option<String> {
+        self.buffer[self.buffer_len] = ch & 0x7f; // Only 7 bits ASCII
+        self.buffer_len += 1;
+
+        let conversion = self.conversion();
+        if conversion.is_some() {
+            self.buffer_len = 0;
+        }
+        conversion
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate_all(emulator: &mut HazeltineToAnsi, input: &[u8]) -> String {
+        input.iter().filter_map(|&ch| emulator.translate(ch)).collect()
+    }
+
+    #[test]
+    fn test_cursor_address() {
+        let mut emulator = HazeltineToAnsi::new(false);
+        assert_eq!(emulator.translate(b'~'), None);
+        assert_eq!(emulator.translate(0x11), None);
+        assert_eq!(emulator.translate(10), None);
+        assert_eq!(emulator.translate(5), Some("\x1b[6;11H".to_string()));
+        // With 96 added, and column over 30 with the high bit
+        assert_eq!(translate_all(&mut emulator, &[b'~', 0x11, 96 + 2, 96 + 3]), "\x1b[4;3H");
+        assert_eq!(translate_all(&mut emulator, &[b'~', 0x11, 128 + 40, 96 + 23]), "\x1b[24;41H");
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut emulator = HazeltineToAnsi::new(false);
+        assert_eq!(translate_all(&mut emulator, b"~\x1c~\x0f~\x18"), "\x1b[2J\x1b[H\x1b[K\x1b[J");
+    }
+
+    #[test]
+    fn test_lines_and_cursor() {
+        let mut emulator = HazeltineToAnsi::new(false);
+        assert_eq!(translate_all(&mut emulator, b"~\x1a~\x13~\x12~\x0c~\x0b\x08\x10"),
+            "\x1b[L\x1b[M\x1b[H\x1b[A\x1b[B\x1b[D\x1b[C");
+    }
+
+    #[test]
+    fn test_intensity() {
+        let mut emulator = HazeltineToAnsi::new(false);
+        assert_eq!(translate_all(&mut emulator, b"~\x1fdim~\x19"), "\x1b[2mdim\x1b[22m");
+    }
+
+    #[test]
+    fn test_tilde_literal() {
+        let mut emulator = HazeltineToAnsi::new(true);
+        assert_eq!(translate_all(&mut emulator, b"a~b\x1b\x1c"), "a~b\x1b[2J\x1b[H");
+        let mut emulator = HazeltineToAnsi::new(false);
+        assert_eq!(translate_all(&mut emulator, b"a~xb"), "ab");
+    }
+}
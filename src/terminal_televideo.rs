@@ -0,0 +1,220 @@
+use crate::terminal::TerminalEmulator;
+
+/*
+See:
+    http://bitsavers.org/pdf/televideo/950/Televideo_950_Operators_Manual_1981.pdf
+    http://bitsavers.org/pdf/televideo/920/TeleVideo_912_920_Operators_Manual.pdf
+    https://www.xfree86.org/current/ctlseqs.html
+
+The Televideo 910, 920 and 950 share the control characters and most of the
+escape sequences.
+
+Control characters:
+    Ring Bell                      07 -> 07
+    Cursor left (non-destructive)  08 -> ESC[D
+    Cursor Up                      11 -> ESC[A
+    Cursor Right                   12 -> ESC[C
+    Clear screen, home cursor      26 -> ESC[2J + ESC[H
+    Home cursor                    30 -> ESC[H
+
+ESCape Sequences
+    Cursor address                              ESC,=,row+32,col+32 -> ESC[n;mH
+    Erase to end of line                        ESC,T or ESC,t -> ESC[K
+    Erase to end of screen                      ESC,Y or ESC,y -> ESC[J
+    Clear screen, home cursor                   ESC,* ESC,+ ESC,: ESC,; -> ESC[2J + ESC[H
+    Half intensity (write protect) start        ESC,)   -> ESC[2m
+    Half intensity (write protect) stop         ESC,(   -> ESC[22m
+    Insert line                                 ESC,E   -> ESC[L
+    Delete line                                 ESC,R   -> ESC[M
+    Insert character                            ESC,Q   -> ESC[@
+    Delete character                            ESC,W   -> ESC[P
+    Reverse line feed                           ESC,j   -> ESCM
+    Tab                                         ESC,i   -> 09
+    Cursor attributes                           ESC,.,0 -> ESC[?25l
+                                                ESC,.,n -> ESC[?25h
+    Visual attributes                           ESC,G,n -> ESC[0;..m + space
+    Other sequences                             ignored
+
+Visual attributes:
+    The attribute n is a bit mask over 0x30: 1 blank, 2 blink, 4 reverse,
+    8 underline. On the 950, 0x40 set (p to DEL) adds half intensity.
+
+    Unlike ANSI, the Televideo stores the attribute on a screen position that
+    is displayed as a space. The programs configured for the Televideo take it
+    into account, so we write the space after the attribute change to keep the
+    text on the same columns.
+*/
+
+pub struct TelevideoToAnsi {
+    buffer: [u8;4],
+    buffer_len: usize
+}
+
+impl TelevideoToAnsi {
+    pub fn new() -> TelevideoToAnsi {
+        TelevideoToAnsi {
+            buffer: [0,0,0,0],
+            buffer_len: 0
+        }
+    }
+
+    fn conversion(&mut self) -> Option<String> {
+        match self.buffer[0] {
+            // Control characters
+            8  => Some("\x1b[D".to_string()),    // Cursor left (non-destructive)
+            11 => Some("\x1b[A".to_string()),    // Cursor Up
+            12 => Some("\x1b[C".to_string()),    // Cursor Right
+            26 => Some("\x1b[2J\x1b[H".to_string()),   // Clear screen, home cursor
+            30 => Some("\x1b[H".to_string()),    // Home cursor
+            // Escape sequences
+            27 => { // ESCAPE
+                if self.buffer_len < 2 {
+                    None // We need more buffer
+                } else {
+                    match self.buffer[1] as char {
+                        '=' => {
+                            if self.buffer_len < 4 {
+                                None // We need more buffer
+                            } else {
+                                let r32 = self.buffer[2];
+                                let c32 = self.buffer[3];
+                                if r32 < 32 || c32 < 32 {
+                                    Some("".to_string()) // Invalid numbers, ignore
+                                } else {
+                                    Some(format!("\x1b[{};{}H", r32-31, c32-31))
+                                }
+                            }
+                        },
+                        'T' | 't' => Some("\x1b[K".to_string()), // Erase to end of line
+                        'Y' | 'y' => Some("\x1b[J".to_string()), // Erase to end of screen
+                        '*' | '+' | ':' | ';' => Some("\x1b[2J\x1b[H".to_string()), // Clear screen
+                        ')' => Some("\x1b[2m".to_string()),  // Half intensity start
+                        '(' => Some("\x1b[22m".to_string()), // Half intensity stop
+                        'E' => Some("\x1b[L".to_string()),   // Insert line
+                        'R' => Some("\x1b[M".to_string()),   // Delete line
+                        'Q' => Some("\x1b[@".to_string()),   // Insert character
+                        'W' => Some("\x1b[P".to_string()),   // Delete character
+                        'j' => Some("\x1bM".to_string()),    // Reverse line feed
+                        'i' => Some("\t".to_string()),       // Tab
+                        '.' => {
+                            if self.buffer_len < 3 {
+                                None // We need more buffer
+                            } else {
+                                match self.buffer[2] as char {
+                                    '0' => Some("\x1b[?25l".to_string()), // Cursor off
+                                     _  => Some("\x1b[?25h".to_string())  // Cursor on
+                                }
+                            }
+                        },
+                        'G' => {
+                            if self.buffer_len < 3 {
+                                None // We need more buffer
+                            } else {
+                                Some(visual_attributes(self.buffer[2]))
+                            }
+                        },
+                        _  => Some("".to_string()) // Unknown, ignore
+                    }
+                }
+            },
+            _ => Some(format!("{}", self.buffer[0] as char)) // Write the char
+        }
+    }
+}
+
+fn visual_attributes(attribute: u8) -> String {
+    if !(0x30..0x80).contains(&attribute) || (0x40..0x70).contains(&attribute) {
+        return "".to_string(); // Unknown, ignore
+    }
+    let mut sgr = "\x1b[0".to_string();
+    if attribute & 0x01 != 0 {
+        sgr += ";8"; // Blank
+    }
+    if attribute & 0x02 != 0 {
+        sgr += ";5"; // Blink
+    }
+    if attribute & 0x04 != 0 {
+        sgr += ";7"; // Reverse
+    }
+    if attribute & 0x08 != 0 {
+        sgr += ";4"; // Underline
+    }
+    if attribute & 0x40 != 0 {
+        sgr += ";2"; // Half intensity
+    }
+    sgr += "m ";
+    sgr
+}
+
+impl TerminalEmulator for TelevideoToAnsi {
+    fn translate(&mut self, ch: u8) -> Option<String> {
+        self.buffer[self.buffer_len] = ch & 0x7f; // Only 7 bits ASCII
+        self.buffer_len += 1;
+
+        let conversion = self.conversion();
+        if conversion.is_some() {
+            self.buffer_len = 0;
+        }
+        conversion
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate_all(emulator: &mut TelevideoToAnsi, input: &[u8]) -> String {
+        input.iter().filter_map(|&ch| emulator.translate(ch)).collect()
+    }
+
+    #[test]
+    fn test_control_characters() {
+        let mut emulator = TelevideoToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"A\x08\x0b\x0c\x1a\x1e\r\n"),
+            "A\x1b[D\x1b[A\x1b[C\x1b[2J\x1b[H\x1b[H\r\n");
+    }
+
+    #[test]
+    fn test_cursor_address() {
+        let mut emulator = TelevideoToAnsi::new();
+        assert_eq!(emulator.translate(27), None);
+        assert_eq!(emulator.translate(b'='), None);
+        assert_eq!(emulator.translate(32 + 23), None);
+        assert_eq!(emulator.translate(32 + 79), Some("\x1b[24;80H".to_string()));
+    }
+
+    #[test]
+    fn test_erase_and_clear() {
+        let mut emulator = TelevideoToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1bT\x1bt\x1bY\x1by"), "\x1b[K\x1b[K\x1b[J\x1b[J");
+        assert_eq!(translate_all(&mut emulator, b"\x1b*\x1b:"), "\x1b[2J\x1b[H\x1b[2J\x1b[H");
+    }
+
+    #[test]
+    fn test_lines_and_characters() {
+        let mut emulator = TelevideoToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1bE\x1bR\x1bQ\x1bW\x1bj"),
+            "\x1b[L\x1b[M\x1b[@\x1b[P\x1bM");
+    }
+
+    #[test]
+    fn test_half_intensity() {
+        let mut emulator = TelevideoToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1b)dim\x1b("), "\x1b[2mdim\x1b[22m");
+    }
+
+    #[test]
+    fn test_visual_attributes_take_a_space() {
+        let mut emulator = TelevideoToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1bG4X\x1bG0"), "\x1b[0;7m X\x1b[0m ");
+        assert_eq!(translate_all(&mut emulator, b"\x1bG<"), "\x1b[0;7;4m ");
+        assert_eq!(translate_all(&mut emulator, b"\x1bGt"), "\x1b[0;7;2m ");
+        assert_eq!(translate_all(&mut emulator, b"\x1bGA"), "");
+    }
+
+    #[test]
+    fn test_cursor_attributes() {
+        let mut emulator = TelevideoToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1b.0\x1b.2"), "\x1b[?25l\x1b[?25h");
+    }
+}
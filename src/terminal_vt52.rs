@@ -0,0 +1,241 @@
+use crate::terminal::TerminalEmulator;
+
+/*
+See:
+    https://vt100.net/docs/vt52-mm/chapter3.html
+    http://bitsavers.org/pdf/heathkit/h19/595-2284-05_H19_Operation_Apr79.pdf
+    https://www.xfree86.org/current/ctlseqs.html
+
+Control characters are the same as ANSI, they are sent as they are.
+
+VT52 escape sequences
+    Cursor up                                   ESC,A   -> ESC[A
+    Cursor down                                 ESC,B   -> ESC[B
+    Cursor right                                ESC,C   -> ESC[C
+    Cursor left                                 ESC,D   -> ESC[D
+    Enter graphics mode                         ESC,F   -> (see below)
+    Exit graphics mode                          ESC,G   -> (see below)
+    Cursor home                                 ESC,H   -> ESC[H
+    Reverse line feed                           ESC,I   -> ESCM
+    Erase to end of screen                      ESC,J   -> ESC[J
+    Erase to end of line                        ESC,K   -> ESC[K
+    Cursor address                              ESC,Y,row+32,col+32 -> ESC[n;mH
+    Identify                                    ESC,Z   -> ignored
+    Alternate keypad mode on/off                ESC,=/> -> ignored
+
+Heath H19 extensions
+    Clear screen, home cursor                   ESC,E   -> ESC[2J + ESC[H
+    Erase to beginning of screen                ESC,b   -> ESC[1J
+    Erase line                                  ESC,l   -> ESC[2K
+    Erase to beginning of line                  ESC,o   -> ESC[1K
+    Insert line                                 ESC,L   -> ESC[L
+    Delete line                                 ESC,M   -> ESC[M
+    Delete character                            ESC,N   -> ESC[P
+    Insert character mode on                    ESC,@   -> ESC[4h
+    Insert character mode off                   ESC,O   -> ESC[4l
+    Reverse video start                         ESC,p   -> ESC[7m
+    Reverse video stop                          ESC,q   -> ESC[27m
+    Save cursor position                        ESC,j   -> ESC7
+    Restore cursor position                     ESC,k   -> ESC8
+    Set mode, cursor off                        ESC,x,5 -> ESC[?25l
+    Reset mode, cursor on                       ESC,y,5 -> ESC[?25h
+    Reset terminal                              ESC,z   -> ESC[0m + ESC[4l + ESC[?25h
+    Other modes of ESC,x and ESC,y              ignored
+
+On graphics mode the chars from ^ to ~ are replaced with the H19 graphics
+characters, we use the closest Unicode chars.
+*/
+
+const GRAPHICS_FIRST: u8 = b'^';
+const GRAPHICS: [char; 33] = [
+    '●', '◥', '│', '─', '┼', '┐', '┘', '└', // ^ _ ` a b c d e
+    '┌', '±', '→', '▒', '▚', '↓', '▗', '▖', // f g h i j k l m
+    '▘', '▝', '▀', '▐', '◤', '┬', '┤', '┴', // n o p q r s t u
+    '├', '╳', '╱', '╲', '▔', '▁', '▏', '▕', // v w x y z { | }
+    '¶',                                    // ~
+];
+
+pub struct Vt52ToAnsi {
+    buffer: [u8;4],
+    buffer_len: usize,
+    graphics: bool,
+}
+
+impl Vt52ToAnsi {
+    pub fn new() -> Vt52ToAnsi {
+        Vt52ToAnsi {
+            buffer: [0,0,0,0],
+            buffer_len: 0,
+            graphics: false,
+        }
+    }
+
+    fn conversion(&mut self) -> Option<String> {
+        match self.buffer[0] {
+            // Escape sequences
+            27 => { // ESCAPE
+                if self.buffer_len < 2 {
+                    None // We need more buffer
+                } else {
+                    match self.buffer[1] as char {
+                        // VT52
+                        'A' => Some("\x1b[A".to_string()), // Cursor up
+                        'B' => Some("\x1b[B".to_string()), // Cursor down
+                        'C' => Some("\x1b[C".to_string()), // Cursor right
+                        'D' => Some("\x1b[D".to_string()), // Cursor left
+                        'F' => { // Enter graphics mode
+                            self.graphics = true;
+                            Some("".to_string())
+                        },
+                        'G' => { // Exit graphics mode
+                            self.graphics = false;
+                            Some("".to_string())
+                        },
+                        'H' => Some("\x1b[H".to_string()), // Cursor home
+                        'I' => Some("\x1bM".to_string()),  // Reverse line feed
+                        'J' => Some("\x1b[J".to_string()), // Erase to end of screen
+                        'K' => Some("\x1b[K".to_string()), // Erase to end of line
+                        'Y' => {
+                            if self.buffer_len < 4 {
+                                None // We need more buffer
+                            } else {
+                                let r32 = self.buffer[2];
+                                let c32 = self.buffer[3];
+                                if r32 < 32 || c32 < 32 {
+                                    Some("".to_string()) // Invalid numbers, ignore
+                                } else {
+                                    Some(format!("\x1b[{};{}H", r32-31, c32-31))
+                                }
+                            }
+                        },
+                        // H19
+                        'E' => Some("\x1b[2J\x1b[H".to_string()), // Clear screen, home cursor
+                        'b' => Some("\x1b[1J".to_string()),  // Erase to beginning of screen
+                        'l' => Some("\x1b[2K".to_string()),  // Erase line
+                        'o' => Some("\x1b[1K".to_string()),  // Erase to beginning of line
+                        'L' => Some("\x1b[L".to_string()),   // Insert line
+                        'M' => Some("\x1b[M".to_string()),   // Delete line
+                        'N' => Some("\x1b[P".to_string()),   // Delete character
+                        '@' => Some("\x1b[4h".to_string()),  // Insert character mode on
+                        'O' => Some("\x1b[4l".to_string()),  // Insert character mode off
+                        'p' => Some("\x1b[7m".to_string()),  // Reverse video start
+                        'q' => Some("\x1b[27m".to_string()), // Reverse video stop
+                        'j' => Some("\x1b7".to_string()),    // Save cursor position
+                        'k' => Some("\x1b8".to_string()),    // Restore cursor position
+                        'x' => {
+                            if self.buffer_len < 3 {
+                                None // We need more buffer
+                            } else {
+                                match self.buffer[2] as char {
+                                    '5' => Some("\x1b[?25l".to_string()), // Cursor off
+                                     _  => Some("".to_string())           // Unknown, ignore
+                                }
+                            }
+                        },
+                        'y' => {
+                            if self.buffer_len < 3 {
+                                None // We need more buffer
+                            } else {
+                                match self.buffer[2] as char {
+                                    '5' => Some("\x1b[?25h".to_string()), // Cursor on
+                                     _  => Some("".to_string())           // Unknown, ignore
+                                }
+                            }
+                        },
+                        'z' => { // Reset terminal
+                            self.graphics = false;
+                            Some("\x1b[0m\x1b[4l\x1b[?25h".to_string())
+                        },
+                        _  => Some("".to_string()) // Unknown, ignore
+                    }
+                }
+            },
+            ch if self.graphics && (GRAPHICS_FIRST..127).contains(&ch) => {
+                Some(GRAPHICS[(ch - GRAPHICS_FIRST) as usize].to_string())
+            },
+            _ => Some(format!("{}", self.buffer[0] as char)) // Write the char
+        }
+    }
+}
+
+impl TerminalEmulator for Vt52ToAnsi {
+    fn translate(&mut self, ch: u8) -> Option<String> {
+        self.buffer[self.buffer_len] = ch & 0x7f; // Only 7 bits ASCII
+        self.buffer_len += 1;
+
+        let conversion = self.conversion();
+        if conversion.is_some() {
+            self.buffer_len = 0;
+        }
+        conversion
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate_all(emulator: &mut Vt52ToAnsi, input: &[u8]) -> String {
+        input.iter().filter_map(|&ch| emulator.translate(ch)).collect()
+    }
+
+    #[test]
+    fn test_plain_text_and_controls() {
+        let mut emulator = Vt52ToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"Hello\r\n\x07\x08"), "Hello\r\n\x07\x08");
+    }
+
+    #[test]
+    fn test_cursor_movement() {
+        let mut emulator = Vt52ToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1bA\x1bB\x1bC\x1bD\x1bH\x1bI"),
+            "\x1b[A\x1b[B\x1b[C\x1b[D\x1b[H\x1bM");
+    }
+
+    #[test]
+    fn test_cursor_address() {
+        let mut emulator = Vt52ToAnsi::new();
+        assert_eq!(emulator.translate(27), None);
+        assert_eq!(emulator.translate(b'Y'), None);
+        assert_eq!(emulator.translate(32 + 4), None);
+        assert_eq!(emulator.translate(32 + 10), Some("\x1b[5;11H".to_string()));
+        // Invalid coordinates are ignored
+        assert_eq!(translate_all(&mut emulator, b"\x1bY\x10\x10X"), "X");
+    }
+
+    #[test]
+    fn test_erase() {
+        let mut emulator = Vt52ToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1bJ\x1bK\x1bE\x1bb\x1bl\x1bo"),
+            "\x1b[J\x1b[K\x1b[2J\x1b[H\x1b[1J\x1b[2K\x1b[1K");
+    }
+
+    #[test]
+    fn test_insert_and_delete() {
+        let mut emulator = Vt52ToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1bL\x1bM\x1bN\x1b@\x1bO"),
+            "\x1b[L\x1b[M\x1b[P\x1b[4h\x1b[4l");
+    }
+
+    #[test]
+    fn test_reverse_video_and_modes() {
+        let mut emulator = Vt52ToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1bpX\x1bq"), "\x1b[7mX\x1b[27m");
+        assert_eq!(translate_all(&mut emulator, b"\x1bx5\x1by5\x1bx1"), "\x1b[?25l\x1b[?25h");
+        assert_eq!(translate_all(&mut emulator, b"\x1bj\x1bk"), "\x1b7\x1b8");
+    }
+
+    #[test]
+    fn test_graphics_mode() {
+        let mut emulator = Vt52ToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"a\x1bFafc^~Z\x1bGa"), "a─┌┐●¶Za");
+        // Reset exits graphics mode
+        assert_eq!(translate_all(&mut emulator, b"\x1bF\x1bza"), "\x1b[0m\x1b[4l\x1b[?25ha");
+    }
+
+    #[test]
+    fn test_unknown_sequences_are_ignored() {
+        let mut emulator = Vt52ToAnsi::new();
+        assert_eq!(translate_all(&mut emulator, b"\x1bZ\x1b=\x1b>\x1b!X"), "X");
+    }
+}
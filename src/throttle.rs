@@ -0,0 +1,101 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+Speed throttling to a clock rate.
+
+We count the T-states of the instructions executed and compare the time they
+would take on the real CPU with the wall clock time. Every time slice we
+sleep the difference. The base for the comparison moves with every slice, so
+the rounding errors of the sleeps don't accumulate.
+
+The time spent on the host by the BDOS and BIOS calls is excluded. A program
+waiting for a key, with blocking reads or polling the console status, doesn't
+count as time emulated and we won't run faster afterwards to catch up. If we
+fall behind anyway, because the host is too slow, we don't try to recover
+more than MAX_LAG.
+
+Without a clock rate the emulation is not paced and we just measure the
+effective speed to report it at exit.
+*/
+
+const SLICE: Duration = Duration::from_millis(5);
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+pub struct Throttle {
+    cycles_per_second: Option<f64>,
+    slice_cycles: u64,
+    base_time: Instant,
+    base_cycles: u64,
+    start_time: Instant,
+    start_cycles: u64,
+    host_time: Duration,
+}
+
+impl Throttle {
+    /// Creates a throttle for the speed in MHz, or unthrottled with None.
+    pub fn new(mhz: Option<f64>, cycles: u64) -> Throttle {
+        let cycles_per_second = mhz.map(|mhz| mhz * 1_000_000.0);
+        let slice_cycles = match cycles_per_second {
+            Some(cps) => ((cps * SLICE.as_secs_f64()) as u64).max(1),
+            None => u64::MAX,
+        };
+        let now = Instant::now();
+        Throttle {
+            cycles_per_second,
+            slice_cycles,
+            base_time: now,
+            base_cycles: cycles,
+            start_time: now,
+            start_cycles: cycles,
+            host_time: Duration::ZERO,
+        }
+    }
+
+    /// Call after every instruction with the CPU cycle count.
+    pub fn pace(&mut self, cycles: u64) {
+        let elapsed_cycles = cycles.wrapping_sub(self.base_cycles);
+        if elapsed_cycles < self.slice_cycles {
+            return;
+        }
+        let cycles_per_second = match self.cycles_per_second {
+            Some(cps) => cps,
+            None => return,
+        };
+
+        let emulated = Duration::from_secs_f64(elapsed_cycles as f64 / cycles_per_second);
+        let real = self.base_time.elapsed();
+        if emulated > real {
+            thread::sleep(emulated - real);
+            self.base_time += emulated;
+        } else if real - emulated > MAX_LAG {
+            // Too slow, forget about the lost time
+            self.base_time = Instant::now();
+        } else {
+            self.base_time += emulated;
+        }
+        self.base_cycles = cycles;
+    }
+
+    /// Excludes the time spent on the host.
+    pub fn host_time(&mut self, time: Duration) {
+        self.base_time += time;
+        self.host_time += time;
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.cycles_per_second.is_some()
+    }
+
+    pub fn report(&self, cycles: u64) -> String {
+        let cycles = cycles.wrapping_sub(self.start_cycles);
+        let seconds = self.start_time.elapsed().saturating_sub(self.host_time).as_secs_f64();
+        let mhz = if seconds > 0.0 {
+            cycles as f64 / seconds / 1_000_000.0
+        } else {
+            0.0
+        };
+        format!("Effective speed: {:.2} MHz ({} T-states in {:.3}s, excluding {:.3}s on the host)",
+            mhz, cycles, seconds, self.host_time.as_secs_f64())
+    }
+}
@@ -0,0 +1,10 @@
+mod common;
+use common::*;
+
+#[test]
+fn test_bank_port_and_xmove() {
+    run_script_with_args(vec!(
+        Step::Expect("BANKS AB"),
+        ), vec!("--banks", "2", "--bank-port", "40", "tests/artifacts/banks.com")
+    );
+}
@@ -0,0 +1,75 @@
+mod common;
+use common::*;
+use izcpm::{CallKind, CallTraceEvent, ConsoleTest, RunOptions, Step};
+
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+#[test]
+fn test_call_trace_callback() {
+    let events: Rc<RefCell<Vec<CallTraceEvent>>> = Rc::new(RefCell::new(Vec::new()));
+    let events_callback = events.clone();
+    let mut options = RunOptions::new();
+    options.call_trace_callback = Some(Box::new(move |event: &CallTraceEvent| {
+        events_callback.borrow_mut().push(event.clone());
+    }));
+
+    let mut console = ConsoleTest::new(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:\r"),
+        Step::Expect("B>"),
+        Step::Input("ret 3\r"),
+        Step::Expect("B>"),
+    ));
+    izcpm::run_with_options(Some(vec!("-b", "tests/artifacts",
+        "--call-trace-function", "F_OPEN",
+        "--call-trace-function", "20",
+        "--call-trace-file", "*.com")), &mut console, options);
+
+    let events = events.borrow();
+    assert!(!events.is_empty());
+    assert!(events.iter().all(|e| e.kind == CallKind::Bdos && (e.function == 15 || e.function == 20)));
+    let open = events.iter().find(|e| e.function == 15).unwrap();
+    assert_eq!(open.drive, Some(1));
+    assert_eq!(open.file.as_deref(), Some("RET     .COM"));
+    assert_eq!(open.result, Some(0));
+    let read = events.iter().find(|e| e.function == 20).unwrap();
+    assert_eq!(read.record, Some(0));
+    assert_eq!(read.dma, Some(0x0100));
+}
+
+#[test]
+fn test_call_trace_file() {
+    let dir = TempDir::new("call_trace");
+    let trace = dir.join("trace.json");
+
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:\r"),
+        Step::Expect("B>"),
+        Step::Input("ret 1\r"),
+        Step::Expect("B>"),
+        ), vec!("-b", "tests/artifacts",
+            "--call-trace-output", trace.to_str().unwrap(),
+            "--call-trace-bios",
+            "--call-trace-function", "DRV_SET",
+            "--call-trace-function", "WBOOT")
+    );
+
+    let trace = fs::read_to_string(&trace).unwrap();
+    assert!(trace.lines().all(|line| line.starts_with("{\"time_us\":") && line.ends_with('}')));
+    assert!(trace.contains("\"type\":\"bdos\",\"function\":14,\"name\":\"DRV_SET\",\"de\":1,"));
+    assert!(trace.contains("\"drive\":\"B\""));
+    assert!(trace.contains("\"type\":\"bios\",\"function\":1,\"name\":\"WBOOT\""));
+    assert!(!trace.contains("C_WRITE"));
+}
+
+#[test]
+fn test_call_trace_not_on_messages() {
+    let (messages, traces) = run_with_messages(vec!("-T", "tests/artifacts/halt.com"));
+
+    assert!(traces.contains("[[BDOS command 12: S_BDOSVER(0000)]]"));
+    assert!(!messages.contains("[["));
+    assert!(messages.contains("HALT instruction at 0107"));
+}
@@ -0,0 +1,30 @@
+use izcpm::{ConsoleTest, Step};
+
+fn boot_ccp(args: Vec<&str>, expected: &str) {
+    let mut console = ConsoleTest::new(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:TPATOP\r"),
+        Step::Expect(expected),
+    ));
+    let mut args = args;
+    args.extend(["-b", "tests/artifacts"]);
+    izcpm::run(Some(args), &mut console);
+    assert!(console.screen().snapshot().contains(expected), "{}", console.screen().snapshot());
+}
+
+#[test]
+fn test_prl_ccp() {
+    boot_ccp(vec!("--tpa-top", "48K", "--ccp", "tests/artifacts/zcpr.prl"), "TOP B8BF");
+}
+
+#[test]
+fn test_ccp_pair() {
+    boot_ccp(vec!("--tpa-top", "56K", "--ccp", "third-party/bin/zcpr.bin",
+        "--ccp-pair", "tests/artifacts/zcpr_e000.bin@e000"), "TOP D8DF");
+}
+
+#[test]
+fn test_ccp_with_bitmap() {
+    boot_ccp(vec!("--tpa-top", "62K", "--ccp", "tests/artifacts/zcpr_0000.bin@0000",
+        "--ccp-bitmap", "tests/artifacts/zcpr_0000.bin"), "TOP F0F7");
+}
@@ -0,0 +1,41 @@
+use izcpm::{parse_command_line, Mode};
+
+fn args(text: &str) -> Vec<String> {
+    text.split_whitespace().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn test_listen_with_equals() {
+    match parse_command_line(args("--listen=127.0.0.1:2323 --session-dir=users -b dir")) {
+        Ok(Mode::Listen{address, session_dir, max_sessions, args: others}) => {
+            assert_eq!(address, "127.0.0.1:2323");
+            assert_eq!(session_dir.as_deref(), Some("users"));
+            assert_eq!(max_sessions, 16);
+            assert_eq!(others, args("-b dir"));
+        },
+        _ => panic!("Not a telnet server"),
+    }
+}
+
+#[test]
+fn test_script_with_separate_value() {
+    match parse_command_line(args("-b dir --script test.txt prog.com")) {
+        Ok(Mode::Script(script, others)) => {
+            assert_eq!(script, "test.txt");
+            assert_eq!(others, args("-b dir prog.com"));
+        },
+        _ => panic!("Not a script"),
+    }
+}
+
+#[test]
+fn test_console() {
+    assert!(matches!(parse_command_line(args("-b dir prog.com")), Ok(Mode::Console(_))));
+}
+
+#[test]
+fn test_invalid_options() {
+    assert!(matches!(parse_command_line(args("--script a.txt --listen :23")), Err(1)));
+    assert!(matches!(parse_command_line(args("--listen :23 --max-sessions 0")), Err(1)));
+    assert!(matches!(parse_command_line(args("--max-sessions 4")), Err(1)));
+}
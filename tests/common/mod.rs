@@ -1,5 +1,10 @@
 
-use izcpm::ConsoleTest;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use izcpm::{ConsoleEmulator, ConsoleTest};
 pub use izcpm::Step as Step;
 
 #[allow(dead_code)]
@@ -13,3 +18,84 @@ pub fn run_script_with_args(script: Vec<Step>, args: Vec<&str>) {
     let mut console = ConsoleTest::new(script);
     izcpm::run(Some(args), &mut console);
 }
+
+/// Runs the steps, then the program until it ends. Returns the exit code and
+/// the screen.
+#[allow(dead_code)]
+pub fn run_to_end(script: Vec<Step>, args: Vec<&str>) -> (i32, String) {
+    let mut console = ConsoleTest::new(script);
+    console.set_stop_when_done(false);
+    let exit_code = izcpm::run(Some(args), &mut console);
+    (exit_code, console.screen().snapshot())
+}
+
+/// As run_to_end(), the screen has to contain the texts. Returns the exit
+/// code.
+#[allow(dead_code)]
+pub fn run_and_check(script: Vec<Step>, args: Vec<&str>, expected: &[&str]) -> i32 {
+    let (exit_code, screen) = run_to_end(script, args);
+    for text in expected {
+        assert!(screen.contains(text), "{}", screen);
+    }
+    exit_code
+}
+
+// Keeps the messages and the traces apart
+struct TraceConsole<'a> {
+    console: ConsoleTest<'a>,
+    messages: String,
+    traces: String,
+}
+
+impl ConsoleEmulator for TraceConsole<'_> {
+    fn status(&mut self) -> bool { self.console.status() }
+    fn read(&mut self) -> u8 { self.console.read() }
+    fn put(&mut self, sequence: Option<String>) { self.console.put(sequence) }
+    fn terminated(&self) -> bool { self.console.terminated() }
+    fn message(&mut self, text: &str) { self.messages += text; }
+    fn trace(&mut self, text: &str) { self.traces += text; }
+}
+
+/// Runs the program until it ends. Returns the messages and the traces.
+#[allow(dead_code)]
+pub fn run_with_messages(args: Vec<&str>) -> (String, String) {
+    let mut console = TraceConsole {
+        console: ConsoleTest::new(vec!()),
+        messages: String::new(),
+        traces: String::new(),
+    };
+    console.console.set_stop_when_done(false);
+    izcpm::run(Some(args), &mut console);
+    (console.messages, console.traces)
+}
+
+/// A directory for the files of a test, in the temp directory with the name
+/// and the process id. It is removed when dropped.
+#[allow(dead_code)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+#[allow(dead_code)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("izcpm_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,50 @@
+mod common;
+use common::*;
+
+use std::fs;
+
+#[test]
+fn test_crash_report_on_halt() {
+    let dir = TempDir::new("crash_report");
+    let report = dir.join("crash.txt");
+
+    run_to_end(vec!(), vec!("tests/artifacts/halt.com", "data.txt",
+            "--history", "4",
+            "--crash-report", report.to_str().unwrap())
+    );
+
+    let report = fs::read_to_string(&report).unwrap();
+    assert!(report.contains("Reason: HALT instruction at 0107"));
+    assert!(report.contains("Last 4 instructions:"));
+    assert!(report.contains("  0107: 76          HALT"));
+    assert!(!report.contains("LD A, 42h")); // Out of the history
+    assert!(report.contains("S_BDOSVER    DE:0000 from 0104 => 0022"));
+    assert!(report.contains("  0000: c3 03 ff"));
+    assert!(report.contains("FCB1 at 005c: dr:01 name:\"DATA    .TXT\""));
+}
+
+#[test]
+fn test_crash_report_default_history() {
+    let dir = TempDir::new("crash_report_default");
+    let report = dir.join("crash.txt");
+
+    run_to_end(vec!(), vec!("tests/artifacts/halt.com",
+            "--crash-report", report.to_str().unwrap())
+    );
+
+    // With the file and no --history, the default history is kept
+    let report = fs::read_to_string(&report).unwrap();
+    assert!(report.contains("Reason: HALT instruction at 0107"));
+    assert!(report.contains("LD A, 42h"));
+}
+
+#[test]
+fn test_halt_message_with_report_file() {
+    let dir = TempDir::new("crash_report_message");
+    let report = dir.join("crash.txt");
+
+    // The report goes to the file, the HALT line to the console
+    let (messages, _) = run_with_messages(vec!("tests/artifacts/halt.com",
+        "--crash-report", report.to_str().unwrap()));
+    assert!(messages.contains("HALT instruction at 0107"));
+}
@@ -0,0 +1,64 @@
+mod common;
+use common::*;
+use izcpm::{ConsoleTest, Device, RunOptions};
+
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+#[test]
+fn test_value_and_printer_devices() {
+    let dir = TempDir::new("printer");
+    let printer = dir.join("printer.txt");
+    let printer_spec = format!("printer:12:{}", printer.to_str().unwrap());
+
+    run_to_end(vec!(), vec!("--device", "value:10-11:41", "--device", &printer_spec,
+            "tests/artifacts/ports.com")
+    );
+
+    assert_eq!(fs::read(&printer).unwrap(), b"A\r");
+}
+
+struct PortLog {
+    accesses: Rc<RefCell<Vec<String>>>,
+}
+
+impl Device for PortLog {
+    fn name(&self) -> String {
+        "port log".to_string()
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        self.accesses.borrow_mut().push(format!("IN {:02x}", port));
+        0x55
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        self.accesses.borrow_mut().push(format!("OUT {:02x} {:02x}", port, value));
+    }
+}
+
+#[test]
+fn test_library_device() {
+    let accesses = Rc::new(RefCell::new(Vec::new()));
+    let mut options = RunOptions::new();
+    options.add_device(0x10, 0x30, Box::new(PortLog { accesses: accesses.clone() }));
+
+    let mut console = ConsoleTest::new(vec!());
+    console.set_stop_when_done(false);
+    izcpm::run_with_options(Some(vec!("tests/artifacts/ports.com")), &mut console, options);
+
+    assert_eq!(*accesses.borrow(), vec!("IN 10", "OUT 12 55", "OUT 12 0d", "IN 30"));
+}
+
+#[test]
+fn test_conflicting_devices() {
+    let dir = TempDir::new("conflict");
+    let printer = dir.join("printer.txt");
+    let printer_spec = format!("printer:12:{}", printer.to_str().unwrap());
+    // The run doesn't start, the program would write to the printer
+    run_to_end(vec!(), vec!("--device", &printer_spec, "--device", "clock:10",
+            "tests/artifacts/ports.com")
+    );
+    assert_eq!(fs::read(&printer).unwrap(), b"");
+}
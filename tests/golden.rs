@@ -0,0 +1,200 @@
+mod common;
+use common::*;
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use izcpm::ConsoleEmulator;
+
+/*
+Golden output regression suite. Every directory in tests/golden/cases is a
+case with:
+
+    args                 Optional, the emulator arguments. A program name
+                         ending in .com is taken from tests/golden/programs.
+                         Without a program, the CCP is run.
+    input                Optional, the keys typed, LF is sent as CR. They
+                         are given when the program reads them.
+    drive/               Optional, the files on A: at the start.
+    expected_output.txt  The console output, without escape sequences.
+    expected_files/      The files on A: at the end.
+
+The programs are assembled with the bundled ZMAC, see
+tests/golden/programs/build.sh. B: is mapped to the programs directory.
+
+When the input is exhausted the run ends. To write the results as the new
+goldens, run with IZCPM_BLESS=1:
+
+    IZCPM_BLESS=1 cargo test --test golden
+*/
+
+const GOLDEN_DIR: &str = "tests/golden";
+const MAX_INSTRUCTIONS: u64 = 50_000_000;
+
+struct HeadlessConsole {
+    input: VecDeque<u8>,
+    instructions: u64,
+    done: bool,
+}
+
+impl HeadlessConsole {
+    fn new(input: &str) -> HeadlessConsole {
+        HeadlessConsole {
+            input: input.replace('\n', "\r").bytes().collect(),
+            instructions: 0,
+            done: false,
+        }
+    }
+}
+
+impl ConsoleEmulator for HeadlessConsole {
+    fn status(&mut self) -> bool {
+        // No type ahead, the keys are given when the program reads. The
+        // CCP would take them to stop the listings.
+        false
+    }
+
+    fn read(&mut self) -> u8 {
+        match self.input.pop_front() {
+            Some(ch) => ch,
+            None => {
+                self.done = true;
+                b'\r'
+            }
+        }
+    }
+
+    fn put(&mut self, _sequence: Option<String>) {}
+
+    fn terminated(&self) -> bool {
+        self.done
+    }
+
+    fn tick(&mut self, instructions: u32) {
+        self.instructions += instructions as u64;
+        if self.instructions > MAX_INSTRUCTIONS {
+            self.done = true;
+        }
+    }
+}
+
+fn read_optional(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_default()
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    if let Ok(entries) = fs::read_dir(from) {
+        for entry in entries {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+fn sorted_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries.map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut text = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        let e = expected.get(i);
+        let a = actual.get(i);
+        if e != a {
+            if let Some(e) = e {
+                text += &format!("    {:4} - {}\n", i + 1, e);
+            }
+            if let Some(a) = a {
+                text += &format!("    {:4} + {}\n", i + 1, a);
+            }
+        }
+    }
+    text
+}
+
+fn run_case(case: &Path, work: &Path, bless: bool) -> Option<String> {
+    let programs = Path::new(GOLDEN_DIR).join("programs");
+    let drive = work.join("drive");
+    let transcript = work.join("output.txt");
+    let _ = fs::remove_dir_all(work);
+    copy_dir(&case.join("drive"), &drive);
+
+    let args_text = read_optional(&case.join("args"));
+    let mut args: Vec<String> = vec!(
+        "-a".to_string(), drive.to_string_lossy().to_string(),
+        "-b".to_string(), programs.to_string_lossy().to_string(),
+        "--transcript".to_string(), transcript.to_string_lossy().to_string());
+    for arg in args_text.split_whitespace() {
+        if arg.to_lowercase().ends_with(".com") {
+            args.push(programs.join(arg).to_string_lossy().to_string());
+        } else {
+            args.push(arg.to_string());
+        }
+    }
+
+    let mut console = HeadlessConsole::new(&read_optional(&case.join("input")));
+    izcpm::run(Some(args.iter().map(|arg| arg.as_str()).collect()), &mut console);
+    let output = read_optional(&transcript);
+
+    let expected_output = case.join("expected_output.txt");
+    let expected_files = case.join("expected_files");
+    if bless {
+        fs::write(&expected_output, &output).unwrap();
+        let _ = fs::remove_dir_all(&expected_files);
+        copy_dir(&drive, &expected_files);
+        return None;
+    }
+
+    let mut failure = String::new();
+    let expected = read_optional(&expected_output);
+    if expected != output {
+        failure += &format!("  Output differs:\n{}", diff(&expected, &output));
+    }
+    let names = sorted_files(&drive);
+    let expected_names = sorted_files(&expected_files);
+    if names != expected_names {
+        failure += &format!("  Files on A: {:?}, expected {:?}\n", names, expected_names);
+    } else {
+        for name in names {
+            if fs::read(drive.join(&name)).unwrap() != fs::read(expected_files.join(&name)).unwrap() {
+                failure += &format!("  File {} differs\n", name);
+            }
+        }
+    }
+    if failure.is_empty() {
+        None
+    } else {
+        Some(failure)
+    }
+}
+
+#[test]
+fn test_golden() {
+    let bless = env::var("IZCPM_BLESS").is_ok_and(|value| value == "1");
+    let work_base = TempDir::new("golden");
+    let mut cases: Vec<PathBuf> = fs::read_dir(Path::new(GOLDEN_DIR).join("cases")).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    cases.sort();
+    assert!(!cases.is_empty());
+
+    let mut failures = String::new();
+    for case in &cases {
+        let name = case.file_name().unwrap().to_string_lossy().to_string();
+        if let Some(failure) = run_case(case, &work_base.join(&name), bless) {
+            failures += &format!("Case {}:\n{}", name, failure);
+        }
+    }
+    assert!(failures.is_empty(), "Golden cases failed:\n{}", failures);
+}
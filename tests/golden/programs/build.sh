@@ -0,0 +1,8 @@
+#!/bin/sh
+# Assembles the golden test programs with the bundled ZMAC, build it first
+# with third-party/build_zcpr.sh
+ZMAC=../../../third-party/zmac/zmac
+for program in random randseq sequent rename delete; do
+    $ZMAC -o $program.cim $program.asm || exit 1
+    mv $program.cim $program.com
+done
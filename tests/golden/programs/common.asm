@@ -0,0 +1,76 @@
+; Routines shared by the golden test programs
+
+bdos    equ 5
+dma     equ 80h
+
+; Prints the $ terminated string at DE
+print:
+        ld c, 9
+        jp bdos
+
+; Prints a new line
+crlf:
+        ld de, crlfmsg
+        jp print
+crlfmsg:
+        db 13, 10, '$'
+
+; Prints A as two hex digits
+hex8:
+        push af
+        rrca
+        rrca
+        rrca
+        rrca
+        call hex4
+        pop af
+hex4:
+        and 0fh
+        add a, 90h
+        daa
+        adc a, 40h
+        daa
+        ld e, a
+        ld c, 2
+        jp bdos
+
+; Prints HL as four hex digits
+hex16:
+        push hl
+        ld a, h
+        call hex8
+        pop hl
+        ld a, l
+        jp hex8
+
+; Prints the message at DE, then A in hex and a new line
+result:
+        push af
+        call print
+        pop af
+        call hex8
+        jp crlf
+
+; Clears the FCB at HL from the extent to the random record
+clearfcb:
+        ld de, 12
+        add hl, de
+        ld b, 24
+        xor a
+clear1:
+        ld (hl), a
+        inc hl
+        dec b
+        jp nz, clear1
+        ret
+
+; Fills the DMA buffer with A
+filldma:
+        ld hl, dma
+        ld b, 128
+fill1:
+        ld (hl), a
+        inc hl
+        dec b
+        jp nz, fill1
+        ret
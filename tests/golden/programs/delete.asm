@@ -0,0 +1,62 @@
+; Delete with wildcards: the drive has DEL1.TMP, DEL2.TMP, DEL10.TMP and
+; KEEP.DAT. Deletes DEL?.TMP and counts the files left for some patterns.
+
+        org 100h
+
+start:
+        ld de, pattern1
+        ld c, 19                ; F_DELETE
+        call bdos
+        ld de, msgdelete
+        call result
+        ld de, pattern1
+        ld c, 19                ; F_DELETE
+        call bdos
+        ld de, msgagain
+        call result
+
+        ld de, pattern1
+        ld hl, msgcount1
+        call count
+        ld de, pattern2
+        ld hl, msgcount2
+        call count
+        ld de, pattern3
+        ld hl, msgcount3
+        call count
+        jp 0
+
+; Counts the files matching the FCB at DE, prints the message at HL
+count:
+        push hl
+        ld c, 17                ; F_SFIRST
+        call bdos
+        ld b, 0
+count1:
+        cp 0ffh
+        jp z, count2
+        inc b
+        push bc
+        ld c, 18                ; F_SNEXT
+        call bdos
+        pop bc
+        jp count1
+count2:
+        pop de
+        ld a, b
+        jp result
+
+msgdelete:      db 'Delete DEL?.TMP: $'
+msgagain:       db 'Delete DEL?.TMP again: $'
+msgcount1:      db 'Files DEL?.TMP: $'
+msgcount2:      db 'Files *.TMP: $'
+msgcount3:      db 'Files *.*: $'
+
+pattern1:       db 0, 'DEL?    TMP', 0, 0, 0, 0
+                ds 20
+pattern2:       db 0, '????????TMP', 0, 0, 0, 0
+                ds 20
+pattern3:       db 0, '???????????', 0, 0, 0, 0
+                ds 20
+
+        include common.asm
@@ -0,0 +1,111 @@
+; Random access: writes records 0, 5 and 200, the last one on the second
+; extent, reads them back, reads a hole and past the end, and computes the
+; file size.
+
+        org 100h
+
+start:
+        ld hl, fcb
+        call clearfcb
+        ld de, fcb
+        ld c, 19                ; F_DELETE
+        call bdos
+        ld hl, fcb
+        call clearfcb
+        ld de, fcb
+        ld c, 22                ; F_MAKE
+        call bdos
+        ld de, msgmake
+        call result
+
+        ld hl, 0
+        call writerec
+        ld hl, 5
+        call writerec
+        ld hl, 200
+        call writerec
+        ld de, fcb
+        ld c, 16                ; F_CLOSE
+        call bdos
+        ld de, msgclose
+        call result
+
+        ld hl, fcb
+        call clearfcb
+        ld de, fcb
+        ld c, 15                ; F_OPEN
+        call bdos
+        ld de, msgopen
+        call result
+
+        ld hl, 5
+        call readrec
+        ld hl, 200
+        call readrec
+        ld hl, 3
+        call readrec
+        ld hl, 300
+        call readrec
+
+        ld de, fcb
+        ld c, 35                ; F_SIZE
+        call bdos
+        ld de, msgsize
+        call print
+        ld hl, (fcb+33)
+        call hex16
+        call crlf
+
+        ld de, fcb
+        ld c, 16                ; F_CLOSE
+        call bdos
+        jp 0
+
+; Writes the record HL filled with its number
+writerec:
+        ld (fcb+33), hl
+        xor a
+        ld (fcb+35), a
+        ld a, l
+        call filldma
+        ld de, fcb
+        ld c, 34                ; F_WRITERAND
+        call bdos
+        ld de, msgwrite
+        jp result
+
+; Reads the record HL and prints the result and the first byte
+readrec:
+        ld (fcb+33), hl
+        push hl
+        xor a
+        ld (fcb+35), a
+        call filldma
+        ld de, msgread
+        call print
+        pop hl
+        call hex16
+        ld de, msgsep
+        call print
+        ld de, fcb
+        ld c, 33                ; F_READRAND
+        call bdos
+        call hex8
+        ld de, msgsep
+        call print
+        ld a, (dma)
+        call hex8
+        jp crlf
+
+msgmake:        db 'Make: $'
+msgwrite:       db 'Write: $'
+msgclose:       db 'Close: $'
+msgopen:        db 'Open: $'
+msgread:        db 'Read $'
+msgsep:         db ' $'
+msgsize:        db 'Size: $'
+
+        include common.asm
+
+fcb:    db 0, 'RANDOM  DAT'
+        ds 24
@@ -0,0 +1,169 @@
+; Random and sequential access mixed: the random read and write set the
+; extent and current record, so the sequential operations continue there.
+; The failed random operations leave the FCB untouched.
+
+        org 100h
+
+start:
+        ld hl, fcb
+        call clearfcb
+        ld de, fcb
+        ld c, 19                ; F_DELETE
+        call bdos
+        ld hl, fcb
+        call clearfcb
+        ld de, fcb
+        ld c, 22                ; F_MAKE
+        call bdos
+        ld de, msgmake
+        call result
+
+        ; Random write on the second extent, then two sequential writes,
+        ; the first one rewrites the same record
+        ld hl, 200
+        ld (fcb+33), hl
+        ld a, 1
+        call filldma
+        ld de, fcb
+        ld c, 34                ; F_WRITERAND
+        call bdos
+        ld de, msgwrand
+        call result
+        ld hl, fcb
+        call showfcb
+        ld a, 2
+        call filldma
+        ld de, fcb
+        ld c, 21                ; F_WRITE
+        call bdos
+        ld de, msgwrite
+        call result
+        ld a, 3
+        call filldma
+        ld de, fcb
+        ld c, 21                ; F_WRITE
+        call bdos
+        ld de, msgwrite
+        call result
+        ld hl, fcb
+        call showfcb
+        ld de, fcb
+        ld c, 16                ; F_CLOSE
+        call bdos
+        ld de, msgclose
+        call result
+
+        ; Random read, then two sequential reads, the first one rereads
+        ; the same record
+        ld hl, fcb
+        call clearfcb
+        ld de, fcb
+        ld c, 15                ; F_OPEN
+        call bdos
+        ld de, msgopen
+        call result
+        ld hl, 200
+        ld (fcb+33), hl
+        ld de, fcb
+        ld c, 33                ; F_READRAND
+        call bdos
+        ld de, msgrrand
+        call readres
+        ld hl, fcb
+        call showfcb
+        ld de, fcb
+        ld c, 20                ; F_READ
+        call bdos
+        ld de, msgread
+        call readres
+        ld de, fcb
+        ld c, 20                ; F_READ
+        call bdos
+        ld de, msgread
+        call readres
+
+        ; Random read past the end
+        ld hl, 300
+        ld (fcb+33), hl
+        ld de, fcb
+        ld c, 33                ; F_READRAND
+        call bdos
+        ld de, msgrrand
+        call result
+        ld hl, fcb
+        call showfcb
+        ld de, fcb
+        ld c, 16                ; F_CLOSE
+        call bdos
+
+        ; Random write on a file not created
+        ld hl, missing
+        call clearfcb
+        ld hl, 300
+        ld (missing+33), hl
+        ld de, missing
+        ld c, 34                ; F_WRITERAND
+        call bdos
+        ld de, msgwrand
+        call result
+        ld hl, missing
+        call showfcb
+        jp 0
+
+; Prints the result in A and the first byte read
+readres:
+        push af
+        call print
+        pop af
+        call hex8
+        ld de, msgsep
+        call print
+        ld a, (dma)
+        call hex8
+        jp crlf
+
+; Prints the extent, current record and record count of the FCB at HL
+showfcb:
+        push hl
+        ld de, msgfcb
+        call print
+        pop hl
+        push hl
+        ld de, 12
+        add hl, de
+        ld a, (hl)
+        call hex8
+        ld de, msgsep
+        call print
+        pop hl
+        push hl
+        ld de, 32
+        add hl, de
+        ld a, (hl)
+        call hex8
+        ld de, msgsep
+        call print
+        pop hl
+        ld de, 15
+        add hl, de
+        ld a, (hl)
+        call hex8
+        jp crlf
+
+msgmake:        db 'Make: $'
+msgwrand:       db 'Write random: $'
+msgwrite:       db 'Write: $'
+msgclose:       db 'Close: $'
+msgopen:        db 'Open: $'
+msgrrand:       db 'Read random: $'
+msgread:        db 'Read: $'
+msgfcb:         db 'FCB ex cr rc: $'
+msgsep:         db ' $'
+
+        include common.asm
+
+fcb:    db 0, 'RANDSEQ DAT'
+        ds 24
+missing:
+        db 0, 'MISSING DAT'
+        ds 24
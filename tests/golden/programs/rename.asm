@@ -0,0 +1,68 @@
+; Rename: renames OLD.TXT to NEW.TXT, checks that only the new name opens,
+; and renames a missing file.
+
+        org 100h
+
+start:
+        ld hl, fcbold
+        call clearfcb
+        ld de, fcbold
+        ld c, 22                ; F_MAKE
+        call bdos
+        ld de, msgmake
+        call result
+        ld a, 'R'
+        call filldma
+        ld de, fcbold
+        ld c, 21                ; F_WRITE
+        call bdos
+        ld de, fcbold
+        ld c, 16                ; F_CLOSE
+        call bdos
+
+        ld de, rename1
+        ld c, 23                ; F_RENAME
+        call bdos
+        ld de, msgrename
+        call result
+
+        ld hl, fcbold
+        call clearfcb
+        ld de, fcbold
+        ld c, 15                ; F_OPEN
+        call bdos
+        ld de, msgopenold
+        call result
+
+        ld hl, fcbnew
+        call clearfcb
+        ld de, fcbnew
+        ld c, 15                ; F_OPEN
+        call bdos
+        ld de, msgopennew
+        call result
+
+        ld de, rename2
+        ld c, 23                ; F_RENAME
+        call bdos
+        ld de, msgmissing
+        call result
+        jp 0
+
+msgmake:        db 'Make OLD.TXT: $'
+msgrename:      db 'Rename to NEW.TXT: $'
+msgopenold:     db 'Open OLD.TXT: $'
+msgopennew:     db 'Open NEW.TXT: $'
+msgmissing:     db 'Rename MISSING.TXT: $'
+
+rename1:        db 0, 'OLD     TXT', 0, 0, 0, 0
+                db 0, 'NEW     TXT', 0, 0, 0, 0
+rename2:        db 0, 'MISSING TXT', 0, 0, 0, 0
+                db 0, 'OTHER   TXT', 0, 0, 0, 0
+
+        include common.asm
+
+fcbold: db 0, 'OLD     TXT'
+        ds 24
+fcbnew: db 0, 'NEW     TXT'
+        ds 24
@@ -0,0 +1,117 @@
+; Sequential access over extents: writes 300 records, three extents, and
+; reads them back checking the content.
+
+        org 100h
+
+start:
+        ld hl, fcb
+        call clearfcb
+        ld de, fcb
+        ld c, 19                ; F_DELETE
+        call bdos
+        ld hl, fcb
+        call clearfcb
+        ld de, fcb
+        ld c, 22                ; F_MAKE
+        call bdos
+        ld de, msgmake
+        call result
+
+        ld hl, 0
+        ld (count), hl
+write1:
+        ld a, (count)
+        call filldma
+        ld de, fcb
+        ld c, 21                ; F_WRITE
+        call bdos
+        or a
+        jp nz, write2
+        ld hl, (count)
+        inc hl
+        ld (count), hl
+        ld de, -300
+        add hl, de
+        ld a, h
+        or l
+        jp nz, write1
+write2:
+        ld de, msgwrite
+        call result
+        ld de, msgcount
+        call print
+        ld hl, (count)
+        call hex16
+        call crlf
+        ld a, (fcb+12)
+        ld de, msgextent
+        call result
+        ld de, fcb
+        ld c, 16                ; F_CLOSE
+        call bdos
+        ld de, msgclose
+        call result
+
+        ld hl, fcb
+        call clearfcb
+        ld de, fcb
+        ld c, 15                ; F_OPEN
+        call bdos
+        ld de, msgopen
+        call result
+        ld a, (fcb+15)
+        ld de, msgrc
+        call result
+
+        ld hl, 0
+        ld (count), hl
+read1:
+        ld de, fcb
+        ld c, 20                ; F_READ
+        call bdos
+        or a
+        jp nz, read2
+        ld a, (count)
+        ld hl, dma+127
+        cp (hl)
+        jp nz, bad
+        ld hl, (count)
+        inc hl
+        ld (count), hl
+        jp read1
+read2:
+        ld de, msgread
+        call result
+        ld de, msgcount
+        call print
+        ld hl, (count)
+        call hex16
+        call crlf
+        ld a, (fcb+12)
+        ld de, msgextent
+        call result
+        jp 0
+
+bad:
+        ld de, msgbad
+        call print
+        ld hl, (count)
+        call hex16
+        call crlf
+        jp 0
+
+msgmake:        db 'Make: $'
+msgwrite:       db 'Last write: $'
+msgread:        db 'Last read: $'
+msgcount:       db 'Records: $'
+msgextent:      db 'Extent: $'
+msgclose:       db 'Close: $'
+msgopen:        db 'Open: $'
+msgrc:          db 'Record count: $'
+msgbad:         db 'Bad content on record $'
+
+        include common.asm
+
+count:  dw 0
+fcb:    db 0, 'SEQUENT DAT'
+        ds 24
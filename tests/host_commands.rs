@@ -0,0 +1,77 @@
+mod common;
+use common::*;
+
+use std::fs;
+
+#[test]
+fn test_exit_with_code() {
+    let exit_code = run_and_check(vec!(
+        Step::Expect("A>"),
+        Step::Input("EXIT 3\r"),
+        ), vec!("-a", "tests/artifacts"), &[]);
+    assert_eq!(exit_code, 3);
+}
+
+#[test]
+fn test_import_and_export() {
+    let dir = TempDir::new("host_commands");
+    let drive = dir.join("drive");
+    fs::create_dir_all(&drive).unwrap();
+    fs::write(dir.join("notes.txt"), b"AB\nCD\n").unwrap();
+    let host_cd = format!("HOSTCD {}\r", dir.path().to_str().unwrap());
+
+    run_and_check(vec!(
+        Step::Expect("A>"),
+        Step::Input(&host_cd),
+        Step::Expect("A>"),
+        Step::Input("HOSTDIR\r"),
+        Step::Expect("A>"),
+        Step::Input("IMPORT NOTES.TXT A:NOTE.TXT [T]\r"),
+        Step::Expect("A>"),
+        Step::Input("EXPORT NOTE.TXT COPY.TXT\r"),
+        Step::Expect("A>"),
+        Step::Input("EXPORT NOTE.TXT BACK.TXT [T]\r"),
+        Step::Expect("A>"),
+        Step::Input("EXPORT MISSING.TXT\r"),
+        Step::Expect("A>"),
+        ), vec!("-a", drive.to_str().unwrap()), &[
+            "drive/\nnotes.txt\n",
+            "EXPORT: File A:MISSING.TXT not found"]);
+
+    assert_eq!(fs::read(drive.join("NOTE.TXT")).unwrap(), b"AB\r\nCD\r\n\x1a");
+    assert_eq!(fs::read(dir.join("copy.txt")).unwrap(), b"AB\r\nCD\r\n\x1a");
+    assert_eq!(fs::read(dir.join("back.txt")).unwrap(), b"AB\nCD\n");
+}
+
+#[test]
+fn test_date_and_mount() {
+    run_and_check(vec!(
+        Step::Expect("A>"),
+        Step::Input("DATE\r"),
+        Step::Expect("UTC"),
+        Step::Expect("A>"),
+        Step::Input("MOUNT C:=TESTS\r"),
+        Step::Expect("A>"),
+        Step::Input("MOUNT\r"),
+        Step::Expect("C: ="),
+        Step::Expect("A>"),
+        ), vec!("-a", "tests/artifacts"), &["C: = tests\n"]);
+}
+
+#[test]
+fn test_host_commands_disabled() {
+    run_and_check(vec!(
+        Step::Expect("A>"),
+        Step::Input("DATE\r"),
+        Step::Expect("A>"),
+        ), vec!("--no-host-commands", "-a", "tests/artifacts"), &["DATE?"]);
+}
+
+#[test]
+fn test_host_functions_disabled() {
+    for program in ["tests/artifacts/hdate.com", "tests/artifacts/mnt.com"] {
+        let (_, screen) = run_to_end(vec!(), vec!("--no-host-commands", program));
+        assert!(!screen.contains("UTC") && !screen.contains("A: ="), "{}", screen);
+    }
+    run_and_check(vec!(), vec!("tests/artifacts/hdate.com"), &["UTC"]);
+}
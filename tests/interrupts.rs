@@ -0,0 +1,37 @@
+mod common;
+use common::*;
+use izcpm::{Step, Timeout};
+
+use std::fs;
+use std::time::Duration;
+
+#[test]
+fn test_timer_interrupts_resume_halt() {
+    run_script_with_args(vec!(
+        Step::SetTimeout(Timeout::WallTime(Duration::from_secs(5))),
+        Step::Expect("5 TICKS"),
+        ), vec!("--timer", "100", "tests/artifacts/timer.com")
+    );
+}
+
+#[test]
+fn test_interrupts_dont_repeat_the_bdos_calls() {
+    let dir = TempDir::new("trapint");
+    let transcript = dir.join("transcript.txt");
+    run_script_with_args(vec!(
+        Step::SetTimeout(Timeout::WallTime(Duration::from_secs(10))),
+        Step::Expect("E"),
+        ), vec!("--timer", "20000", "--transcript", transcript.to_str().unwrap(), "tests/artifacts/trapint.com")
+    );
+    let text = fs::read_to_string(&transcript).unwrap();
+    assert_eq!(text.matches('x').count(), 2000);
+}
+
+#[test]
+fn test_control_c_stops_while_halted() {
+    run_script_with_args(vec!(
+        Step::SetTimeout(Timeout::WallTime(Duration::from_secs(5))),
+        Step::Input("\x03\x03Y"),
+        ), vec!("--timer", "1", "tests/artifacts/eihalt.com")
+    );
+}
@@ -0,0 +1,53 @@
+mod common;
+use common::*;
+use izcpm::Step;
+
+#[test]
+#[cfg(not(windows))] // adm3a by default on Windows
+fn test_keys_not_translated_by_default() {
+    // The sequence is echoed as is, with the adm3a preset it would be ^K.
+    // With the ADM-3A emulation, ^K would be shown as ESC[A too.
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("\x1b[A"),
+        Step::Expect("A"),
+        ), vec!("--terminal", "ansi")
+    );
+}
+
+#[test]
+fn test_keys_wordstar() {
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("\x1b[A"),
+        Step::Expect("\x05"),
+        Step::Input("\x1b[1;5D"),
+        Step::Expect("\x01"),
+        Step::Input("\r"),
+        Step::Expect("?"),
+        ), vec!("--keys", "wordstar")
+    );
+}
+
+#[test]
+fn test_key_definition() {
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("\x1bOP\r"),
+        Step::Expect("B>"),
+        ), vec!("-b", "tests/artifacts", "--key", "f1=B:")
+    );
+}
+
+
+#[test]
+fn test_mapped_ctrl_c_doesnt_stop() {
+    // PgDn is ^C on WordStar, twice is not the stop request
+    run_and_check(vec!(
+        Step::Expect("A>"),
+        Step::Input("\x1b[6~\x1b[6~"),
+        Step::Expect("A>"),
+        Step::Input("B:\r"),
+        Step::Expect("B>"),
+        ), vec!("--keys", "wordstar", "-b", "tests/artifacts"), &["B>"]);
+}
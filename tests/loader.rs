@@ -0,0 +1,18 @@
+mod common;
+use common::*;
+
+#[test]
+fn test_intel_hex() {
+    run_and_check(vec!(), vec!("tests/artifacts/tpatop.hex"), &["TOP F8FF"]);
+}
+
+#[test]
+fn test_binary_at_load_address() {
+    run_and_check(vec!(), vec!("--load-address", "8000", "tests/artifacts/tpatop_8000.bin"), &["TOP F8FF"]);
+}
+
+#[test]
+fn test_start_address() {
+    run_and_check(vec!(), vec!("--load-address", "8000", "--start-address", "8000", "--tpa-top", "48K",
+        "tests/artifacts/tpatop_8000.bin"), &["TOP B8BF"]);
+}
@@ -0,0 +1,21 @@
+mod common;
+use common::*;
+
+#[test]
+fn test_tpa_top_size() {
+    run_script_with_args(vec!(
+        Step::Expect("TOP B8BF"),
+        ), vec!("--tpa-top", "48K", "tests/artifacts/tpatop.com")
+    );
+}
+
+#[test]
+fn test_relocated_ccp() {
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:TPATOP\r"),
+        Step::Expect("TOP D8DF"),
+        Step::Expect("A>"),
+        ), vec!("--tpa-top", "D800", "-b", "tests/artifacts")
+    );
+}
@@ -0,0 +1,33 @@
+mod common;
+use common::*;
+
+#[test]
+fn test_mount_and_list() {
+    run_and_check(vec!(
+        Step::Expect("A>"),
+        Step::Input("MNT B:=TESTS/ARTIFACTS\r"),
+        Step::Expect("A>"),
+        Step::Input("B:TPATOP\r"),
+        Step::Expect("TOP"),
+        Step::Expect("A>"),
+        Step::Input("MNT\r"),
+        Step::Expect("B: ="),
+        Step::Expect("A>"),
+        ), vec!("-a", "tests/artifacts"), &["TOP F8FF", "A: = tests/artifacts\n", "B: = tests/artifacts\n"]);
+}
+
+#[test]
+fn test_mount_errors() {
+    run_and_check(vec!(
+        Step::Expect("A>"),
+        Step::Input("MNT Q:=TESTS\r"),
+        Step::Expect("A>"),
+        Step::Input("MNT C:=NOWHERE\r"),
+        Step::Expect("A>"),
+        Step::Input("MNT A:=\r"),
+        Step::Expect("A>"),
+        ), vec!("-a", "tests/artifacts"), &[
+            "Mount error: Invalid drive",
+            "Mount error: Directory \"NOWHERE\" not found",
+            "Mount error: Drive A: and the current drive can't be unmounted"]);
+}
@@ -0,0 +1,38 @@
+mod common;
+use common::*;
+use izcpm::Step;
+
+use std::fs;
+
+#[test]
+fn test_profile_reports() {
+    let dir = TempDir::new("profile");
+    let text = dir.join("profile.txt");
+    let json = dir.join("profile.json");
+    let callgrind = dir.join("callgrind.out");
+
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:\r"),
+        Step::Expect("B>"),
+        Step::Input("ret 3\r"),
+        Step::Expect("B>"),
+        ), vec!("-b", "tests/artifacts",
+            "--profile", text.to_str().unwrap(),
+            "--profile", json.to_str().unwrap(),
+            "--profile", callgrind.to_str().unwrap())
+    );
+
+    let report = fs::read_to_string(&text).unwrap();
+    assert!(report.contains("BDOS calls:"));
+    assert!(report.contains("C_READSTR"));
+    assert!(report.contains("f000-f0ff"));
+
+    let report = fs::read_to_string(&json).unwrap();
+    assert!(report.starts_with("{\"instructions\":"));
+    assert!(report.contains("\"name\":\"S_BDOSVER\"") || report.contains("\"name\":\"DRV_SET\""));
+
+    let report = fs::read_to_string(&callgrind).unwrap();
+    assert!(report.contains("events: Instructions Cycles"));
+    assert!(report.contains("fn=CBASE 0xf000"));
+}
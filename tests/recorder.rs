@@ -0,0 +1,44 @@
+mod common;
+use common::*;
+use izcpm::Step;
+
+use std::fs;
+
+#[test]
+fn test_transcript_and_cast() {
+    let dir = TempDir::new("recorder");
+    let transcript = dir.join("session.txt");
+    let cast = dir.join("session.cast");
+
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:screen\r"),
+        Step::ExpectRow(6, "HELLO"),
+        ), vec!("-b", "tests/artifacts",
+            "--transcript", transcript.to_str().unwrap(),
+            "--record", cast.to_str().unwrap(),
+            "--record-input")
+    );
+
+    let transcript = fs::read_to_string(&transcript).unwrap();
+    assert!(transcript.contains("A>B:screen\nHELLO"));
+    assert!(!transcript.contains('\x1b'));
+    assert!(!transcript.contains('\r'));
+
+    let cast = fs::read_to_string(&cast).unwrap();
+    let mut lines = cast.lines();
+    let header = lines.next().unwrap();
+    assert!(header.starts_with("{\"version\":2,\"width\":80,\"height\":24,"));
+    let events: Vec<&str> = lines.collect();
+    assert!(events.iter().all(|event| event.starts_with('[') && event.ends_with(']')));
+    assert!(events.iter().any(|event| event.ends_with(",\"i\",\"s\"]")));
+    assert!(events.iter().any(|event| event.ends_with(",\"o\",\"\\u001b[2J\\u001b[H\"]")));
+    assert!(events.iter().any(|event| event.ends_with(",\"o\",\"\\u001b[6;10H\"]")));
+    // The CR+LF from CP/M is kept, not doubled
+    let output: String = events.iter()
+        .filter_map(|event| event.split_once(",\"o\",\""))
+        .map(|(_, data)| data.trim_end_matches("\"]"))
+        .collect();
+    assert!(output.contains("A>B:screen\\r\\n"));
+    assert!(!output.contains("\\r\\r"));
+}
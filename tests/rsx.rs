@@ -0,0 +1,24 @@
+mod common;
+use common::*;
+
+#[test]
+fn test_cpm3_com_with_rsx() {
+    run_and_check(vec!(), vec!("tests/artifacts/rsx.com"), &["RSX Y F7"]);
+}
+
+#[test]
+fn test_rsx_below_ccp_removed_on_warm_boot() {
+    run_and_check(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:RSX\r"),
+        Step::Expect("A>"),
+        Step::Input("B:TPATOP\r"),
+        Step::Expect("TOP"),
+        Step::Expect("A>"),
+        ), vec!("-b", "tests/artifacts"), &["RSX Y EF", "TOP F8FF"]);
+}
+
+#[test]
+fn test_prl_program() {
+    run_and_check(vec!(), vec!("tests/artifacts/tpatop.prl"), &["TOP F8FF"]);
+}
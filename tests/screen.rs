@@ -0,0 +1,37 @@
+mod common;
+use common::*;
+use izcpm::Step;
+
+#[test]
+fn test_screen_row_and_cursor() {
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:\r"),
+        Step::Expect("B>"),
+        Step::Input("screen\r"),
+        Step::ExpectRow(6, "HELLO"),
+        Step::ExpectCursor(7, 3),
+        ), vec!("-b", "tests/artifacts")
+    );
+}
+
+#[test]
+fn test_screen_snapshot() {
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:screen\r"),
+        Step::ExpectScreen("\n\n\n\n\n         HELLO\nA>"),
+        ), vec!("-b", "tests/artifacts")
+    );
+}
+
+#[test]
+#[should_panic(expected = "|         HELLO")]
+fn test_screen_dump_on_failure() {
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:screen\r"),
+        Step::ExpectRow(6, "GOODBYE"),
+        ), vec!("-b", "tests/artifacts")
+    );
+}
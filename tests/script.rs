@@ -0,0 +1,22 @@
+mod common;
+use common::*;
+
+use std::fs;
+
+#[test]
+fn test_script_file() {
+    izcpm::run_script_file("tests/scripts/screen.script", vec!()).unwrap();
+}
+
+#[test]
+fn test_script_failure_context() {
+    let dir = TempDir::new("script");
+    let script = dir.join("failure.script");
+    fs::write(&script, "expect \"A>\"\nsend \"DIR\\r\"\nexpect \"NOT THERE\"\nsend \"B:\\r\"\n").unwrap();
+
+    let failure = izcpm::run_script_file(script.to_str().unwrap(), vec!("-a", "tests/artifacts")).unwrap_err();
+    assert!(failure.starts_with("Test failed in step 3: input not available waiting for expected output\n"));
+    assert!(failure.contains(">   3: expect \"NOT THERE\""));
+    assert!(failure.contains("Output collected: \"") && failure.contains("HALT"));
+    assert!(failure.contains("Screen, cursor at row"));
+}
@@ -0,0 +1,17 @@
+mod common;
+use common::*;
+
+use std::fs;
+
+#[test]
+fn test_8251_with_files() {
+    let dir = TempDir::new("serial");
+    let input = dir.join("in.bin");
+    let output = dir.join("out.bin");
+    fs::write(&input, b"abc").unwrap();
+    let spec = format!("8251:10:in={},out={}", input.to_str().unwrap(), output.to_str().unwrap());
+
+    run_to_end(vec!(), vec!("--device", &spec, "tests/artifacts/serial.com"));
+
+    assert_eq!(fs::read(&output).unwrap(), b"ABC");
+}
@@ -0,0 +1,28 @@
+mod common;
+use common::*;
+
+use std::fs;
+
+#[test]
+fn test_symbols_on_profile() {
+    let dir = TempDir::new("symbols");
+    let symbols = dir.join("ret.sym");
+    let report = dir.join("profile.txt");
+    fs::write(&symbols, "; Symbols of ret.com\nRETSTART=0100\n").unwrap();
+
+    run_script_with_args(vec!(
+        Step::Expect("A>"),
+        Step::Input("B:\r"),
+        Step::Expect("B>"),
+        Step::Input("ret 3\r"),
+        Step::Expect("B>"),
+        ), vec!("-b", "tests/artifacts",
+            "--symbols", symbols.to_str().unwrap(),
+            "--profile", report.to_str().unwrap())
+    );
+
+    let report = fs::read_to_string(&report).unwrap();
+    assert!(report.contains("RETSTART"));
+    // Built-in CCP symbols
+    assert!(report.contains("CBASE"));
+}
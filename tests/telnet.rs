@@ -0,0 +1,71 @@
+mod common;
+use common::*;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn wait_for(stream: &mut TcpStream, received: &mut Vec<u8>, text: &str) {
+    let start = Instant::now();
+    let mut buf = [0; 256];
+    while !String::from_utf8_lossy(received).contains(text) {
+        assert!(start.elapsed() < Duration::from_secs(10),
+            "Timeout waiting for {:?}, received {:?}", text, String::from_utf8_lossy(received));
+        if let Ok(size) = stream.read(&mut buf) {
+            received.extend_from_slice(&buf[..size]);
+        }
+    }
+}
+
+#[test]
+fn test_telnet_session() {
+    let dir = TempDir::new("telnet");
+    let address = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+
+    let server_address = address.clone();
+    let session_dir = dir.path().to_string_lossy().to_string();
+    thread::spawn(move || {
+        izcpm::serve(&server_address, vec!("-b".to_string(), "tests/artifacts".to_string()),
+            Some(session_dir), 16).unwrap();
+    });
+
+    let start = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(&address) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < Duration::from_secs(5) => thread::sleep(Duration::from_millis(10)),
+            Err(err) => panic!("Can't connect: {}", err),
+        }
+    };
+    stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    let mut received = Vec::new();
+    wait_for(&mut stream, &mut received, "User: ");
+    // Character mode negotiation: WILL ECHO, WILL SGA, DO SGA, DONT LINEMODE
+    assert!(received.starts_with(&[255, 251, 1, 255, 251, 3, 255, 253, 3, 255, 254, 34]));
+
+    // Enter as CR NUL, with a negotiation reply in the middle
+    stream.write_all(b"ivan\r\0").unwrap();
+    stream.write_all(&[255, 253, 1]).unwrap();
+    wait_for(&mut stream, &mut received, "A>");
+    assert!(dir.join("ivan").is_dir());
+
+    stream.write_all(b"B:screen\r\n").unwrap();
+    wait_for(&mut stream, &mut received, "HELLO\r\nA>");
+    assert!(String::from_utf8_lossy(&received).contains("A>B:screen\r\n"));
+
+    // No host commands on the sessions
+    stream.write_all(b"MOUNT C:=/\r\n").unwrap();
+    wait_for(&mut stream, &mut received, "MOUNT?");
+
+    // The exit prompt goes to the client, not to the server console
+    stream.write_all(&[3, 3]).unwrap();
+    wait_for(&mut stream, &mut received, "Press Y to exit iz-cpm.");
+    stream.write_all(b"n").unwrap();
+
+    drop(stream);
+}
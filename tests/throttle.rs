@@ -0,0 +1,12 @@
+mod common;
+use common::*;
+
+use std::time::Instant;
+
+#[test]
+fn test_mhz_paces_execution() {
+    // 1703971 T-states at 10 MHz take at least 170 ms
+    let start = Instant::now();
+    run_to_end(vec!(), vec!("tests/artifacts/loop.com", "--mhz", "10"));
+    assert!(start.elapsed().as_millis() >= 170);
+}